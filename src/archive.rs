@@ -0,0 +1,175 @@
+use std::collections::HashSet;
+use std::fmt::Display;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use anyhow::Context;
+use tokio::io::AsyncWriteExt;
+
+use crate::downloaders::{EpisodeNumber, VideoType};
+
+/// Record of finished downloads with one entry per line, similar to the
+/// download archive of yt-dlp. Entries are only ever appended to the file.
+#[derive(Debug)]
+pub struct DownloadArchive {
+    path: PathBuf,
+    entries: Mutex<HashSet<String>>,
+}
+
+impl DownloadArchive {
+    /// Loads the archive file, which does not have to exist yet
+    pub async fn load(path: PathBuf) -> Result<Self, anyhow::Error> {
+        let entries = match tokio::fs::read_to_string(&path).await {
+            Ok(content) => content
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty())
+                .map(str::to_owned)
+                .collect(),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => HashSet::new(),
+            Err(err) => return Err(err).with_context(|| "failed to read download archive"),
+        };
+
+        Ok(DownloadArchive {
+            path,
+            entries: Mutex::new(entries),
+        })
+    }
+
+    pub fn contains(&self, entry: &ArchiveEntry) -> bool {
+        self.entries.lock().unwrap().contains(&entry.to_string())
+    }
+
+    pub async fn add(&self, entry: &ArchiveEntry) -> Result<(), anyhow::Error> {
+        let line = entry.to_string();
+
+        if !self.entries.lock().unwrap().insert(line.clone()) {
+            return Ok(());
+        }
+
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await
+            .with_context(|| "failed to open download archive")?;
+
+        file.write_all(format!("{}\n", line).as_bytes())
+            .await
+            .with_context(|| "failed to write to download archive")
+    }
+}
+
+/// Identifies an episode of a series on a site in a specific video type
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArchiveEntry {
+    site: &'static str,
+    series: String,
+    season: Option<u32>,
+    episode: EpisodeNumber,
+    video_type: VideoType,
+}
+
+impl ArchiveEntry {
+    pub fn new(
+        site: &'static str,
+        series: &str,
+        season: Option<u32>,
+        episode: EpisodeNumber,
+        video_type: VideoType,
+    ) -> Self {
+        ArchiveEntry {
+            site,
+            series: series.to_owned(),
+            season,
+            episode,
+            video_type,
+        }
+    }
+}
+
+impl Display for ArchiveEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Every field has to stay a single word, because they are separated by spaces
+        let without_whitespace = |value: &str| value.split_whitespace().collect::<Vec<_>>().join("_");
+        let season = match self.season {
+            Some(season) => season.to_string(),
+            None => "-".to_owned(),
+        };
+        let episode = match &self.episode {
+            EpisodeNumber::Number(number) => number.to_string(),
+            EpisodeNumber::String(string) => without_whitespace(string),
+        };
+
+        write!(
+            f,
+            "{} {} {} {} {}",
+            self.site,
+            without_whitespace(&self.series),
+            season,
+            episode,
+            self.video_type
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::archive::{ArchiveEntry, DownloadArchive};
+    use crate::downloaders::{EpisodeNumber, Language, VideoType};
+
+    #[test]
+    fn test_archive_entry() {
+        let entry = ArchiveEntry::new(
+            "aniworld",
+            "one-piece",
+            Some(1),
+            EpisodeNumber::Number(5),
+            VideoType::Dub(Language::German),
+        );
+        assert_eq!(entry.to_string(), "aniworld one-piece 1 5 GerDub");
+
+        let entry = ArchiveEntry::new(
+            "aniwave",
+            "one-piece",
+            None,
+            EpisodeNumber::String("Special 1".to_owned()),
+            VideoType::Sub(Language::English),
+        );
+        assert_eq!(entry.to_string(), "aniwave one-piece - Special_1 EngSub");
+    }
+
+    #[tokio::test]
+    async fn test_download_archive() {
+        let path = std::env::temp_dir().join(format!("sdl-archive-test-{}.txt", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let entry = ArchiveEntry::new(
+            "aniworld",
+            "one-piece",
+            Some(1),
+            EpisodeNumber::Number(5),
+            VideoType::Raw,
+        );
+        let other_entry = ArchiveEntry::new(
+            "aniworld",
+            "one-piece",
+            Some(1),
+            EpisodeNumber::Number(6),
+            VideoType::Raw,
+        );
+
+        let archive = DownloadArchive::load(path.clone()).await.unwrap();
+        assert!(!archive.contains(&entry));
+        archive.add(&entry).await.unwrap();
+        archive.add(&entry).await.unwrap();
+        assert!(archive.contains(&entry));
+
+        let archive = DownloadArchive::load(path.clone()).await.unwrap();
+        assert!(archive.contains(&entry));
+        assert!(!archive.contains(&other_entry));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "aniworld one-piece 1 5 Raw\n");
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -20,6 +20,19 @@ pub(crate) struct ChromeDriver<'a> {
     downloader: &'a Downloader,
 }
 
+/// Kills the ChromeDriver process, if starting ChromeDriver fails or is cancelled
+struct KillOnDrop(Option<Child>);
+
+impl Drop for KillOnDrop {
+    fn drop(&mut self) {
+        if let Some(mut child) = self.0.take() {
+            if let Err(err) = child.kill() {
+                log::warn!("Failed to kill ChromeDriver: {}", err);
+            }
+        }
+    }
+}
+
 impl<'a> ChromeDriver<'a> {
     pub(crate) async fn get(
         data_dir: &'a Path,
@@ -51,10 +64,12 @@ impl<'a> ChromeDriver<'a> {
                 .stderr(Stdio::null());
         }
 
-        let child_process = chromedriver_cmd
-            .arg(format!("--port={}", port))
-            .spawn()
-            .with_context(|| "failed to start ChromeDriver")?;
+        let mut child_process = KillOnDrop(Some(
+            chromedriver_cmd
+                .arg(format!("--port={}", port))
+                .spawn()
+                .with_context(|| "failed to start ChromeDriver")?,
+        ));
 
         // ChromeDriver Capabilities
         let mut caps = thirtyfour::DesiredCapabilities::chrome();
@@ -154,7 +169,7 @@ impl<'a> ChromeDriver<'a> {
             .await
             .unwrap();
 
-        Ok((driver, child_process))
+        Ok((driver, child_process.0.take().unwrap()))
     }
 
     async fn get_chromedriver_and_browser_path() -> Result<(PathBuf, String), anyhow::Error> {
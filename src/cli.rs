@@ -1,12 +1,16 @@
 use std::fmt::Display;
 use std::num::NonZeroU32;
 use std::ops::RangeInclusive;
+use std::path::PathBuf;
 use std::str::FromStr;
 use std::time::Duration;
 
 use clap::{Parser, ValueEnum};
 
+use crate::download::{ByteRate, OutputContainer, ProgressMode, VariantQuality};
 use crate::downloaders::{AllOrSpecific, DownloadSettings, EpisodesRequest, Language, VideoType};
+use crate::extractors::{normalized_name, HosterPreference};
+use crate::template::OutputTemplate;
 
 #[derive(Parser, Debug)]
 #[command(version)]
@@ -33,7 +37,7 @@ pub(crate) struct Args {
     pub(crate) seasons: SimpleRanges,
 
     /// Use underlying extractors directly
-    #[arg(short = 'u', long, num_args = 0..=1, require_equals = true, value_parser = parse_extractor, default_missing_value = "auto", conflicts_with_all = ["video_type", "language", "type_language", "episodes", "seasons", "concurrent_downloads", "ddos_wait_episodes", "ddos_wait_ms"], value_name = "NAME")]
+    #[arg(short = 'u', long, num_args = 0..=1, require_equals = true, value_parser = parse_extractor, default_missing_value = "auto", conflicts_with_all = ["video_type", "language", "type_language", "episodes", "seasons", "concurrent_downloads", "ddos_wait_episodes", "ddos_wait_ms", "exec", "hosters", "exclude_hosters", "report_json", "download_archive", "output", "series_folders", "embed_metadata", "write_nfo"], value_name = "NAME")]
     pub(crate) extractor: Option<Extractor>,
 
     /// Concurrent downloads
@@ -44,6 +48,94 @@ pub(crate) struct Args {
     #[arg(short = 'r', long, value_parser = parse_optional_with_inf_as_none::<NonZeroU32>, default_value = "5", value_name = "INF|NUMBER")]
     pub(crate) retries: OptionWrapper<NonZeroU32>,
 
+    /// Concurrent m3u8 segment downloads per episode
+    #[arg(long, default_value = "3", value_name = "NUMBER")]
+    pub(crate) concurrent_segments: NonZeroU32,
+
+    /// Connections per direct file download, each fetching a part of the file
+    #[arg(long, default_value = "1", value_name = "NUMBER")]
+    pub(crate) connections: NonZeroU32,
+
+    /// Maximum combined download rate in bytes per second (e.g. 500K or 2M)
+    #[arg(long, value_name = "RATE")]
+    pub(crate) limit_rate: Option<ByteRate>,
+
+    /// Maximum download rate of every single download in bytes per second
+    #[arg(long, value_name = "RATE")]
+    pub(crate) limit_rate_per_download: Option<ByteRate>,
+
+    /// Record downloaded episodes in this file and skip the ones that are already recorded
+    #[arg(long, value_name = "FILE")]
+    pub(crate) download_archive: Option<PathBuf>,
+
+    /// Save downloads in this directory instead of the current working directory
+    #[arg(long, value_name = "DIR")]
+    pub(crate) output_dir: Option<PathBuf>,
+
+    /// Save downloads in "<series>/Season NN/" subfolders, movies in "<series>/Movies/"
+    #[arg(long)]
+    pub(crate) series_folders: bool,
+
+    /// Container of the downloaded files, direct downloads are only remuxed if this is set [default: mp4]
+    #[arg(value_enum, long, ignore_case = true, value_name = "CONTAINER")]
+    pub(crate) container: Option<OutputContainer>,
+
+    /// Pipe m3u8 and mpd segments into FFmpeg instead of writing temporary files. Such downloads can not be resumed
+    #[arg(long)]
+    pub(crate) pipe_to_ffmpeg: bool,
+
+    /// Delete unfinished downloads on Ctrl-C instead of keeping them to be resumed later
+    #[arg(long)]
+    pub(crate) delete_partial: bool,
+
+    /// Do not check the size and duration of downloaded files and whether FFmpeg can read them
+    #[arg(long)]
+    pub(crate) no_verify: bool,
+
+    /// Fail direct downloads that are shorter than this, which are most likely placeholders of removed videos.
+    /// Without it, such files only cause a warning
+    #[arg(long, conflicts_with = "no_verify", value_name = "SECONDS")]
+    pub(crate) min_duration: Option<f64>,
+
+    /// Write series and episode information into the metadata of the downloaded files
+    #[arg(long)]
+    pub(crate) embed_metadata: bool,
+
+    /// Write .nfo files for Kodi and Jellyfin and download the artwork of the series.
+    /// Needs --series-folders or an output template that starts with a {series} directory
+    #[arg(long)]
+    pub(crate) write_nfo: bool,
+
+    /// Output path template, e.g. "{series}/Season {season:02}/{series} S{season:02}E{episode:02} {title}".
+    /// Fields: series, season, episode, title, language, hoster, year
+    #[arg(short, long, value_name = "TEMPLATE")]
+    pub(crate) output: Option<OutputTemplate>,
+
+    /// Show progress bars or print JSON lines with progress events to stdout
+    #[arg(value_enum, long, default_value_t = ProgressMode::Bars, value_name = "MODE")]
+    pub(crate) progress: ProgressMode,
+
+    /// Write the outcome of every episode as JSON to this file
+    #[arg(long, value_name = "FILE")]
+    pub(crate) report_json: Option<PathBuf>,
+
+    /// Hosters to try first, in this order (e.g. voe,filemoon,vidoza). On Aniwave, only these are kept as fallbacks
+    #[arg(long, value_delimiter = ',', value_parser = parse_hoster, value_name = "NAMES")]
+    pub(crate) hosters: Vec<&'static str>,
+
+    /// Hosters that are never used
+    #[arg(long, value_delimiter = ',', value_parser = parse_hoster, value_name = "NAMES")]
+    pub(crate) exclude_hosters: Vec<&'static str>,
+
+    /// Run this shell command after every downloaded episode. It gets the file in SDL_PATH and
+    /// the episode in SDL_SERIES, SDL_SEASON, SDL_EPISODE, SDL_TITLE and SDL_LANGUAGE
+    #[arg(long, value_name = "COMMAND")]
+    pub(crate) exec: Option<String>,
+
+    /// Video quality: best, worst, maximum height (e.g. 720p) or maximum bandwidth (e.g. 3000k)
+    #[arg(short = 'q', long, default_value_t = VariantQuality::Best, value_name = "QUALITY")]
+    pub(crate) quality: VariantQuality,
+
     /// Amount of requests before waiting
     #[arg(long, value_parser = parse_optional_with_never_as_none::<NonZeroU32>, default_value = "4", value_name = "NEVER|NUMBER")]
     pub(crate) ddos_wait_episodes: OptionWrapper<NonZeroU32>,
@@ -53,7 +145,7 @@ pub(crate) struct Args {
     pub(crate) ddos_wait_ms: u32,
 
     /// Play in mpv
-    #[arg(long, conflicts_with_all = ["concurrent_downloads", "retries"])]
+    #[arg(long, conflicts_with_all = ["concurrent_downloads", "retries", "concurrent_segments", "connections", "limit_rate", "limit_rate_per_download", "download_archive", "output", "output_dir", "series_folders", "embed_metadata", "write_nfo", "container", "pipe_to_ffmpeg", "delete_partial", "no_verify", "min_duration", "exec", "report_json", "progress"])]
     pub(crate) mpv: bool,
 
     /// Enable debug mode
@@ -126,7 +218,11 @@ impl Args {
         let wait_duration = Duration::from_millis(self.ddos_wait_ms as u64);
         let wait_fn = move || wait_duration;
 
-        DownloadSettings::new(self.ddos_wait_episodes.inner().copied(), wait_fn)
+        DownloadSettings::new(self.ddos_wait_episodes.inner().copied(), wait_fn).hoster_preference(
+            HosterPreference::default()
+                .preferred(self.hosters.clone())
+                .excluded(self.exclude_hosters.clone()),
+        )
     }
 }
 
@@ -287,6 +383,10 @@ pub(crate) enum Extractor {
     Name(String),
 }
 
+fn parse_hoster(input: &str) -> Result<&'static str, String> {
+    normalized_name(input.trim()).ok_or_else(|| format!("no extractor for hoster: {}", input))
+}
+
 fn parse_extractor(input: &str) -> Result<Extractor, String> {
     if input.eq_ignore_ascii_case("auto") {
         Ok(Extractor::Auto)
@@ -0,0 +1,623 @@
+use std::ops::Range;
+
+use anyhow::Context;
+use roxmltree::Node;
+use url::Url;
+
+/// The parts of a static MPEG-DASH manifest that are needed for downloading
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Mpd {
+    pub(crate) adaptation_sets: Vec<AdaptationSet>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ContentType {
+    Video,
+    Audio,
+    Text,
+    Other,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct AdaptationSet {
+    pub(crate) content_type: ContentType,
+    pub(crate) language: Option<String>,
+    pub(crate) label: Option<String>,
+    pub(crate) representations: Vec<Representation>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Representation {
+    pub(crate) id: String,
+    pub(crate) bandwidth: u64,
+    pub(crate) width: Option<u64>,
+    pub(crate) height: Option<u64>,
+    pub(crate) mime_type: Option<String>,
+    pub(crate) init: Option<SegmentReference>,
+    pub(crate) segments: Vec<SegmentReference>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct SegmentReference {
+    pub(crate) url: Url,
+    pub(crate) byte_range: Option<Range<u64>>,
+    /// Duration in seconds
+    pub(crate) duration: f64,
+}
+
+/// Segment template attributes, which are inherited from the period and
+/// adaptation set down to the representation
+#[derive(Debug, Clone, Default)]
+struct SegmentTemplate {
+    media: Option<String>,
+    initialization: Option<String>,
+    start_number: Option<u64>,
+    timescale: Option<u64>,
+    duration: Option<u64>,
+    timeline: Option<Vec<TimelineEntry>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct TimelineEntry {
+    time: Option<u64>,
+    duration: u64,
+    repeat: i64,
+}
+
+impl SegmentTemplate {
+    fn parse(node: Node) -> Result<Self, anyhow::Error> {
+        let timeline = child_elements(node, "SegmentTimeline")
+            .next()
+            .map(|timeline| {
+                child_elements(timeline, "S")
+                    .map(|entry| {
+                        Ok(TimelineEntry {
+                            time: parse_attribute(entry, "t")?,
+                            duration: parse_attribute(entry, "d")?
+                                .with_context(|| "segment timeline entry is missing d")?,
+                            repeat: parse_attribute(entry, "r")?.unwrap_or(0),
+                        })
+                    })
+                    .collect::<Result<Vec<_>, anyhow::Error>>()
+            })
+            .transpose()?;
+
+        Ok(SegmentTemplate {
+            media: node.attribute("media").map(str::to_string),
+            initialization: node.attribute("initialization").map(str::to_string),
+            start_number: parse_attribute(node, "startNumber")?,
+            timescale: parse_attribute(node, "timescale")?,
+            duration: parse_attribute(node, "duration")?,
+            timeline,
+        })
+    }
+
+    fn inherit(self, parent: &SegmentTemplate) -> Self {
+        SegmentTemplate {
+            media: self.media.or_else(|| parent.media.clone()),
+            initialization: self.initialization.or_else(|| parent.initialization.clone()),
+            start_number: self.start_number.or(parent.start_number),
+            timescale: self.timescale.or(parent.timescale),
+            duration: self.duration.or(parent.duration),
+            timeline: self.timeline.or_else(|| parent.timeline.clone()),
+        }
+    }
+}
+
+pub(crate) fn parse_mpd(mpd: &str, mpd_url: &Url) -> Result<Mpd, anyhow::Error> {
+    let document = roxmltree::Document::parse(mpd).with_context(|| "failed to parse mpd")?;
+    let root = document.root_element();
+
+    if root.tag_name().name() != "MPD" {
+        anyhow::bail!("mpd root element is missing");
+    }
+
+    if root.attribute("type") == Some("dynamic") {
+        anyhow::bail!("live mpd manifests are not supported");
+    }
+
+    let mut periods = child_elements(root, "Period");
+    let period = periods.next().with_context(|| "mpd has no period")?;
+
+    if periods.next().is_some() {
+        anyhow::bail!("mpd manifests with multiple periods are not supported");
+    }
+
+    let period_duration = match period
+        .attribute("duration")
+        .or_else(|| root.attribute("mediaPresentationDuration"))
+    {
+        Some(duration) => Some(parse_duration(duration)?),
+        None => None,
+    };
+    let period_base_url = resolve_base_url(&resolve_base_url(mpd_url, root)?, period)?;
+    let period_template = parse_inherited_template(period, &SegmentTemplate::default())?;
+    let mut adaptation_sets = Vec::new();
+
+    for adaptation_set in child_elements(period, "AdaptationSet") {
+        if child_elements(adaptation_set, "ContentProtection").next().is_some() {
+            anyhow::bail!("drm protected mpd manifests are not supported");
+        }
+
+        let base_url = resolve_base_url(&period_base_url, adaptation_set)?;
+        let template = parse_inherited_template(adaptation_set, &period_template)?;
+        let mut representations = Vec::new();
+
+        for representation in child_elements(adaptation_set, "Representation") {
+            let id = representation.attribute("id").unwrap_or_default().to_string();
+            let bandwidth = parse_attribute(representation, "bandwidth")?.unwrap_or(0);
+            let base_url = resolve_base_url(&base_url, representation)?;
+            let template = parse_inherited_template(representation, &template)?;
+            let segment_list = child_elements(representation, "SegmentList")
+                .next()
+                .or_else(|| child_elements(adaptation_set, "SegmentList").next());
+
+            let (init, segments) = if let Some(segment_list) = segment_list {
+                get_segment_list_segments(segment_list, &base_url)?
+            } else if template.media.is_some() {
+                get_template_segments(&template, &id, bandwidth, &base_url, period_duration)?
+            } else {
+                // SegmentBase or a plain BaseURL, the whole resource is one segment
+                let segment = SegmentReference {
+                    url: base_url,
+                    byte_range: None,
+                    duration: period_duration.unwrap_or(0.0),
+                };
+                (None, vec![segment])
+            };
+
+            representations.push(Representation {
+                id,
+                bandwidth,
+                width: parse_attribute(representation, "width")?,
+                height: parse_attribute(representation, "height")?,
+                mime_type: representation
+                    .attribute("mimeType")
+                    .or_else(|| adaptation_set.attribute("mimeType"))
+                    .map(str::to_string),
+                init,
+                segments,
+            });
+        }
+
+        adaptation_sets.push(AdaptationSet {
+            content_type: get_content_type(adaptation_set),
+            language: adaptation_set.attribute("lang").map(str::to_string),
+            label: child_elements(adaptation_set, "Label")
+                .next()
+                .and_then(|label| label.text())
+                .map(str::to_string),
+            representations,
+        });
+    }
+
+    Ok(Mpd { adaptation_sets })
+}
+
+fn child_elements<'a, 'input: 'a>(node: Node<'a, 'input>, name: &'a str) -> impl Iterator<Item = Node<'a, 'input>> {
+    node.children()
+        .filter(move |child| child.is_element() && child.tag_name().name() == name)
+}
+
+fn parse_attribute<T: std::str::FromStr>(node: Node, name: &str) -> Result<Option<T>, anyhow::Error> {
+    node.attribute(name)
+        .map(|value| {
+            value
+                .trim()
+                .parse::<T>()
+                .map_err(|_| anyhow::anyhow!("invalid mpd attribute {}: {}", name, value))
+        })
+        .transpose()
+}
+
+fn resolve_base_url(parent: &Url, node: Node) -> Result<Url, anyhow::Error> {
+    match child_elements(node, "BaseURL")
+        .next()
+        .and_then(|base_url| base_url.text())
+    {
+        Some(base_url) => parent
+            .join(base_url.trim())
+            .with_context(|| "failed to create mpd base url"),
+        None => Ok(parent.clone()),
+    }
+}
+
+fn parse_inherited_template(node: Node, parent: &SegmentTemplate) -> Result<SegmentTemplate, anyhow::Error> {
+    match child_elements(node, "SegmentTemplate").next() {
+        Some(template) => Ok(SegmentTemplate::parse(template)?.inherit(parent)),
+        None => Ok(parent.clone()),
+    }
+}
+
+fn get_content_type(adaptation_set: Node) -> ContentType {
+    let content_type = adaptation_set.attribute("contentType").or_else(|| {
+        adaptation_set
+            .attribute("mimeType")
+            .or_else(|| {
+                child_elements(adaptation_set, "Representation")
+                    .next()
+                    .and_then(|representation| representation.attribute("mimeType"))
+            })
+            .and_then(|mime_type| mime_type.split('/').next())
+    });
+
+    match content_type {
+        Some("video") => ContentType::Video,
+        Some("audio") => ContentType::Audio,
+        Some("text") => ContentType::Text,
+        _ => ContentType::Other,
+    }
+}
+
+fn parse_byte_range(range: &str) -> Result<Range<u64>, anyhow::Error> {
+    let (start, end) = range
+        .split_once('-')
+        .with_context(|| format!("invalid mpd byte range: {}", range))?;
+    let start = start
+        .parse::<u64>()
+        .with_context(|| format!("invalid mpd byte range: {}", range))?;
+    let end = end
+        .parse::<u64>()
+        .with_context(|| format!("invalid mpd byte range: {}", range))?;
+
+    if end < start {
+        anyhow::bail!("invalid mpd byte range: {}", range);
+    }
+
+    // Byte ranges in MPD manifests are inclusive
+    Ok(start..end + 1)
+}
+
+fn get_segment_list_segments(
+    segment_list: Node,
+    base_url: &Url,
+) -> Result<(Option<SegmentReference>, Vec<SegmentReference>), anyhow::Error> {
+    let timescale = parse_attribute::<u64>(segment_list, "timescale")?.unwrap_or(1).max(1);
+    let duration = parse_attribute::<u64>(segment_list, "duration")?.unwrap_or(0) as f64 / timescale as f64;
+    let get_reference = |url: Option<&str>, range: Option<&str>, duration: f64| {
+        Ok::<_, anyhow::Error>(SegmentReference {
+            url: match url {
+                Some(url) => base_url.join(url).with_context(|| "failed to create mpd segment url")?,
+                None => base_url.clone(),
+            },
+            byte_range: range.map(parse_byte_range).transpose()?,
+            duration,
+        })
+    };
+
+    let init = child_elements(segment_list, "Initialization")
+        .next()
+        .map(|init| get_reference(init.attribute("sourceURL"), init.attribute("range"), 0.0))
+        .transpose()?;
+    let segments = child_elements(segment_list, "SegmentURL")
+        .map(|segment| get_reference(segment.attribute("media"), segment.attribute("mediaRange"), duration))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok((init, segments))
+}
+
+fn get_template_segments(
+    template: &SegmentTemplate,
+    representation_id: &str,
+    bandwidth: u64,
+    base_url: &Url,
+    period_duration: Option<f64>,
+) -> Result<(Option<SegmentReference>, Vec<SegmentReference>), anyhow::Error> {
+    let media = template
+        .media
+        .as_deref()
+        .with_context(|| "mpd segment template is missing media")?;
+    let timescale = template.timescale.unwrap_or(1).max(1);
+    let start_number = template.start_number.unwrap_or(1);
+    let get_url = |template: &str, number: u64, time: u64| {
+        let path = expand_template(template, representation_id, bandwidth, number, time)?;
+        base_url.join(&path).with_context(|| "failed to create mpd segment url")
+    };
+
+    let init = template
+        .initialization
+        .as_deref()
+        .map(|initialization| {
+            Ok::<_, anyhow::Error>(SegmentReference {
+                url: get_url(initialization, start_number, 0)?,
+                byte_range: None,
+                duration: 0.0,
+            })
+        })
+        .transpose()?;
+    let mut segments = Vec::new();
+
+    if let Some(timeline) = &template.timeline {
+        let period_end = period_duration.map(|period_duration| (period_duration * timescale as f64).ceil() as u64);
+        let mut time = 0;
+        let mut number = start_number;
+
+        for (entry_index, entry) in timeline.iter().enumerate() {
+            time = entry.time.unwrap_or(time);
+
+            if entry.duration == 0 {
+                anyhow::bail!("mpd segment timeline entry has no duration");
+            }
+
+            // A negative repeat count lasts until the next entry or the end of the period
+            let repeat = if entry.repeat < 0 {
+                let end = timeline
+                    .get(entry_index + 1)
+                    .and_then(|next_entry| next_entry.time)
+                    .or(period_end)
+                    .with_context(|| "mpd segment timeline has an open repeat without end")?;
+                end.saturating_sub(time).div_ceil(entry.duration).saturating_sub(1)
+            } else {
+                entry.repeat as u64
+            };
+
+            for _ in 0..=repeat {
+                segments.push(SegmentReference {
+                    url: get_url(media, number, time)?,
+                    byte_range: None,
+                    duration: entry.duration as f64 / timescale as f64,
+                });
+                time += entry.duration;
+                number += 1;
+            }
+        }
+    } else {
+        let segment_duration = template
+            .duration
+            .filter(|&duration| duration > 0)
+            .with_context(|| "mpd segment template has neither duration nor timeline")?;
+        let period_duration = period_duration.with_context(|| "mpd has no duration")?;
+        let segment_count = (period_duration * timescale as f64 / segment_duration as f64).ceil() as u64;
+
+        for segment_index in 0..segment_count {
+            segments.push(SegmentReference {
+                url: get_url(media, start_number + segment_index, segment_index * segment_duration)?,
+                byte_range: None,
+                duration: segment_duration as f64 / timescale as f64,
+            });
+        }
+    }
+
+    Ok((init, segments))
+}
+
+/// Replaces the `$Identifier$` placeholders of a segment template, including
+/// an optional printf-like width such as `$Number%05d$`
+fn expand_template(
+    template: &str,
+    representation_id: &str,
+    bandwidth: u64,
+    number: u64,
+    time: u64,
+) -> Result<String, anyhow::Error> {
+    let mut expanded = String::with_capacity(template.len());
+    let mut parts = template.split('$');
+
+    expanded.push_str(parts.next().unwrap_or_default());
+
+    while let Some(identifier) = parts.next() {
+        let Some(literal) = parts.next() else {
+            anyhow::bail!("unterminated identifier in mpd segment template: {}", template);
+        };
+
+        if identifier.is_empty() {
+            expanded.push('$');
+        } else {
+            let (name, format) = match identifier.split_once('%') {
+                Some((name, format)) => (name, Some(format)),
+                None => (identifier, None),
+            };
+            let value = match name {
+                "RepresentationID" => {
+                    expanded.push_str(representation_id);
+                    expanded.push_str(literal);
+                    continue;
+                }
+                "Number" => number,
+                "Bandwidth" => bandwidth,
+                "Time" => time,
+                _ => anyhow::bail!("unknown identifier in mpd segment template: {}", identifier),
+            };
+            let width = match format {
+                Some(format) => format
+                    .strip_suffix('d')
+                    .and_then(|width| width.trim_start_matches('0').parse::<usize>().ok().or(Some(0)))
+                    .with_context(|| format!("invalid format in mpd segment template: {}", identifier))?,
+                None => 0,
+            };
+
+            expanded.push_str(&format!("{:0width$}", value, width = width));
+        }
+
+        expanded.push_str(literal);
+    }
+
+    Ok(expanded)
+}
+
+/// Parses an ISO 8601 duration like `PT1H23M45.6S` into seconds
+fn parse_duration(duration: &str) -> Result<f64, anyhow::Error> {
+    let invalid_duration = || format!("invalid mpd duration: {}", duration);
+    let rest = duration.trim().strip_prefix('P').with_context(invalid_duration)?;
+    let mut seconds = 0.0;
+    let mut number = String::new();
+    let mut is_time = false;
+
+    for char in rest.chars() {
+        match char {
+            'T' if !is_time && number.is_empty() => is_time = true,
+            '0'..='9' | '.' => number.push(char),
+            _ => {
+                let value = number.parse::<f64>().ok().with_context(invalid_duration)?;
+                let factor = match (char, is_time) {
+                    ('Y', false) => 365.0 * 24.0 * 60.0 * 60.0,
+                    ('M', false) => 30.0 * 24.0 * 60.0 * 60.0,
+                    ('W', false) => 7.0 * 24.0 * 60.0 * 60.0,
+                    ('D', false) => 24.0 * 60.0 * 60.0,
+                    ('H', true) => 60.0 * 60.0,
+                    ('M', true) => 60.0,
+                    ('S', true) => 1.0,
+                    _ => anyhow::bail!(invalid_duration()),
+                };
+
+                seconds += value * factor;
+                number.clear();
+            }
+        }
+    }
+
+    if !number.is_empty() {
+        anyhow::bail!(invalid_duration());
+    }
+
+    Ok(seconds)
+}
+
+#[cfg(test)]
+mod tests {
+    use url::Url;
+
+    use crate::dash::{expand_template, parse_duration, parse_mpd, ContentType};
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("PT1H2M3.5S").unwrap(), 3723.5);
+        assert_eq!(parse_duration("PT24M").unwrap(), 1440.0);
+        assert_eq!(parse_duration("P1DT1S").unwrap(), 86401.0);
+        assert!(parse_duration("1H").is_err());
+        assert!(parse_duration("PT5").is_err());
+        assert!(parse_duration("PTS").is_err());
+    }
+
+    #[test]
+    fn test_expand_template() {
+        assert_eq!(
+            expand_template("$RepresentationID$/seg-$Number%05d$.m4s", "v1", 1000, 42, 0).unwrap(),
+            "v1/seg-00042.m4s"
+        );
+        assert_eq!(
+            expand_template("t$Time$_b$Bandwidth$_$$.mp4", "v1", 1000, 1, 9000).unwrap(),
+            "t9000_b1000_$.mp4"
+        );
+        assert!(expand_template("$Number", "v1", 0, 1, 0).is_err());
+        assert!(expand_template("$Unknown$", "v1", 0, 1, 0).is_err());
+    }
+
+    #[test]
+    fn test_parse_mpd_template() {
+        let mpd = r#"<?xml version="1.0" encoding="UTF-8"?>
+<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" type="static" mediaPresentationDuration="PT10S">
+  <BaseURL>media/</BaseURL>
+  <Period>
+    <AdaptationSet contentType="video" mimeType="video/mp4">
+      <SegmentTemplate timescale="1000" duration="4000" startNumber="1" initialization="$RepresentationID$/init.mp4" media="$RepresentationID$/$Number$.m4s"/>
+      <Representation id="720p" bandwidth="3000000" width="1280" height="720"/>
+      <Representation id="1080p" bandwidth="6000000" width="1920" height="1080"/>
+    </AdaptationSet>
+    <AdaptationSet mimeType="audio/mp4" lang="ja">
+      <Representation id="audio" bandwidth="128000">
+        <SegmentTemplate timescale="48000" initialization="audio/init.mp4" media="audio/$Time$.m4s">
+          <SegmentTimeline>
+            <S t="0" d="192000" r="1"/>
+            <S d="96000"/>
+          </SegmentTimeline>
+        </SegmentTemplate>
+      </Representation>
+    </AdaptationSet>
+  </Period>
+</MPD>"#;
+        let mpd_url = Url::parse("https://example.com/stream/manifest.mpd").unwrap();
+        let mpd = parse_mpd(mpd, &mpd_url).unwrap();
+
+        assert_eq!(mpd.adaptation_sets.len(), 2);
+
+        let video = &mpd.adaptation_sets[0];
+        assert_eq!(video.content_type, ContentType::Video);
+        assert_eq!(video.representations[1].height, Some(1080));
+
+        let representation = &video.representations[0];
+        assert_eq!(
+            representation.init.as_ref().unwrap().url.as_str(),
+            "https://example.com/stream/media/720p/init.mp4"
+        );
+        let segment_urls = representation
+            .segments
+            .iter()
+            .map(|segment| segment.url.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            segment_urls,
+            [
+                "https://example.com/stream/media/720p/1.m4s",
+                "https://example.com/stream/media/720p/2.m4s",
+                "https://example.com/stream/media/720p/3.m4s",
+            ]
+        );
+
+        let audio = &mpd.adaptation_sets[1];
+        assert_eq!(audio.content_type, ContentType::Audio);
+        assert_eq!(audio.language.as_deref(), Some("ja"));
+        let segments = audio.representations[0]
+            .segments
+            .iter()
+            .map(|segment| (segment.url.as_str(), segment.duration))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            segments,
+            [
+                ("https://example.com/stream/media/audio/0.m4s", 4.0),
+                ("https://example.com/stream/media/audio/192000.m4s", 4.0),
+                ("https://example.com/stream/media/audio/384000.m4s", 2.0),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_mpd_segment_list() {
+        let mpd = r#"<MPD type="static" mediaPresentationDuration="PT8S">
+  <Period>
+    <AdaptationSet mimeType="video/mp4">
+      <Representation id="1" bandwidth="1000" width="640" height="360">
+        <BaseURL>video.mp4</BaseURL>
+        <SegmentList timescale="1" duration="4">
+          <Initialization range="0-99"/>
+          <SegmentURL mediaRange="100-199"/>
+          <SegmentURL mediaRange="200-299"/>
+        </SegmentList>
+      </Representation>
+    </AdaptationSet>
+  </Period>
+</MPD>"#;
+        let mpd_url = Url::parse("https://example.com/manifest.mpd").unwrap();
+        let mpd = parse_mpd(mpd, &mpd_url).unwrap();
+        let representation = &mpd.adaptation_sets[0].representations[0];
+
+        assert_eq!(representation.init.as_ref().unwrap().byte_range, Some(0..100));
+        let segments = representation
+            .segments
+            .iter()
+            .map(|segment| (segment.url.as_str(), segment.byte_range.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            segments,
+            [
+                ("https://example.com/video.mp4", Some(100..200)),
+                ("https://example.com/video.mp4", Some(200..300)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_mpd_unsupported() {
+        let mpd_url = Url::parse("https://example.com/manifest.mpd").unwrap();
+
+        assert!(parse_mpd(r#"<MPD type="dynamic"><Period/></MPD>"#, &mpd_url).is_err());
+        assert!(parse_mpd(r#"<MPD><Period/><Period/></MPD>"#, &mpd_url).is_err());
+        assert!(parse_mpd(
+            r#"<MPD><Period><AdaptationSet><ContentProtection/></AdaptationSet></Period></MPD>"#,
+            &mpd_url
+        )
+        .is_err());
+        assert!(parse_mpd("<html></html>", &mpd_url).is_err());
+    }
+}
@@ -1,10 +1,16 @@
 use std::borrow::Cow;
-use std::cell::RefCell;
-use std::fmt::Write;
-use std::num::NonZeroU32;
-use std::ops::Deref;
-use std::path::PathBuf;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ffi::OsStr;
+use std::fmt::{Display, Write};
+use std::io::SeekFrom;
+use std::num::{NonZeroU32, NonZeroU64};
+use std::ops::{Deref, Range};
+use std::path::{Path, PathBuf};
 use std::process::Stdio;
+use std::rc::Rc;
+use std::str::FromStr;
+use std::sync::Arc;
 use std::time::Duration;
 
 use anyhow::Context;
@@ -17,18 +23,41 @@ use reqwest_partial_retry::{ClientExt, Config};
 use reqwest_retry::policies::ExponentialBackoffBuilder;
 use reqwest_retry::DefaultRetryableStrategy;
 use retry::strategy::CustomRetryStrategy;
-use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncBufReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
 use tokio::sync::mpsc::UnboundedSender;
 use tokio_stream::wrappers::UnboundedReceiverStream;
 use url::Url;
 
+use crate::archive::DownloadArchive;
+use crate::dash;
 use crate::downloaders::{DownloadTask, EpisodeInfo, EpisodeNumber, Language, SeriesInfo, VideoType};
+use crate::extractors::ExtractedVideo;
+use crate::hook::ExecHook;
 use crate::logger::log_wrapper::SetLogWrapper;
+use crate::nfo;
+use crate::report::{get_episode_label, DownloadReport};
+use crate::template::{OutputTemplate, TemplateValues};
 use crate::utils::remove_file_ignore_not_exists;
 
 const DEFAULT_USER_AGENT: &str =
     "Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/116.0.0.0 Safari/537.36";
 
+const PART_FILE_EXTENSION: &str = "part";
+const RESUME_STATE_FILE_EXTENSION: &str = "state";
+
+/// How many bytes of a direct download may be written before the resume state
+/// is saved again.
+const RESUME_STATE_SAVE_INTERVAL: u64 = 4 * 1024 * 1024;
+
+/// Direct downloads are not split into byte ranges smaller than this
+const MIN_RANGE_LENGTH: u64 = 4 * 1024 * 1024;
+
+/// How often a byte range is requested again after its download failed
+const RANGE_RETRIES: u32 = 5;
+
+/// A byte range is requested again if no data arrives for this long
+const RANGE_STREAM_TIMEOUT: Duration = Duration::from_secs(60);
+
 static DEFAULT_RETRY_CLIENT_NO_REDIRECT: Lazy<reqwest_partial_retry::Client> = Lazy::new(|| {
     reqwest::Client::builder()
         .user_agent(DEFAULT_USER_AGENT)
@@ -53,6 +82,20 @@ pub(crate) struct DownloadManager {
     downloader: Downloader,
     rx_stream: UnboundedReceiverStream<DownloadTask>,
     max_concurrent: Option<usize>,
+    concurrent_segments: NonZeroU32,
+    connections: NonZeroU32,
+    quality: VariantQuality,
+    container: Option<OutputContainer>,
+    pipe_to_ffmpeg: bool,
+    verify: bool,
+    min_duration: Option<f64>,
+    download_archive: Option<Arc<DownloadArchive>>,
+    output_template: Option<OutputTemplate>,
+    series_folders: bool,
+    embed_metadata: bool,
+    write_nfo: bool,
+    exec_hook: Option<ExecHook>,
+    download_report: Option<Arc<DownloadReport>>,
     save_directory: PathBuf,
     series_info: SeriesInfo,
 }
@@ -71,6 +114,20 @@ impl DownloadManager {
             downloader,
             rx_stream,
             max_concurrent: max_concurrent.map(|n| n.get() as usize),
+            concurrent_segments: NonZeroU32::MIN,
+            connections: NonZeroU32::MIN,
+            quality: VariantQuality::Best,
+            container: None,
+            pipe_to_ffmpeg: false,
+            verify: false,
+            min_duration: None,
+            download_archive: None,
+            output_template: None,
+            series_folders: false,
+            embed_metadata: false,
+            write_nfo: false,
+            exec_hook: None,
+            download_report: None,
             save_directory,
             series_info,
         };
@@ -78,40 +135,312 @@ impl DownloadManager {
         (manager, tx)
     }
 
+    pub(crate) fn concurrent_segments(mut self, concurrent_segments: NonZeroU32) -> Self {
+        self.concurrent_segments = concurrent_segments;
+        self
+    }
+
+    pub(crate) fn connections(mut self, connections: NonZeroU32) -> Self {
+        self.connections = connections;
+        self
+    }
+
+    pub(crate) fn quality(mut self, quality: VariantQuality) -> Self {
+        self.quality = quality;
+        self
+    }
+
+    pub(crate) fn container(mut self, container: Option<OutputContainer>) -> Self {
+        self.container = container;
+        self
+    }
+
+    pub(crate) fn pipe_to_ffmpeg(mut self, pipe_to_ffmpeg: bool) -> Self {
+        self.pipe_to_ffmpeg = pipe_to_ffmpeg;
+        self
+    }
+
+    pub(crate) fn verify(mut self, verify: bool) -> Self {
+        self.verify = verify;
+        self
+    }
+
+    pub(crate) fn min_duration(mut self, min_duration: Option<f64>) -> Self {
+        self.min_duration = min_duration;
+        self
+    }
+
+    /// Successful downloads are added to the archive
+    pub(crate) fn download_archive(mut self, download_archive: Option<Arc<DownloadArchive>>) -> Self {
+        self.download_archive = download_archive;
+        self
+    }
+
+    /// Names the downloaded files after the template instead of the default episode name
+    pub(crate) fn output_template(mut self, output_template: Option<OutputTemplate>) -> Self {
+        self.output_template = output_template;
+        self
+    }
+
+    /// Sorts the downloaded files into `<series>/Season NN/` folders
+    pub(crate) fn series_folders(mut self, series_folders: bool) -> Self {
+        self.series_folders = series_folders;
+        self
+    }
+
+    /// Writes series and episode information into the metadata of the downloaded files
+    pub(crate) fn embed_metadata(mut self, embed_metadata: bool) -> Self {
+        self.embed_metadata = embed_metadata;
+        self
+    }
+
+    /// Writes `.nfo` files for Kodi and Jellyfin and downloads the artwork of the series
+    pub(crate) fn write_nfo(mut self, write_nfo: bool) -> Self {
+        self.write_nfo = write_nfo;
+        self
+    }
+
+    /// Runs the command after every successful download
+    pub(crate) fn exec_hook(mut self, exec_hook: Option<ExecHook>) -> Self {
+        self.exec_hook = exec_hook;
+        self
+    }
+
+    /// Adds the outcome of every download to the report
+    pub(crate) fn download_report(mut self, download_report: Option<Arc<DownloadReport>>) -> Self {
+        self.download_report = download_report;
+        self
+    }
+
     pub(crate) async fn progress_downloads(self) {
         let anime_name_for_file = prepare_series_name_for_file(&self.series_info.title);
-        let download_future = self
-            .rx_stream
-            .for_each_concurrent(self.max_concurrent, |download_task| {
-                let output_name = get_episode_name(
-                    anime_name_for_file.as_deref(),
-                    Some(&download_task.language),
-                    &download_task.episode_info,
-                    false,
-                );
-                let output_path_no_extension = self.save_directory.join(&output_name);
+        // The series folders take precedence, because the output template is rendered inside of them
+        let series_directory = if self.series_folders {
+            anime_name_for_file.as_deref().map(|anime_name| {
+                self.save_directory
+                    .join(avoid_windows_reserved_name(anime_name).as_ref())
+            })
+        } else {
+            self.output_template
+                .as_ref()
+                .and_then(|output_template| {
+                    output_template.render_series_directory(&self.series_info.title, self.series_info.year)
+                })
+                .map(|directory| self.save_directory.join(directory))
+        };
+        let (downloader, series_info, write_nfo) = (&self.downloader, &self.series_info, self.write_nfo);
+        let series_sidecars_future = async {
+            if !write_nfo {
+                return;
+            }
 
-                let internal_task = InternalDownloadTask::new(output_path_no_extension, download_task.download_url)
+            match &series_directory {
+                Some(series_directory) => write_series_sidecars(downloader, series_directory, series_info).await,
+                None => log::warn!("Failed to write tvshow.nfo: no directory for the series"),
+            }
+        };
+        // The concurrent downloads only take the next task once one of them is finished,
+        // so the tasks are taken from the scrapers as soon as they are sent
+        let (queued_tx, queued_rx) = tokio::sync::mpsc::unbounded_channel::<(String, DownloadTask)>();
+        let rx_stream = self.rx_stream;
+        let queue_future = async move {
+            rx_stream
+                .for_each(|download_task| {
+                    let episode_label = get_episode_label(
+                        download_task.episode_info.season_number,
+                        &download_task.episode_info.episode_number,
+                        Some(&download_task.language),
+                    );
+                    downloader.emit_event(
+                        "queued",
+                        Some(&episode_label),
+                        serde_json::json!({
+                            "series": series_info.title,
+                            "season": download_task.episode_info.season_number,
+                            "title": download_task.episode_info.name,
+                            "language": download_task.language.to_string(),
+                            "hoster": download_task.hoster,
+                        }),
+                    );
+                    let _ = queued_tx.send((episode_label, download_task));
+                    futures_util::future::ready(())
+                })
+                .await;
+        };
+        let download_future = UnboundedReceiverStream::new(queued_rx).for_each_concurrent(
+            self.max_concurrent,
+            |(episode_label, download_task)| {
+                let save_directory = if self.series_folders {
+                    self.save_directory.join(get_series_directory(
+                        anime_name_for_file.as_deref(),
+                        &download_task.episode_info,
+                    ))
+                } else {
+                    self.save_directory.clone()
+                };
+                let internal_task = InternalDownloadTask::new(PathBuf::new(), download_task.download_url)
                     .output_path_has_extension(false)
-                    .referer(download_task.referer);
+                    .referer(download_task.referer)
+                    .concurrent_segments(self.concurrent_segments)
+                    .connections(self.connections)
+                    .quality(self.quality)
+                    .container(self.container)
+                    .pipe_to_ffmpeg(self.pipe_to_ffmpeg)
+                    .verify(self.verify)
+                    .min_duration(self.min_duration)
+                    .episode(Some(episode_label.clone()))
+                    .metadata(self.embed_metadata.then(|| {
+                        MediaMetadata::new(&self.series_info, &download_task.episode_info, download_task.language)
+                    }));
                 let downloader_borrowed = &self.downloader;
+                let download_archive = self.download_archive.as_deref();
+                let exec_hook = self.exec_hook.as_ref();
+                let download_report = self.download_report.as_deref();
+                let output_template = self.output_template.as_ref();
+                let anime_name_for_file = anime_name_for_file.as_deref();
+                let write_nfo = self.write_nfo;
+                let archive_entry = download_task.archive_entry;
+                let episode_info = download_task.episode_info;
+                let language = download_task.language;
+                let mut hoster = download_task.hoster;
+                let mut fallback_sources = download_task.fallback_sources.into_iter();
 
                 async move {
-                    if let Err(err) = downloader_borrowed.download_to_file(internal_task).await {
+                    let mut internal_task = internal_task;
+
+                    let (output_name, output_path_no_extension, final_path) = loop {
+                        let (output_name, output_path_no_extension) = match output_template {
+                            Some(output_template) => {
+                                let output_path = output_template.render(&TemplateValues {
+                                    series: &series_info.title,
+                                    episode_info: &episode_info,
+                                    language: Some(&language),
+                                    hoster: hoster.as_deref(),
+                                    year: series_info.year,
+                                });
+                                (output_path.display().to_string(), save_directory.join(output_path))
+                            }
+                            None => {
+                                let output_name =
+                                    get_episode_name(anime_name_for_file, Some(&language), &episode_info, false);
+                                let output_path_no_extension = save_directory.join(&output_name);
+                                (output_name, output_path_no_extension)
+                            }
+                        };
+
+                        let task = internal_task.output_path(output_path_no_extension.clone());
+
+                        let err = match downloader_borrowed.download_to_file(task).await {
+                            Ok(final_path) => break (output_name, output_path_no_extension, final_path),
+                            Err(err) => err,
+                        };
+
                         log::warn!("Failed download of {}: {:#}", output_name, err);
+
+                        // The other hosters are only extracted once they are needed. They would
+                        // not help against an error of the local file system or FFmpeg.
+                        let extracted_video = loop {
+                            let Some(fallback_source) = fallback_sources.next().filter(|_| !is_local_error(&err))
+                            else {
+                                if let Some(download_report) = download_report {
+                                    download_report.failed(episode_label, &err);
+                                }
+
+                                return;
+                            };
+
+                            match fallback_source.extract().await {
+                                Ok(extracted_video) => {
+                                    log::info!("Retrying download of {} from {}", output_name, fallback_source.hoster);
+                                    hoster = Some(fallback_source.hoster);
+                                    break extracted_video;
+                                }
+                                Err(err) => log::debug!(
+                                    "Failed to extract video url from {}: {:#}",
+                                    fallback_source.hoster,
+                                    err
+                                ),
+                            }
+                        };
+
+                        internal_task = internal_task.source(extracted_video);
+                    };
+
+                    if write_nfo {
+                        let nfo_path = path_with_added_extension(&output_path_no_extension, "nfo");
+
+                        if let Err(err) = nfo::write_episode_nfo(&nfo_path, series_info, &episode_info).await {
+                            log::warn!("Failed to write nfo of {}: {:#}", output_name, err);
+                        }
+                    }
+
+                    if let (Some(download_archive), Some(archive_entry)) = (download_archive, archive_entry) {
+                        if let Err(err) = download_archive.add(&archive_entry).await {
+                            log::warn!("Failed to add {} to download archive: {:#}", output_name, err);
+                        }
+                    }
+
+                    if let Some(download_report) = download_report {
+                        download_report.succeeded(episode_label, &final_path);
+                    }
+
+                    if let Some(exec_hook) = exec_hook {
+                        if let Err(err) = exec_hook.run(&final_path, series_info, &episode_info, language).await {
+                            log::warn!("Failed exec command of {}: {:#}", output_name, err);
+                        }
                     }
                 }
-            });
+            },
+        );
 
         tokio::select! {
             biased;
 
-            _ = download_future => {}
+            _ = futures_util::future::join3(series_sidecars_future, queue_future, download_future) => {}
             _ = self.downloader.tick() => unreachable!()
         }
     }
 }
 
+/// Writes `tvshow.nfo` and downloads the poster and backdrop, existing artwork is kept
+async fn write_series_sidecars(downloader: &Downloader, directory: &Path, series_info: &SeriesInfo) {
+    if let Err(err) = nfo::write_tvshow_nfo(directory, series_info).await {
+        log::warn!("Failed to write tvshow.nfo: {:#}", err);
+    }
+
+    let artworks = [
+        ("poster", &series_info.poster_url),
+        ("fanart", &series_info.backdrop_url),
+    ];
+
+    for (name, url) in artworks {
+        let Some(url) = url else {
+            continue;
+        };
+
+        let extension = Url::parse(url)
+            .ok()
+            .and_then(|url| {
+                Path::new(url.path())
+                    .extension()
+                    .map(|extension| extension.to_string_lossy().to_ascii_lowercase())
+            })
+            .unwrap_or_else(|| "jpg".to_owned());
+        let path = directory.join(format!("{}.{}", name, extension));
+
+        if path.try_exists().unwrap_or(false) {
+            continue;
+        }
+
+        if let Err(err) = downloader
+            .download_to_file(InternalDownloadTask::new(path, url.clone()))
+            .await
+        {
+            log::warn!("Failed to download {} of the series: {:#}", name, err);
+        }
+    }
+}
+
 pub(crate) struct InternalDownloadTask {
     url: String,
     output_path: PathBuf,
@@ -119,6 +448,17 @@ pub(crate) struct InternalDownloadTask {
     overwrite_file: bool,
     custom_message: Option<String>,
     referer: Option<String>,
+    concurrent_segments: NonZeroU32,
+    connections: NonZeroU32,
+    quality: VariantQuality,
+    container: Option<OutputContainer>,
+    pipe_to_ffmpeg: bool,
+    verify: bool,
+    min_duration: Option<f64>,
+    metadata: Option<MediaMetadata>,
+    episode: Option<String>,
+    /// Per-download limit, created from the limit of the downloader
+    rate_limiter: Option<TokenBucket>,
 }
 
 impl InternalDownloadTask {
@@ -130,9 +470,40 @@ impl InternalDownloadTask {
             overwrite_file: false,
             custom_message: None,
             referer: None,
+            concurrent_segments: NonZeroU32::MIN,
+            connections: NonZeroU32::MIN,
+            quality: VariantQuality::Best,
+            container: None,
+            pipe_to_ffmpeg: false,
+            verify: false,
+            min_duration: None,
+            metadata: None,
+            episode: None,
+            rate_limiter: None,
+        }
+    }
+
+    /// Copy of the task that is saved to another path
+    fn output_path(&self, output_path: PathBuf) -> Self {
+        InternalDownloadTask {
+            url: self.url.clone(),
+            output_path,
+            custom_message: self.custom_message.clone(),
+            referer: self.referer.clone(),
+            metadata: self.metadata.clone(),
+            episode: self.episode.clone(),
+            rate_limiter: None,
+            ..*self
         }
     }
 
+    /// Replaces the video with the same one from another hoster
+    fn source(mut self, extracted_video: ExtractedVideo) -> Self {
+        self.url = extracted_video.url;
+        self.referer = extracted_video.referer;
+        self
+    }
+
     pub(crate) fn output_path_has_extension(mut self, output_path_has_extension: bool) -> Self {
         self.output_path_has_extension = output_path_has_extension;
         self
@@ -152,6 +523,242 @@ impl InternalDownloadTask {
         self.referer = referer;
         self
     }
+
+    /// Number of m3u8 segments fetched at the same time. Segments are still written in playlist order.
+    pub(crate) fn concurrent_segments(mut self, concurrent_segments: NonZeroU32) -> Self {
+        self.concurrent_segments = concurrent_segments;
+        self
+    }
+
+    /// Number of connections a direct download is split into. Only used if the
+    /// server supports range requests and the file is large enough.
+    pub(crate) fn connections(mut self, connections: NonZeroU32) -> Self {
+        self.connections = connections;
+        self
+    }
+
+    /// The variant of an m3u8 master playlist that is downloaded
+    pub(crate) fn quality(mut self, quality: VariantQuality) -> Self {
+        self.quality = quality;
+        self
+    }
+
+    /// Container of the output file. Without one, direct downloads are saved as they are
+    /// and segmented downloads are remuxed to MP4.
+    pub(crate) fn container(mut self, container: Option<OutputContainer>) -> Self {
+        self.container = container;
+        self
+    }
+
+    /// Segments are piped into FFmpeg instead of being written to temporary files
+    pub(crate) fn pipe_to_ffmpeg(mut self, pipe_to_ffmpeg: bool) -> Self {
+        self.pipe_to_ffmpeg = pipe_to_ffmpeg;
+        self
+    }
+
+    /// Checks the size and duration of the finished file and whether FFmpeg can read it.
+    /// Files that fail the check are deleted and the download counts as failed.
+    pub(crate) fn verify(mut self, verify: bool) -> Self {
+        self.verify = verify;
+        self
+    }
+
+    /// Direct downloads that are shorter fail the verification instead of only causing a warning
+    pub(crate) fn min_duration(mut self, min_duration: Option<f64>) -> Self {
+        self.min_duration = min_duration;
+        self
+    }
+
+    /// Identifies the episode in progress events
+    pub(crate) fn episode(mut self, episode: Option<String>) -> Self {
+        self.episode = episode;
+        self
+    }
+
+    /// Container metadata written by FFmpeg, direct downloads are remuxed for this
+    pub(crate) fn metadata(mut self, metadata: Option<MediaMetadata>) -> Self {
+        self.metadata = metadata;
+        self
+    }
+}
+
+/// How the progress of downloads is shown
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub(crate) enum ProgressMode {
+    #[default]
+    Bars,
+    // One JSON object per line on stdout, for other programs
+    Json,
+}
+
+/// The container that downloads are remuxed to with FFmpeg
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub(crate) enum OutputContainer {
+    #[default]
+    Mp4,
+    Mkv,
+}
+
+impl OutputContainer {
+    pub(crate) fn extension(&self) -> &'static str {
+        match self {
+            OutputContainer::Mp4 => "mp4",
+            OutputContainer::Mkv => "mkv",
+        }
+    }
+
+    /// MP4 only supports its own text subtitle format
+    fn subtitle_codec(&self) -> &'static str {
+        match self {
+            OutputContainer::Mp4 => "mov_text",
+            OutputContainer::Mkv => "srt",
+        }
+    }
+}
+
+/// FFmpeg with its progress written to stdout and its log to stderr, which are
+/// read by `Downloader::wait_for_ffmpeg`
+fn ffmpeg_command(ffmpeg_path: &Path, stdin_piped: bool) -> tokio::process::Command {
+    let mut ffmpeg_cmd = tokio::process::Command::new(ffmpeg_path);
+    ffmpeg_cmd
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+
+    if stdin_piped {
+        ffmpeg_cmd.stdin(Stdio::piped());
+    } else {
+        ffmpeg_cmd.stdin(Stdio::null()).arg("-nostdin");
+    }
+
+    ffmpeg_cmd.args(["-hide_banner", "-nostats", "-progress", "pipe:1"]);
+    ffmpeg_cmd
+}
+
+/// The position in seconds of a `-progress` line of FFmpeg. `out_time_ms` is in
+/// microseconds as well and only used by older versions.
+fn parse_ffmpeg_out_time(line: &str) -> Option<f64> {
+    let microseconds = line
+        .strip_prefix("out_time_us=")
+        .or_else(|| line.strip_prefix("out_time_ms="))?
+        .trim()
+        .parse::<i64>()
+        .ok()?;
+
+    Some(microseconds.max(0) as f64 / 1_000_000.0)
+}
+
+/// Direct downloads that are shorter are most likely placeholders of removed videos
+const MIN_VIDEO_DURATION: f64 = 30.0;
+
+/// The shortest duration a downloaded file may have. A small difference to the
+/// duration of the playlist is allowed, because it is only an approximation.
+/// Direct downloads only have one if it was requested.
+fn get_min_duration(expected_duration: Option<f64>, min_duration: Option<f64>) -> Option<f64> {
+    match expected_duration {
+        Some(expected_duration) => Some(expected_duration * 0.98 - 2.0),
+        None => min_duration,
+    }
+}
+
+/// Series and episode information that is written into the container metadata
+#[derive(Debug, Clone)]
+pub(crate) struct MediaMetadata {
+    show: String,
+    season: Option<u32>,
+    episode: EpisodeNumber,
+    episode_title: Option<String>,
+    description: Option<String>,
+    language: VideoType,
+}
+
+impl MediaMetadata {
+    pub(crate) fn new(series_info: &SeriesInfo, episode_info: &EpisodeInfo, language: VideoType) -> Self {
+        MediaMetadata {
+            show: series_info.title.clone(),
+            season: episode_info.season_number,
+            episode: episode_info.episode_number.clone(),
+            episode_title: episode_info.name.clone(),
+            description: series_info.description.clone(),
+            language,
+        }
+    }
+
+    /// Global metadata and the language of the first audio stream. Arguments for
+    /// single streams that follow these take precedence.
+    fn ffmpeg_args(&self) -> Vec<String> {
+        let mut metadata = vec![("show", self.show.clone())];
+
+        if let Some(episode_title) = &self.episode_title {
+            metadata.push(("title", episode_title.clone()));
+        }
+
+        if let Some(season) = self.season {
+            metadata.push(("season_number", season.to_string()));
+        }
+
+        if let EpisodeNumber::Number(episode) = self.episode {
+            metadata.push(("episode_sort", episode.to_string()));
+        }
+
+        let episode_id = match self.season {
+            Some(season) => format!("S{:02}E{}", season, format_episode_number(&self.episode, None)),
+            None => format!("E{}", format_episode_number(&self.episode, None)),
+        };
+        metadata.push(("episode_id", episode_id));
+
+        if let Some(description) = &self.description {
+            metadata.push(("description", description.clone()));
+            metadata.push(("synopsis", description.clone()));
+        }
+
+        let mut args = metadata
+            .into_iter()
+            .flat_map(|(key, value)| ["-metadata".to_owned(), format!("{}={}", key, value)])
+            .collect::<Vec<_>>();
+
+        // Subbed videos keep the original audio, whose language is unknown
+        let audio_language = match self.language {
+            VideoType::Unspecified(Language::Unspecified) | VideoType::Dub(Language::Unspecified) => None,
+            VideoType::Unspecified(language) | VideoType::Dub(language) => Some(to_iso639_2(language.get_name_short())),
+            VideoType::Raw | VideoType::Sub(_) => None,
+        };
+
+        if let Some(audio_language) = audio_language {
+            args.push("-metadata:s:a:0".to_owned());
+            args.push(format!("language={}", audio_language));
+        }
+
+        args
+    }
+}
+
+/// An error of the local file system or FFmpeg, which a download from another hoster would run into as well
+#[derive(Debug)]
+struct LocalError(&'static str);
+
+impl Display for LocalError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+impl std::error::Error for LocalError {}
+
+/// Whether the error is local instead of one of the source. I/O errors of the network
+/// are wrapped in request errors, which come first in the chain.
+fn is_local_error(err: &anyhow::Error) -> bool {
+    for cause in err.chain() {
+        if cause.is::<reqwest::Error>() || cause.is::<reqwest_middleware::Error>() {
+            return false;
+        }
+
+        if cause.is::<LocalError>() || cause.is::<std::io::Error>() {
+            return true;
+        }
+    }
+
+    false
 }
 
 enum ProgressBarOrResult {
@@ -186,98 +793,409 @@ impl ProgressBarOrResult {
     }
 }
 
-pub(crate) struct Downloader {
-    client: Option<reqwest_partial_retry::Client>,
-    multi_progress: indicatif::MultiProgress,
-    total_progress: RefCell<Option<indicatif::ProgressBar>>,
-    sub_progresses: RefCell<Vec<ProgressBarOrResult>>,
-    ffmpeg_path: Option<PathBuf>,
-    user_agent: Option<String>,
-    debug: bool,
+/// A download target that is first written to a `.part` file and only renamed
+/// to its final name once it is complete. A sidecar state file next to it
+/// records how far the download got, so that it can be resumed by a later run.
+struct PartFile {
+    final_path: PathBuf,
+    part_path: PathBuf,
+    state_path: PathBuf,
+    resumable: bool,
 }
 
-impl Downloader {
-    pub(crate) fn new(
-        log_wrapper: &mut SetLogWrapper,
-        debug: bool,
-        ffmpeg_path: Option<PathBuf>,
-        user_agent: Option<String>,
-        retries: Option<Option<NonZeroU32>>,
-    ) -> Self {
-        let multi_progress = indicatif::MultiProgress::new();
-        log_wrapper.set_multi(Some(multi_progress.clone()));
+impl PartFile {
+    fn new(final_path: PathBuf, resumable: bool) -> Self {
+        let part_path = path_with_added_extension(&final_path, PART_FILE_EXTENSION);
+        let state_path = path_with_added_extension(&part_path, RESUME_STATE_FILE_EXTENSION);
 
-        let client = if let Some(retries) = retries {
-            let client = reqwest::Client::builder()
-                .user_agent(DEFAULT_USER_AGENT)
-                .connect_timeout(Duration::from_secs(20))
-                .build()
-                .unwrap()
-                .resumable_with_config(
-                    Config::builder()
-                        .retry_policy(
-                            ExponentialBackoffBuilder::default()
-                                .retry_bounds(Duration::from_secs(1), Duration::from_secs(10))
-                                .build_with_max_retries(retries.map(|x| x.get()).unwrap_or(u32::MAX)),
-                        )
-                        .retryable_strategy(DefaultRetryableStrategy)
-                        .stream_timeout(Some(Duration::from_secs(60)))
-                        .build(),
-                );
+        PartFile {
+            final_path,
+            part_path,
+            state_path,
+            resumable,
+        }
+    }
 
-            Some(client)
+    /// Opens the part file for writing. If a compatible resume state exists, the
+    /// file is truncated to the last saved position and the state is returned.
+    /// Files of multi-connection downloads are written at offsets and are
+    /// therefore never truncated.
+    async fn open(
+        &self,
+        url: &str,
+        total: Option<u64>,
+        range_count: Option<usize>,
+    ) -> Result<(tokio::fs::File, Option<ResumeState>), anyhow::Error> {
+        let resume_state = if self.resumable {
+            self.load_state(url, total, range_count).await
         } else {
             None
         };
 
-        Downloader {
-            client,
-            multi_progress,
-            total_progress: RefCell::new(None),
-            sub_progresses: RefCell::new(vec![]),
-            ffmpeg_path,
-            user_agent,
-            debug,
+        let mut file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(resume_state.is_none())
+            .open(&self.part_path)
+            .await
+            .with_context(|| "failed to open download target file")?;
+
+        if let Some(resume_state) = resume_state
+            .as_ref()
+            .filter(|resume_state| resume_state.range_bytes_written.is_none())
+        {
+            file.set_len(resume_state.bytes_written)
+                .await
+                .with_context(|| "failed to truncate download file to resume position")?;
+            file.seek(SeekFrom::End(0))
+                .await
+                .with_context(|| "failed to seek to resume position")?;
+            log::trace!(
+                "Resuming download of \"{}\" at {} bytes",
+                self.part_path.display(),
+                resume_state.bytes_written
+            );
         }
+
+        Ok((file, resume_state))
     }
 
-    pub(crate) async fn download_to_file(&self, task: InternalDownloadTask) -> Result<(), anyhow::Error> {
-        let url = Url::parse(&task.url).with_context(|| "failed to parse URL")?;
-        let response = get_response(
-            self.client.as_ref(),
-            url.clone(),
-            self.user_agent.as_deref(),
-            task.referer.as_deref(),
-            None,
-        )
-        .await?;
-        let is_m3u8 = is_m3u8_url(response.url());
+    async fn load_state(&self, url: &str, total: Option<u64>, range_count: Option<usize>) -> Option<ResumeState> {
+        let state_bytes = tokio::fs::read(&self.state_path).await.ok()?;
+        let state_json = serde_json::from_slice::<serde_json::Value>(&state_bytes).ok()?;
+        let resume_state = ResumeState::from_json(&state_json)?;
+        let part_length = tokio::fs::metadata(&self.part_path).await.ok()?.len();
+
+        if !resume_state.is_compatible(url, total)
+            || resume_state.range_bytes_written.as_ref().map(Vec::len) != range_count
+            || part_length < resume_state.bytes_written
+        {
+            log::trace!("Ignoring outdated resume state: {}", self.state_path.display());
+            return None;
+        }
 
-        let output_path = if !task.output_path_has_extension {
-            match (
-                task.output_path.parent(),
-                task.output_path.file_name().map(|file_name| file_name.to_owned()),
-            ) {
-                (Some(parent), Some(mut file_name)) => {
-                    let extension = if is_m3u8 { ".ts" } else { ".mp4" };
-                    file_name.push(extension);
+        Some(resume_state)
+    }
+
+    async fn save_state(&self, resume_state: &ResumeState) -> Result<(), anyhow::Error> {
+        if !self.resumable {
+            return Ok(());
+        }
+
+        tokio::fs::write(&self.state_path, resume_state.to_json().to_string())
+            .await
+            .with_context(|| "failed to write download state file")
+    }
+
+    async fn finish(&self) -> Result<(), anyhow::Error> {
+        tokio::fs::rename(&self.part_path, &self.final_path)
+            .await
+            .with_context(|| "failed to rename finished download file")?;
+
+        if let Err(err) = remove_file_ignore_not_exists(&self.state_path).await {
+            log::warn!("Failed to delete download state file: {}", err);
+        }
+
+        Ok(())
+    }
+
+    /// Blocks of segmented downloads are only temporary files. Their state file
+    /// is kept until they are joined, to tell them apart from leftover files.
+    async fn finish_block(&self) -> Result<(), anyhow::Error> {
+        tokio::fs::rename(&self.part_path, &self.final_path)
+            .await
+            .with_context(|| "failed to rename finished download file")
+    }
+
+    /// Returns the length of a block that was finished by an earlier run
+    async fn get_finished_block_length(&self, url: &str, total_segments: usize) -> Option<u64> {
+        if !self.resumable {
+            return None;
+        }
+
+        let state_bytes = tokio::fs::read(&self.state_path).await.ok()?;
+        let state_json = serde_json::from_slice::<serde_json::Value>(&state_bytes).ok()?;
+        let resume_state = ResumeState::from_json(&state_json)?;
+        let block_length = tokio::fs::metadata(&self.final_path).await.ok()?.len();
+
+        (resume_state.is_compatible(url, Some(total_segments as u64))
+            && resume_state.segments_completed == Some(total_segments)
+            && resume_state.bytes_written == block_length)
+            .then_some(block_length)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ResumeState {
+    url: String,
+    /// Content length for direct downloads, number of segments for m3u8
+    total: Option<u64>,
+    bytes_written: u64,
+    segments_completed: Option<usize>,
+    /// Bytes written of each byte range for multi-connection downloads
+    range_bytes_written: Option<Vec<u64>>,
+}
+
+impl ResumeState {
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "url": self.url,
+            "total": self.total,
+            "bytes_written": self.bytes_written,
+            "segments_completed": self.segments_completed,
+            "range_bytes_written": self.range_bytes_written,
+        })
+    }
+
+    fn from_json(json: &serde_json::Value) -> Option<Self> {
+        let object = json.as_object()?;
+
+        Some(ResumeState {
+            url: object.get("url")?.as_str()?.to_owned(),
+            total: object.get("total").and_then(|total| total.as_u64()),
+            bytes_written: object.get("bytes_written")?.as_u64()?,
+            segments_completed: object
+                .get("segments_completed")
+                .and_then(|segments| segments.as_u64())
+                .map(|segments| segments as usize),
+            range_bytes_written: object
+                .get("range_bytes_written")
+                .and_then(|ranges| ranges.as_array())
+                .and_then(|ranges| ranges.iter().map(|bytes| bytes.as_u64()).collect()),
+        })
+    }
+
+    /// Video URLs often contain expiring tokens, so a changed URL of a direct
+    /// download is still accepted if its size did not change. Segmented downloads
+    /// are identified by their playlist URL without query, because another variant
+    /// or hoster can easily have the same amount of segments.
+    fn is_compatible(&self, url: &str, total: Option<u64>) -> bool {
+        if self.segments_completed.is_some() {
+            return self.url == url && self.total == total;
+        }
+
+        self.url == url || (total.is_some() && self.total == total)
+    }
+}
+
+/// Files of downloads that are in progress, so that they can be cleaned up if
+/// sdl is interrupted. Part files and temporary files of segmented downloads can
+/// be resumed later, the outputs of FFmpeg are incomplete and always deleted.
+#[derive(Debug, Default)]
+pub(crate) struct UnfinishedFiles {
+    resumable: RefCell<HashSet<PathBuf>>,
+    incomplete: RefCell<HashSet<PathBuf>>,
+}
+
+impl UnfinishedFiles {
+    fn add_resumable<'a>(&self, paths: impl IntoIterator<Item = &'a PathBuf>) {
+        self.resumable.borrow_mut().extend(paths.into_iter().cloned());
+    }
+
+    fn remove_resumable<'a>(&self, paths: impl IntoIterator<Item = &'a PathBuf>) {
+        let mut resumable = self.resumable.borrow_mut();
+
+        for path in paths {
+            resumable.remove(path);
+        }
+    }
+
+    fn add_incomplete(&self, path: &Path) {
+        self.incomplete.borrow_mut().insert(path.to_owned());
+    }
+
+    fn remove_incomplete(&self, path: &Path) {
+        self.incomplete.borrow_mut().remove(path);
+    }
+
+    pub(crate) async fn clean_up(&self, delete_resumable: bool) {
+        let mut paths = self.incomplete.take();
+
+        if delete_resumable {
+            paths.extend(self.resumable.take());
+        }
+
+        for path in paths {
+            if let Err(err) = remove_file_ignore_not_exists(&path).await {
+                log::warn!("Failed to delete unfinished file \"{}\": {}", path.display(), err);
+            }
+        }
+    }
+}
+
+pub(crate) struct Downloader {
+    client: Option<reqwest_partial_retry::Client>,
+    multi_progress: indicatif::MultiProgress,
+    total_progress: RefCell<Option<indicatif::ProgressBar>>,
+    sub_progresses: RefCell<Vec<ProgressBarOrResult>>,
+    /// Episode of every progress bar, for progress events
+    sub_progress_episodes: RefCell<Vec<Option<String>>>,
+    progress_mode: ProgressMode,
+    ffmpeg_path: Option<PathBuf>,
+    user_agent: Option<String>,
+    /// Shared by all downloads
+    rate_limiter: Option<TokenBucket>,
+    download_rate_limit: Option<ByteRate>,
+    unfinished_files: Rc<UnfinishedFiles>,
+    debug: bool,
+}
+
+impl Downloader {
+    pub(crate) fn new(
+        log_wrapper: &mut SetLogWrapper,
+        debug: bool,
+        ffmpeg_path: Option<PathBuf>,
+        user_agent: Option<String>,
+        retries: Option<Option<NonZeroU32>>,
+    ) -> Self {
+        let multi_progress = indicatif::MultiProgress::new();
+        log_wrapper.set_multi(Some(multi_progress.clone()));
+
+        let client = if let Some(retries) = retries {
+            let client = reqwest::Client::builder()
+                .user_agent(DEFAULT_USER_AGENT)
+                .connect_timeout(Duration::from_secs(20))
+                .build()
+                .unwrap()
+                .resumable_with_config(
+                    Config::builder()
+                        .retry_policy(
+                            ExponentialBackoffBuilder::default()
+                                .retry_bounds(Duration::from_secs(1), Duration::from_secs(10))
+                                .build_with_max_retries(retries.map(|x| x.get()).unwrap_or(u32::MAX)),
+                        )
+                        .retryable_strategy(DefaultRetryableStrategy)
+                        .stream_timeout(Some(Duration::from_secs(60)))
+                        .build(),
+                );
+
+            Some(client)
+        } else {
+            None
+        };
+
+        Downloader {
+            client,
+            multi_progress,
+            total_progress: RefCell::new(None),
+            sub_progresses: RefCell::new(vec![]),
+            sub_progress_episodes: RefCell::new(vec![]),
+            progress_mode: ProgressMode::Bars,
+            ffmpeg_path,
+            user_agent,
+            rate_limiter: None,
+            download_rate_limit: None,
+            unfinished_files: Rc::new(UnfinishedFiles::default()),
+            debug,
+        }
+    }
+
+    /// JSON progress events replace the progress bars
+    pub(crate) fn progress_mode(mut self, progress_mode: ProgressMode) -> Self {
+        if progress_mode == ProgressMode::Json {
+            self.multi_progress
+                .set_draw_target(indicatif::ProgressDrawTarget::hidden());
+        }
+
+        self.progress_mode = progress_mode;
+        self
+    }
+
+    /// Limits the combined throughput of all downloads
+    pub(crate) fn limit_rate(mut self, limit_rate: Option<ByteRate>) -> Self {
+        self.rate_limiter = limit_rate.map(TokenBucket::new);
+        self
+    }
+
+    /// Limits the throughput of every single download
+    pub(crate) fn limit_rate_per_download(mut self, limit_rate_per_download: Option<ByteRate>) -> Self {
+        self.download_rate_limit = limit_rate_per_download;
+        self
+    }
+
+    /// Registers the files of running downloads here, to clean them up on Ctrl-C
+    pub(crate) fn unfinished_files(mut self, unfinished_files: Rc<UnfinishedFiles>) -> Self {
+        self.unfinished_files = unfinished_files;
+        self
+    }
+
+    /// Returns the path of the downloaded file, whose extension depends on the container
+    pub(crate) async fn download_to_file(&self, task: InternalDownloadTask) -> Result<PathBuf, anyhow::Error> {
+        let episode = task.episode.clone();
+        let result = self.download_task_to_file(task).await;
+
+        match &result {
+            Ok(path) => self.emit_event(
+                "finished",
+                episode.as_deref(),
+                serde_json::json!({ "path": path.display().to_string() }),
+            ),
+            Err(err) => self.emit_event(
+                "failed",
+                episode.as_deref(),
+                serde_json::json!({ "error": format!("{:#}", err) }),
+            ),
+        }
+
+        result
+    }
+
+    async fn download_task_to_file(&self, mut task: InternalDownloadTask) -> Result<PathBuf, anyhow::Error> {
+        task.rate_limiter = self.download_rate_limit.map(TokenBucket::new);
+        let url = Url::parse(&task.url).with_context(|| "failed to parse URL")?;
+        let response = get_response(
+            self.client.as_ref(),
+            url.clone(),
+            self.user_agent.as_deref(),
+            task.referer.as_deref(),
+            None,
+        )
+        .await?;
+        let is_m3u8 = is_m3u8_url(response.url());
+        let is_mpd = is_mpd_url(response.url());
+        let container = task.container.unwrap_or_default();
+
+        let output_path = if !task.output_path_has_extension {
+            match (
+                task.output_path.parent(),
+                task.output_path.file_name().map(|file_name| file_name.to_owned()),
+            ) {
+                (Some(parent), Some(mut file_name)) => {
+                    file_name.push(".");
+                    file_name.push(container.extension());
                     parent.join(file_name)
                 }
-                _ => task.output_path,
+                _ => task.output_path.clone(),
             }
         } else {
-            task.output_path
+            task.output_path.clone()
         };
 
-        let message = if let Some(custom_message) = task.custom_message {
-            custom_message
+        // The temporary container of segmented downloads is only known after parsing the playlist
+        let final_path = if is_m3u8 || is_mpd || task.container.is_some() {
+            output_path.with_extension(container.extension())
         } else {
-            let final_path = if is_m3u8 {
-                Cow::Owned(output_path.with_extension("mp4"))
-            } else {
-                Cow::Borrowed(&output_path)
-            };
+            output_path
+        };
+
+        if !task.overwrite_file
+            && final_path
+                .try_exists()
+                .with_context(|| "failed to check if download target file exists")?
+        {
+            return Err(LocalError("download target file already exists").into());
+        }
+
+        // Output templates can place the file in subdirectories
+        if let Some(parent) = final_path.parent() {
+            tokio::fs::create_dir_all(parent)
+                .await
+                .with_context(|| "failed to create directory of download target file")?;
+        }
 
+        let message = if let Some(custom_message) = &task.custom_message {
+            custom_message.clone()
+        } else {
             final_path
                 .file_name()
                 .with_context(|| "failed to get file name")?
@@ -285,54 +1203,265 @@ impl Downloader {
                 .to_string()
         };
 
-        let target_file = if task.overwrite_file {
-            tokio::fs::OpenOptions::new()
-                .write(true)
-                .create(true)
-                .truncate(true)
-                .open(&output_path)
-                .await
+        // Without FFmpeg, segmented downloads stay in their temporary files
+        if is_m3u8 {
+            return self.m3u8_download(response, &task, url, final_path, message).await;
+        } else if is_mpd {
+            return self.dash_download(response, &task, url, final_path, message).await;
         } else {
-            tokio::fs::OpenOptions::new()
-                .write(true)
-                .create_new(true)
-                .open(&output_path)
-                .await
+            // A direct download would otherwise be left with its temporary extension
+            if task.container.is_some() && self.ffmpeg_path.is_none() {
+                anyhow::bail!("remuxing into a container requires FFmpeg");
+            }
+
+            // Direct files are saved as they are and remuxed into the container afterwards
+            let source_extension = Path::new(response.url().path())
+                .extension()
+                .map(|extension| extension.to_owned());
+            let download_path = if task.container.is_some() {
+                path_with_added_extension(&final_path, "download")
+            } else {
+                final_path.clone()
+            };
+            let part_file = PartFile::new(download_path.clone(), !task.overwrite_file);
+            let supports_ranges = response
+                .headers()
+                .get(reqwest::header::ACCEPT_RANGES)
+                .is_some_and(|accept_ranges| accept_ranges.as_bytes().eq_ignore_ascii_case(b"bytes"));
+            let expected_size = response
+                .content_length()
+                .filter(|_| response.status() == reqwest::StatusCode::OK);
+            let ranges = match response.content_length() {
+                Some(content_length) if supports_ranges && response.status() == reqwest::StatusCode::OK => {
+                    split_byte_ranges(content_length, task.connections.get() as u64, MIN_RANGE_LENGTH)
+                }
+                _ => Vec::new(),
+            };
+
+            if ranges.len() > 1 {
+                drop(response);
+                self.ranged_download(&task, url, part_file, message, ranges).await?;
+            } else {
+                self.simple_download(response, &task, url, part_file, message).await?;
+            }
+
+            // Remuxing keeps the duration, but changes the size
+            if task.verify {
+                self.verify_download(&download_path, None, task.min_duration, expected_size)
+                    .await?;
+            }
+
+            if task.container.is_some() || task.metadata.is_some() {
+                self.emit_event("remux_started", task.episode.as_deref(), serde_json::json!({}));
+                let remux_result = self
+                    .remux_direct_download(&download_path, &final_path, task.metadata.as_ref())
+                    .await;
+
+                // A failed remux keeps the download, but with the extension of its source instead of the temporary one
+                if let Err(err) = remux_result {
+                    // The downloaded file is complete, it only lacks the metadata
+                    if download_path == final_path {
+                        log::warn!(
+                            "Failed to add metadata, keeping the download as \"{}\": {:#}",
+                            final_path.display(),
+                            err
+                        );
+                        return Ok(final_path);
+                    }
+
+                    let source_path = match &source_extension {
+                        Some(source_extension) => final_path.with_extension(source_extension),
+                        None => final_path.clone(),
+                    };
+
+                    if !task.overwrite_file
+                        && source_path
+                            .try_exists()
+                            .with_context(|| "failed to check if source file exists")?
+                    {
+                        return Err(err);
+                    }
+
+                    tokio::fs::rename(&download_path, &source_path)
+                        .await
+                        .with_context(|| "failed to rename download after failed remux")?;
+                    log::warn!(
+                        "Failed to remux, keeping the download as \"{}\": {:#}",
+                        source_path.display(),
+                        err
+                    );
+
+                    return Ok(source_path);
+                }
+            }
         }
-        .with_context(|| "failed to open download target file")?;
 
-        if is_m3u8 {
-            self.m3u8_download(
-                response,
-                task.referer.as_deref(),
-                url,
-                target_file,
-                output_path,
-                message,
-            )
-            .await
+        Ok(final_path)
+    }
+
+    /// Remuxes a finished direct download into the container of the final path and
+    /// adds the metadata. The downloaded file is kept as it is on failure.
+    async fn remux_direct_download(
+        &self,
+        download_path: &Path,
+        final_path: &Path,
+        metadata: Option<&MediaMetadata>,
+    ) -> Result<(), anyhow::Error> {
+        let Some(ffmpeg_path) = &self.ffmpeg_path else {
+            log::info!(
+                "Failed to remux \"{}\" due to FFmpeg not being installed",
+                download_path
+                    .file_name()
+                    .unwrap_or(download_path.as_os_str())
+                    .to_string_lossy()
+            );
+            return Ok(());
+        };
+
+        // Metadata alone is written into a copy that replaces the download
+        let in_place = download_path == final_path;
+        let output_path = if in_place {
+            let extension = final_path.extension().unwrap_or(OsStr::new("mp4")).to_string_lossy();
+            final_path.with_extension(format!("metadata.{}", extension))
+        } else {
+            final_path.to_owned()
+        };
+        let mut ffmpeg_cmd = ffmpeg_command(ffmpeg_path, false);
+
+        ffmpeg_cmd
+            .arg("-i")
+            .arg(download_path)
+            .args(["-map", "0", "-c", "copy"]);
+
+        if let Some(metadata) = metadata {
+            ffmpeg_cmd.args(metadata.ffmpeg_args());
+        }
+
+        ffmpeg_cmd.arg("-y").arg(&output_path);
+
+        self.unfinished_files.add_incomplete(&output_path);
+        let ffmpeg_result = match ffmpeg_cmd.spawn() {
+            Ok(mut child) => self.wait_for_ffmpeg(&mut child, None).await,
+            Err(err) => Err(err).with_context(|| "failed to start FFmpeg"),
+        };
+        self.unfinished_files.remove_incomplete(&output_path);
+
+        if let Err(err) = ffmpeg_result {
+            if let Err(err) = remove_file_ignore_not_exists(&output_path).await {
+                log::warn!("Failed to delete incomplete output file of FFmpeg: {}", err);
+            }
+
+            return Err(err);
+        }
+
+        if in_place {
+            tokio::fs::rename(&output_path, final_path)
+                .await
+                .with_context(|| "failed to replace file with the remuxed one")
         } else {
-            self.simple_download(response, target_file, message).await
+            if let Err(err) = remove_file_ignore_not_exists(download_path).await {
+                log::warn!("Failed to delete downloaded file after remuxing: {}", err);
+            }
+
+            Ok(())
         }
     }
 
     async fn simple_download(
         &self,
         response: reqwest_partial_retry::ResumableResponse,
-        target_file: tokio::fs::File,
+        task: &InternalDownloadTask,
+        url: Url,
+        part_file: PartFile,
         message: String,
     ) -> Result<(), anyhow::Error> {
-        let content_length = response.content_length();
+        let (mut target_file, resume_state) = self
+            .open_part_file(&part_file, url.as_str(), response.content_length(), None)
+            .await?;
+
+        let (response, mut downloaded, content_length) = match resume_state {
+            Some(resume_state) if resume_state.bytes_written > 0 => {
+                drop(response);
+
+                let range = format!("bytes={}-", resume_state.bytes_written);
+                let range_response = get_response(
+                    self.client.as_ref(),
+                    url.clone(),
+                    self.user_agent.as_deref(),
+                    task.referer.as_deref(),
+                    Some(&[(reqwest::header::RANGE, &range)]),
+                )
+                .await?;
+
+                let range_start = range_response
+                    .headers()
+                    .get(reqwest::header::CONTENT_RANGE)
+                    .and_then(|content_range| content_range.to_str().ok())
+                    .and_then(parse_content_range_start);
+
+                if range_response.status() == reqwest::StatusCode::PARTIAL_CONTENT
+                    && range_start == Some(resume_state.bytes_written)
+                {
+                    let content_length = range_response
+                        .content_length()
+                        .map(|length| length + resume_state.bytes_written)
+                        .or(resume_state.total);
+                    (range_response, resume_state.bytes_written, content_length)
+                } else {
+                    log::trace!("Server did not resume at the requested position, restarting download");
+                    let range_response = if range_response.status() == reqwest::StatusCode::PARTIAL_CONTENT {
+                        get_response(
+                            self.client.as_ref(),
+                            url.clone(),
+                            self.user_agent.as_deref(),
+                            task.referer.as_deref(),
+                            None,
+                        )
+                        .await?
+                    } else {
+                        range_response
+                    };
+
+                    target_file
+                        .set_len(0)
+                        .await
+                        .with_context(|| "failed to truncate download file")?;
+                    target_file
+                        .rewind()
+                        .await
+                        .with_context(|| "failed to seek in download file")?;
+                    let content_length = range_response.content_length();
+                    (range_response, 0, content_length)
+                }
+            }
+            _ => {
+                let content_length = response.content_length();
+                (response, 0, content_length)
+            }
+        };
+
+        let mut resume_state = ResumeState {
+            url: url.to_string(),
+            total: content_length,
+            bytes_written: downloaded,
+            segments_completed: None,
+            range_bytes_written: None,
+        };
+        part_file.save_state(&resume_state).await?;
 
         let (sub_progresses_index, progress_bar) = if let Some(content_length) = content_length {
-            self.create_progress_bar(message, content_length)
+            self.create_progress_bar(message, content_length, task.episode.as_deref())
         } else {
-            self.create_progress_bar_unknown_bytes(message)
+            self.create_progress_bar_unknown_bytes(message, task.episode.as_deref())
         };
 
+        if downloaded > 0 {
+            self.update_progress(&progress_bar, downloaded, content_length);
+            progress_bar.reset_eta();
+        }
+
         let mut input_stream = response.bytes_stream_resumable();
         let mut output_stream = tokio::io::BufWriter::new(target_file);
-        let mut downloaded = 0;
 
         while let Some(item) = input_stream.next().await {
             let mut chunk = match item {
@@ -344,12 +1473,27 @@ impl Downloader {
             };
 
             downloaded += chunk.len() as u64;
+            self.limit_rate_of(task, chunk.len() as u64).await;
 
             if let Err(err) = output_stream.write_all_buf(&mut chunk).await {
                 self.error_cleanup_progress_bar(&progress_bar, sub_progresses_index);
                 return Err(err).with_context(|| "failed writing to download file");
             }
 
+            if downloaded - resume_state.bytes_written >= RESUME_STATE_SAVE_INTERVAL {
+                if let Err(err) = output_stream.flush().await {
+                    self.error_cleanup_progress_bar(&progress_bar, sub_progresses_index);
+                    return Err(err).with_context(|| "failed flushing to download file");
+                }
+
+                resume_state.bytes_written = downloaded;
+
+                if let Err(err) = part_file.save_state(&resume_state).await {
+                    self.error_cleanup_progress_bar(&progress_bar, sub_progresses_index);
+                    return Err(err);
+                }
+            }
+
             self.update_progress(&progress_bar, downloaded, content_length);
         }
 
@@ -358,714 +1502,2837 @@ impl Downloader {
             return Err(err);
         }
 
+        if let Err(err) = self.finish_part_file(&part_file).await {
+            self.clean_up_progress_bar(&progress_bar, sub_progresses_index);
+            return Err(err);
+        }
+
         self.clean_up_progress_bar(&progress_bar, sub_progresses_index);
 
         Ok(())
     }
 
-    async fn m3u8_download(
+    /// Downloads a direct file over multiple connections. Every connection
+    /// fetches one byte range and writes it at its offset into the part file.
+    async fn ranged_download(
         &self,
-        response: reqwest_partial_retry::ResumableResponse,
-        referer: Option<&str>,
-        m3u8_url: Url,
-        target_file: tokio::fs::File,
-        target_path: PathBuf,
+        task: &InternalDownloadTask,
+        url: Url,
+        part_file: PartFile,
         message: String,
+        ranges: Vec<Range<u64>>,
     ) -> Result<(), anyhow::Error> {
-        let m3u8_bytes = get_response_bytes(response.response()).await?;
+        let content_length = ranges.last().map(|range| range.end).unwrap_or(0);
+        let (target_file, resume_state) = self
+            .open_part_file(&part_file, url.as_str(), Some(content_length), Some(ranges.len()))
+            .await?;
 
-        let (media_playlist_url, media_playlist) = match m3u8_rs::parse_playlist_res(&m3u8_bytes) {
-            Ok(m3u8_rs::Playlist::MasterPlaylist(mut playlist)) => {
-                if playlist.variants.is_empty() {
-                    anyhow::bail!("could not find any media playlists");
-                }
+        target_file
+            .set_len(content_length)
+            .await
+            .with_context(|| "failed to preallocate download file")?;
+        drop(target_file);
+
+        let range_bytes_written = resume_state
+            .and_then(|resume_state| resume_state.range_bytes_written)
+            .unwrap_or_else(|| vec![0; ranges.len()]);
+        let downloaded = range_bytes_written.iter().sum();
+        let resume_state = ResumeState {
+            url: url.to_string(),
+            total: Some(content_length),
+            bytes_written: downloaded,
+            segments_completed: None,
+            range_bytes_written: Some(range_bytes_written),
+        };
+        part_file.save_state(&resume_state).await?;
 
-                let highest_quality_variant = playlist
-                    .variants
-                    .select_nth_unstable_by(0, |a, b| {
-                        use std::cmp::Ordering;
+        let (sub_progresses_index, progress_bar) =
+            self.create_progress_bar(message, content_length, task.episode.as_deref());
 
-                        match (a.is_i_frame, b.is_i_frame) {
-                            (true, true) => return Ordering::Equal,
-                            (true, false) => return Ordering::Greater,
-                            (false, true) => return Ordering::Less,
-                            (false, false) => {}
-                        }
+        if downloaded > 0 {
+            self.update_progress(&progress_bar, downloaded, Some(content_length));
+            progress_bar.reset_eta();
+        }
 
-                        if let (Some(res_a), Some(res_b)) = (a.resolution, b.resolution) {
-                            let res_a_pixels = res_a.width * res_a.height;
-                            let res_b_pixels = res_b.width * res_b.height;
+        let ranged_download = RangedDownload {
+            url: &url,
+            task,
+            part_file: &part_file,
+            content_length,
+            resume_state: RefCell::new(resume_state),
+            state_lock: tokio::sync::Mutex::new(()),
+            downloaded: Cell::new(downloaded),
+            progress_bar,
+        };
+        let download_result = futures_util::future::try_join_all(
+            ranges
+                .iter()
+                .enumerate()
+                .map(|(range_index, range)| self.download_range(&ranged_download, range_index, range)),
+        )
+        .await;
 
-                            if res_a_pixels != res_b_pixels {
-                                return res_a_pixels.cmp(&res_b_pixels).reverse();
-                            }
-                        }
+        if let Err(err) = download_result {
+            self.error_cleanup_progress_bar(&ranged_download.progress_bar, sub_progresses_index);
+            return Err(err);
+        }
 
-                        if let (Some(bw_a), Some(bw_b)) = (a.average_bandwidth, b.average_bandwidth) {
-                            return bw_a.cmp(&bw_b).reverse();
-                        }
+        if let Err(err) = self.finish_part_file(&part_file).await {
+            self.clean_up_progress_bar(&ranged_download.progress_bar, sub_progresses_index);
+            return Err(err);
+        }
 
-                        a.bandwidth.cmp(&b.bandwidth).reverse()
-                    })
-                    .1;
+        self.clean_up_progress_bar(&ranged_download.progress_bar, sub_progresses_index);
 
-                if highest_quality_variant.is_i_frame {
-                    anyhow::bail!("could not find a non-iframe media playlist");
-                }
+        Ok(())
+    }
 
-                let media_playlist_url = m3u8_url
-                    .join(&highest_quality_variant.uri)
-                    .with_context(|| "failed to create m3u8 media playlist url")?;
-                let m3u8_media_bytes =
-                    get_page_bytes(media_playlist_url.as_str(), self.user_agent.as_deref(), referer, None).await?;
+    async fn download_range(
+        &self,
+        ranged_download: &RangedDownload<'_>,
+        range_index: usize,
+        range: &Range<u64>,
+    ) -> Result<(), anyhow::Error> {
+        let range_length = range.end - range.start;
+        let mut range_written = ranged_download
+            .resume_state
+            .borrow()
+            .range_bytes_written
+            .as_ref()
+            .map(|range_bytes_written| range_bytes_written[range_index])
+            .unwrap_or(0)
+            .min(range_length);
+
+        let mut failed_attempts = 0;
+
+        // The resumable stream of the client would set a Range header of its own on
+        // reconnects, so every attempt requests the range from its saved position instead
+        loop {
+            if range_written == range_length {
+                return Ok(());
+            }
 
-                match m3u8_rs::parse_media_playlist_res(&m3u8_media_bytes) {
-                    Ok(media_playlist) => (media_playlist_url, media_playlist),
-                    Err(_) => anyhow::bail!("failed to parse m3u8 media playlist"),
+            match self
+                .download_range_attempt(ranged_download, range_index, range, &mut range_written)
+                .await
+            {
+                Ok(()) => return Ok(()),
+                Err(err) if failed_attempts < RANGE_RETRIES => {
+                    failed_attempts += 1;
+                    log::trace!(
+                        "Retrying byte range {} at {} bytes: {:#}",
+                        range_index,
+                        range_written,
+                        err
+                    );
+                    tokio::time::sleep(Duration::from_secs(1)).await;
                 }
+                Err(err) => return Err(err),
             }
-            Ok(m3u8_rs::Playlist::MediaPlaylist(playlist)) => {
-                if playlist.i_frames_only {
-                    anyhow::bail!("is iframe media playlist");
-                }
+        }
+    }
 
-                (m3u8_url, playlist)
-            }
-            Err(_) => anyhow::bail!("failed to parse m3u8"),
-        };
+    async fn download_range_attempt(
+        &self,
+        ranged_download: &RangedDownload<'_>,
+        range_index: usize,
+        range: &Range<u64>,
+        range_written: &mut u64,
+    ) -> Result<(), anyhow::Error> {
+        let range_length = range.end - range.start;
+        let range_header = get_range_header(range, *range_written);
+        let response = get_response(
+            self.client.as_ref(),
+            ranged_download.url.clone(),
+            self.user_agent.as_deref(),
+            ranged_download.task.referer.as_deref(),
+            Some(&[(reqwest::header::RANGE, &range_header)]),
+        )
+        .await?;
 
-        let (sub_progresses_index, progress_bar) = self.create_progress_bar(message, u64::MAX);
-        let mut output_stream = tokio::io::BufWriter::new(target_file);
-        let mut downloaded_bytes = 0;
-        let total_duration: f64 = media_playlist
-            .segments
-            .iter()
-            .map(|segment| segment.duration as f64)
-            .sum();
-        let mut downloaded_duration: f64 = 0.0;
-        let mut total_bytes_estimation = None;
-
-        for segment in media_playlist.segments {
-            let segment_url = match media_playlist_url.join(&segment.uri) {
-                Ok(segment_url) => segment_url,
-                Err(err) => {
-                    self.error_cleanup_progress_bar(&progress_bar, sub_progresses_index);
-                    return Err(err).with_context(|| "failed to create m3u8 segment url");
-                }
-            };
-            let response = match get_response(
-                self.client.as_ref(),
-                segment_url,
-                self.user_agent.as_deref(),
-                referer,
-                None,
-            )
+        if response.status() != reqwest::StatusCode::PARTIAL_CONTENT {
+            anyhow::bail!("server does not support range requests");
+        }
+
+        let mut target_file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .open(&ranged_download.part_file.part_path)
             .await
-            {
-                Ok(response) => response,
-                Err(err) => {
-                    self.error_cleanup_progress_bar(&progress_bar, sub_progresses_index);
-                    return Err(err).with_context(|| "failed to get segment response");
-                }
-            };
-            let mut input_stream = response.bytes_stream_resumable();
+            .with_context(|| "failed to open download target file")?;
+        target_file
+            .seek(SeekFrom::Start(range.start + *range_written))
+            .await
+            .with_context(|| "failed to seek in download file")?;
 
-            while let Some(item) = input_stream.next().await {
-                let mut chunk = match item {
-                    Ok(chunk) => chunk,
-                    Err(err) => {
-                        self.error_cleanup_progress_bar(&progress_bar, sub_progresses_index);
-                        return Err(err).with_context(|| "failed download");
-                    }
+        let mut input_stream = response.response().bytes_stream();
+        let mut output_stream = tokio::io::BufWriter::new(target_file);
+        let mut unsaved = 0;
+
+        let stream_result = async {
+            loop {
+                let item = tokio::time::timeout(RANGE_STREAM_TIMEOUT, input_stream.next())
+                    .await
+                    .with_context(|| "download stalled")?;
+                let Some(item) = item else {
+                    return Ok::<_, anyhow::Error>(());
                 };
+                let mut chunk = item.with_context(|| "failed download")?;
+                // Never write into the next range, even if the server sends too much
+                chunk.truncate((range_length - *range_written - unsaved) as usize);
+                let chunk_length = chunk.len() as u64;
+                self.limit_rate_of(ranged_download.task, chunk_length).await;
+
+                output_stream
+                    .write_all_buf(&mut chunk)
+                    .await
+                    .with_context(|| "failed writing to download file")?;
+
+                unsaved += chunk_length;
+                ranged_download
+                    .downloaded
+                    .set(ranged_download.downloaded.get() + chunk_length);
+                self.update_progress(
+                    &ranged_download.progress_bar,
+                    ranged_download.downloaded.get(),
+                    Some(ranged_download.content_length),
+                );
 
-                downloaded_bytes += chunk.len() as u64;
+                let is_complete = *range_written + unsaved == range_length;
 
-                if let Err(err) = output_stream.write_all_buf(&mut chunk).await {
-                    self.error_cleanup_progress_bar(&progress_bar, sub_progresses_index);
-                    return Err(err).with_context(|| "failed writing to download file");
+                if unsaved >= RESUME_STATE_SAVE_INTERVAL || is_complete {
+                    output_stream
+                        .flush()
+                        .await
+                        .with_context(|| "failed flushing to download file")?;
+
+                    *range_written += unsaved;
+                    unsaved = 0;
+                    ranged_download.save_range_state(range_index, *range_written).await?;
                 }
 
-                self.update_progress(&progress_bar, downloaded_bytes, total_bytes_estimation);
+                if is_complete {
+                    return Ok(());
+                }
             }
-
-            downloaded_duration += segment.duration as f64;
-            total_bytes_estimation =
-                Some(((downloaded_bytes as f64 * total_duration) / downloaded_duration).ceil() as u64);
         }
+        .await;
 
-        if let Err(err) = Self::clean_up_write(output_stream).await {
-            self.clean_up_progress_bar(&progress_bar, sub_progresses_index);
+        // Bytes that were not saved are downloaded again by the next attempt
+        if let Err(err) = stream_result {
+            ranged_download
+                .downloaded
+                .set(ranged_download.downloaded.get() - unsaved);
             return Err(err);
         }
 
-        if let Some(ffmpeg_path) = &self.ffmpeg_path {
-            let mut ffmpeg_cmd = tokio::process::Command::new(ffmpeg_path);
-
-            if !self.debug {
-                ffmpeg_cmd
-                    .stdin(Stdio::null())
-                    .stdout(Stdio::null())
-                    .stderr(Stdio::null());
-                ffmpeg_cmd.arg("-nostdin");
-            }
+        Self::clean_up_write(output_stream).await?;
 
-            let ffmpeg_spawn_result = ffmpeg_cmd
-                .arg("-i")
-                .arg(&target_path)
-                .arg("-c")
-                .arg("copy")
-                .arg(target_path.with_extension("mp4"))
-                .spawn();
-
-            match ffmpeg_spawn_result {
-                Ok(mut child) => match child.wait().await {
-                    Ok(ffmpeg_result) => match ffmpeg_result.code() {
-                        Some(code) if code != 0 => log::warn!("FFmpeg failed with exit code {}", code),
-                        None => log::warn!("FFmpeg failed due to signal termination"),
-                        _ => {
-                            if let Err(err) = remove_file_ignore_not_exists(&target_path).await {
-                                log::warn!("Failed to delete temporary input file for FFmpeg: {}", err);
-                            }
-                        }
-                    },
-                    Err(err) => {
-                        log::warn!("FFmpeg was not running: {}", err);
-                    }
-                },
-                Err(err) => {
-                    log::warn!("Failed to start FFmpeg: {}", err);
-                }
-            }
-        } else {
-            let temp_name = target_path
-                .file_name()
-                .unwrap_or(target_path.as_os_str())
-                .to_string_lossy();
-            log::info!(
-                "Failed to convert \"{}\" to MP4 due to FFmpeg not being installed",
-                temp_name
-            );
+        if *range_written < range_length {
+            anyhow::bail!("download ended before the end of the byte range");
         }
 
-        self.clean_up_progress_bar(&progress_bar, sub_progresses_index);
-
         Ok(())
     }
 
-    async fn clean_up_write(mut output_stream: tokio::io::BufWriter<tokio::fs::File>) -> Result<(), anyhow::Error> {
-        if let Err(err) = output_stream.flush().await {
-            return Err(err).with_context(|| "failed flushing to download file");
-        }
-
-        if let Err(err) = output_stream.get_mut().sync_all().await {
-            return Err(err).with_context(|| "failed syncing download file to disk");
-        }
-
-        Ok(())
-    }
+    async fn m3u8_download(
+        &self,
+        response: reqwest_partial_retry::ResumableResponse,
+        task: &InternalDownloadTask,
+        m3u8_url: Url,
+        final_path: PathBuf,
+        message: String,
+    ) -> Result<PathBuf, anyhow::Error> {
+        let referer = task.referer.as_deref();
+        let m3u8_bytes = get_response_bytes(response.response()).await?;
+        let resumable = !task.overwrite_file;
 
-    fn update_progress(&self, progress_bar: &indicatif::ProgressBar, downloaded: u64, total_bytes: Option<u64>) {
-        progress_bar.update(|state| {
-            if !(state.len() == Some(u64::MAX) && total_bytes.is_none()) {
-                state.set_len(total_bytes.unwrap_or(0).max(downloaded));
-            }
+        let (media_playlist_url, media_playlist, renditions, variant_has_audio) =
+            match m3u8_rs::parse_playlist_res(&m3u8_bytes) {
+                Ok(m3u8_rs::Playlist::MasterPlaylist(playlist)) => {
+                    if playlist.variants.is_empty() {
+                        anyhow::bail!("could not find any media playlists");
+                    }
 
-            state.set_pos(downloaded);
-        });
+                    let Some(selected_variant) = task.quality.select_variant(&playlist.variants) else {
+                        anyhow::bail!("could not find a non-iframe media playlist");
+                    };
+
+                    let media_playlist_url = m3u8_url
+                        .join(&selected_variant.uri)
+                        .with_context(|| "failed to create m3u8 media playlist url")?;
+                    let media_playlist = self.get_media_playlist(&media_playlist_url, referer).await?;
+                    let renditions = get_renditions(&playlist, selected_variant, &m3u8_url)?;
+
+                    (
+                        media_playlist_url,
+                        media_playlist,
+                        renditions,
+                        variant_has_audio(&playlist, selected_variant),
+                    )
+                }
+                Ok(m3u8_rs::Playlist::MediaPlaylist(playlist)) => {
+                    if playlist.i_frames_only {
+                        anyhow::bail!("is iframe media playlist");
+                    }
 
-        self.update_progress_total(true, true)
-    }
+                    (m3u8_url, playlist, Vec::new(), true)
+                }
+                Err(_) => anyhow::bail!("failed to parse m3u8"),
+            };
 
-    fn update_progress_total(&self, bytes: bool, message: bool) {
-        if !bytes && !message {
-            return;
+        let mut tracks = vec![M3u8Track::from_media_playlist(
+            &media_playlist,
+            &media_playlist_url,
+            &final_path,
+            None,
+            resumable,
+        )?];
+
+        for (rendition_index, rendition) in renditions.iter().enumerate() {
+            let rendition_playlist = self.get_media_playlist(&rendition.url, referer).await?;
+            let suffix = format!("{}{}", rendition.kind.name(), rendition_index + 1);
+
+            tracks.push(M3u8Track::from_media_playlist(
+                &rendition_playlist,
+                &rendition.url,
+                &final_path,
+                Some(&suffix),
+                resumable,
+            )?);
         }
 
-        let sub_progresses_lock = self.sub_progresses.borrow();
+        let metadata_args = get_rendition_metadata_args(&renditions, variant_has_audio);
+        self.download_tracks(task, tracks, final_path, message, metadata_args)
+            .await
+    }
 
-        let updated_bytes = if bytes {
-            let total_downloaded: u64 = sub_progresses_lock
-                .iter()
-                .map(|pb| pb.position())
-                .reduce(|acc, e| acc.saturating_add(e))
-                .unwrap();
-            let total_length: u64 = sub_progresses_lock
+    /// Downloads the selected video representation and one audio representation
+    /// per language of a static mpd manifest.
+    async fn dash_download(
+        &self,
+        response: reqwest_partial_retry::ResumableResponse,
+        task: &InternalDownloadTask,
+        mpd_url: Url,
+        final_path: PathBuf,
+        message: String,
+    ) -> Result<PathBuf, anyhow::Error> {
+        let mpd_bytes = get_response_bytes(response.response()).await?;
+        let mpd = std::str::from_utf8(&mpd_bytes).with_context(|| "mpd is not valid utf-8")?;
+        let mpd = dash::parse_mpd(mpd, &mpd_url)?;
+        let resumable = !task.overwrite_file;
+
+        let video_representation = task.quality.select_representation(
+            mpd.adaptation_sets
                 .iter()
-                .filter_map(|pb| pb.length())
-                .reduce(|acc, e| acc.saturating_add(e))
-                .unwrap();
-            Some((total_downloaded, total_length))
-        } else {
-            None
-        };
+                .filter(|adaptation_set| adaptation_set.content_type == dash::ContentType::Video)
+                .flat_map(|adaptation_set| &adaptation_set.representations),
+        );
 
-        let updated_message = if message {
-            let total_finished = sub_progresses_lock.iter().filter(|pb| pb.is_finished()).count();
-            let total_bars = sub_progresses_lock.len();
-            Some(format!("Total {total_finished}/{total_bars}"))
-        } else {
-            None
-        };
+        let mut audio_representations = Vec::new();
+        let mut audio_languages = Vec::new();
 
-        drop(sub_progresses_lock);
+        for adaptation_set in &mpd.adaptation_sets {
+            if adaptation_set.content_type != dash::ContentType::Audio
+                || audio_languages.contains(&adaptation_set.language)
+            {
+                continue;
+            }
 
-        let total_progress_lock = self.total_progress.borrow();
-        let total_progress = total_progress_lock.as_ref().unwrap();
+            let by_bandwidth = |a: &&dash::Representation, b: &&dash::Representation| a.bandwidth.cmp(&b.bandwidth);
+            let representation = if task.quality == VariantQuality::Worst {
+                adaptation_set.representations.iter().min_by(by_bandwidth)
+            } else {
+                adaptation_set.representations.iter().max_by(by_bandwidth)
+            };
 
-        if let Some((total_downloaded, total_length)) = updated_bytes {
-            total_progress.update(|state| {
-                state.set_len(total_length);
-                state.set_pos(total_downloaded);
-            });
+            if let Some(representation) = representation {
+                audio_languages.push(adaptation_set.language.clone());
+                audio_representations.push((adaptation_set, representation));
+            }
         }
 
-        if let Some(message) = updated_message {
-            total_progress.set_message(message);
+        if video_representation.is_none() && audio_representations.is_empty() {
+            anyhow::bail!("could not find any video or audio representations in mpd");
         }
 
-        drop(total_progress_lock);
-    }
+        let mut tracks = Vec::new();
+        let mut renditions = Vec::new();
 
-    fn create_total_progress_bar() -> indicatif::ProgressBar {
-        indicatif::ProgressBar::new(u64::MAX)
-            .with_style(custom_progress_style(
-                indicatif::ProgressStyle::with_template(
-                    "[{elapsed_precise}] {wide_msg} {binary_bytes_per_sec:>14} {bytes:>10}{total_bytes:<11} [{bar}] {eta:>5} {percent:>3}%",
-                )
-                .unwrap()
-            ))
-            .with_message("Total 0/1")
-    }
+        if let Some(representation) = video_representation {
+            tracks.push(M3u8Track::from_representation(
+                representation,
+                &mpd_url,
+                &final_path,
+                None,
+                resumable,
+            )?);
+        }
 
-    fn create_progress_bar(&self, name: String, bytes: u64) -> (usize, indicatif::ProgressBar) {
-        let pb = indicatif::ProgressBar::new(bytes)
-            .with_style(custom_progress_style(
-                indicatif::ProgressStyle::with_template(
-                    "[{elapsed_precise}] {wide_msg} {binary_bytes_per_sec:>14} {bytes:>10}{total_bytes:<11} [{bar}] {eta:>5} {percent:>3}%",
-                )
-                .unwrap()
-            ))
-            .with_message(name);
-        self.post_prepare_progress_bar(pb)
-    }
+        for (audio_index, (adaptation_set, representation)) in audio_representations.into_iter().enumerate() {
+            let suffix = format!("{}{}", RenditionKind::Audio.name(), audio_index + 1);
+
+            tracks.push(M3u8Track::from_representation(
+                representation,
+                &mpd_url,
+                &final_path,
+                Some(&suffix),
+                resumable,
+            )?);
+            renditions.push(HlsRendition {
+                kind: RenditionKind::Audio,
+                url: mpd_url.clone(),
+                name: adaptation_set
+                    .label
+                    .clone()
+                    .or_else(|| adaptation_set.language.clone())
+                    .unwrap_or_else(|| representation.id.clone()),
+                language: adaptation_set.language.clone(),
+            });
+        }
 
-    fn create_progress_bar_unknown_bytes(&self, name: String) -> (usize, indicatif::ProgressBar) {
-        let pb = indicatif::ProgressBar::new(0)
-            .with_style(custom_progress_style(
-                indicatif::ProgressStyle::with_template(
-                    "[{elapsed_precise}] {wide_msg} {binary_bytes_per_sec:>14} {bytes:>10}",
-                )
-                .unwrap(),
-            ))
-            .with_message(name);
-        self.post_prepare_progress_bar(pb)
+        // Video representations of separate audio adaptation sets carry no audio
+        let metadata_args = get_rendition_metadata_args(&renditions, false);
+        self.download_tracks(task, tracks, final_path, message, metadata_args)
+            .await
     }
 
-    fn post_prepare_progress_bar(&self, progress_bar: indicatif::ProgressBar) -> (usize, indicatif::ProgressBar) {
-        let mut total_progress_lock = self.total_progress.borrow_mut();
+    /// Downloads all segment tracks and muxes them into the final file. Returns the
+    /// final file, or the first temporary file if FFmpeg is not installed.
+    async fn download_tracks(
+        &self,
+        task: &InternalDownloadTask,
+        tracks: Vec<M3u8Track>,
+        final_path: PathBuf,
+        message: String,
+        metadata_args: Vec<String>,
+    ) -> Result<PathBuf, anyhow::Error> {
+        let (sub_progresses_index, progress_bar) = self.create_progress_bar(message, u64::MAX, task.episode.as_deref());
+        let progress = M3u8Progress {
+            progress_bar,
+            total_duration: tracks
+                .iter()
+                .flat_map(|track| &track.segments)
+                .map(|segment| segment.duration)
+                .sum(),
+            downloaded_bytes: Cell::new(0),
+            downloaded_duration: Cell::new(0.0),
+            received_bytes: Cell::new(0),
+            total_bytes_estimation: Cell::new(None),
+        };
 
-        if total_progress_lock.is_none() {
-            let new_total_progress = Self::create_total_progress_bar();
-            *total_progress_lock = Some(self.multi_progress.add(new_total_progress));
-        }
+        // Tracks are played in parallel, so the longest one determines the duration of the file
+        let media_duration = tracks
+            .iter()
+            .map(|track| track.segments.iter().map(|segment| segment.duration).sum::<f64>())
+            .fold(0.0, f64::max);
+
+        if task.pipe_to_ffmpeg {
+            match (&self.ffmpeg_path, tracks.as_slice()) {
+                (Some(ffmpeg_path), [track]) if track.blocks.len() == 1 => {
+                    self.emit_download_event(sub_progresses_index, "remux_started", serde_json::json!({}));
+                    let mut result = self
+                        .pipe_track_to_ffmpeg(task, ffmpeg_path, track, &final_path, metadata_args, &progress)
+                        .await;
+
+                    if result.is_ok() && task.verify {
+                        result = self.verify_download(&final_path, Some(media_duration), None, None).await;
+                    }
 
-        let pb = self
-            .multi_progress
-            .insert_before(total_progress_lock.as_ref().unwrap(), progress_bar);
+                    if result.is_ok() {
+                        self.clean_up_progress_bar(&progress.progress_bar, sub_progresses_index);
+                    } else {
+                        self.error_cleanup_progress_bar(&progress.progress_bar, sub_progresses_index);
+                    }
 
-        drop(total_progress_lock);
+                    return result.map(|_| final_path);
+                }
+                (None, _) => log::warn!("Writing temporary files, because FFmpeg is not installed"),
+                _ => log::info!(
+                    "Writing temporary files, because only a single track without discontinuities can be piped into FFmpeg"
+                ),
+            }
+        }
 
-        let mut sub_progresses_lock = self.sub_progresses.borrow_mut();
-        let sub_progresses_index = sub_progresses_lock.len();
-        sub_progresses_lock.push(ProgressBarOrResult::ProgressBar(pb.clone()));
-        drop(sub_progresses_lock);
+        // Finished blocks are kept until they are joined, so that they do not have to be downloaded again
+        let state_paths = tracks
+            .iter()
+            .flat_map(|track| &track.part_files)
+            .map(|part_file| part_file.state_path.clone())
+            .collect::<Vec<_>>();
+        self.unfinished_files.add_resumable(
+            tracks
+                .iter()
+                .flat_map(|track| &track.part_files)
+                .map(|part_file| &part_file.final_path)
+                .chain(&state_paths),
+        );
+
+        for track in &tracks {
+            for (block_index, (block, block_part_file)) in track.blocks.iter().zip(&track.part_files).enumerate() {
+                let block_state_url = if block_index == 0 {
+                    Cow::Borrowed(&track.state_url)
+                } else {
+                    Cow::Owned(format!("{}#{}", track.state_url, block_index))
+                };
 
-        pb.tick();
-        self.update_progress_total(true, true);
+                if let Err(err) = self
+                    .download_m3u8_block(
+                        task,
+                        &block_state_url,
+                        &track.segments[block.clone()],
+                        track.container,
+                        block_part_file,
+                        &progress,
+                    )
+                    .await
+                {
+                    self.error_cleanup_progress_bar(&progress.progress_bar, sub_progresses_index);
+                    return Err(err);
+                }
+            }
+        }
 
-        (sub_progresses_index, pb)
-    }
+        let track_paths = tracks
+            .into_iter()
+            .map(|track| {
+                track
+                    .part_files
+                    .into_iter()
+                    .map(|part_file| part_file.final_path)
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        let first_target_path = &track_paths[0][0];
+
+        let downloaded_path = if let Some(ffmpeg_path) = &self.ffmpeg_path {
+            let mut ffmpeg_cmd = ffmpeg_command(ffmpeg_path, false);
+
+            let mut concat_list_paths = Vec::new();
+
+            for (track_index, target_paths) in track_paths.iter().enumerate() {
+                // Discontinuity blocks are joined with the concat demuxer, which
+                // handles the timestamp jumps between them
+                if target_paths.len() > 1 {
+                    let concat_list_path = path_with_added_extension(&final_path, &format!("{}.ffconcat", track_index));
+                    let mut concat_list = "ffconcat version 1.0\n".to_string();
+
+                    for target_path in target_paths {
+                        let _ = writeln!(concat_list, "file {}", escape_ffconcat_path(target_path));
+                    }
 
-    fn clean_up_progress_bar(&self, progress_bar: &indicatif::ProgressBar, sub_progresses_index: usize) {
-        progress_bar.finish();
+                    if let Err(err) = tokio::fs::write(&concat_list_path, concat_list).await {
+                        self.clean_up_progress_bar(&progress.progress_bar, sub_progresses_index);
+                        return Err(err).with_context(|| "failed to write FFmpeg concat list");
+                    }
 
-        let position = progress_bar.position();
-        let mut sub_progresses_lock = self.sub_progresses.borrow_mut();
-        sub_progresses_lock[sub_progresses_index] = ProgressBarOrResult::Finished { length: position };
-        drop(sub_progresses_lock);
+                    ffmpeg_cmd
+                        .args(["-f", "concat", "-safe", "0", "-i"])
+                        .arg(&concat_list_path);
+                    concat_list_paths.push(concat_list_path);
+                } else {
+                    ffmpeg_cmd.arg("-i").arg(&target_paths[0]);
+                }
+            }
 
-        self.update_progress_total(true, true);
-    }
+            for track_index in 0..track_paths.len() {
+                ffmpeg_cmd.arg("-map").arg(track_index.to_string());
+            }
 
-    fn error_cleanup_progress_bar(&self, progress_bar: &indicatif::ProgressBar, sub_progresses_index: usize) {
-        progress_bar.abandon();
+            ffmpeg_cmd.args([
+                "-c",
+                "copy",
+                "-c:s",
+                task.container.unwrap_or_default().subtitle_codec(),
+            ]);
 
-        let position = progress_bar.position();
-        let length = progress_bar.length();
-        let mut sub_progresses_lock = self.sub_progresses.borrow_mut();
-        sub_progresses_lock[sub_progresses_index] = ProgressBarOrResult::Abandoned { position, length };
-        drop(sub_progresses_lock);
-    }
+            if let Some(metadata) = &task.metadata {
+                ffmpeg_cmd.args(metadata.ffmpeg_args());
+            }
 
-    fn clean_up_total_progress_bar(&self) {
-        if let Some(total_progress) = self.total_progress.borrow().deref() {
-            let all_finished = self.sub_progresses.borrow().iter().all(|pb| pb.is_finished());
+            ffmpeg_cmd.args(metadata_args);
 
-            if all_finished {
-                total_progress.finish();
-            } else {
-                total_progress.abandon();
-            }
-        }
-    }
+            self.emit_download_event(sub_progresses_index, "remux_started", serde_json::json!({}));
 
-    /// This function never finishes. It should be used in a select! expression.
-    pub(crate) async fn tick(&self) {
-        const TICK_INTERVAL: Duration = Duration::from_millis(100);
+            self.unfinished_files.add_incomplete(&final_path);
+            let ffmpeg_result = match ffmpeg_cmd.arg(&final_path).spawn() {
+                Ok(mut child) => {
+                    self.wait_for_ffmpeg(&mut child, Some((&progress.progress_bar, media_duration)))
+                        .await
+                }
+                Err(err) => Err(err).with_context(|| "failed to start FFmpeg"),
+            };
+            self.unfinished_files.remove_incomplete(&final_path);
 
-        loop {
-            for sub_progress in self.sub_progresses.borrow().deref() {
-                if let ProgressBarOrResult::ProgressBar(pb) = &sub_progress {
-                    if !pb.is_finished() {
-                        pb.tick();
-                    }
+            for concat_list_path in concat_list_paths {
+                if let Err(err) = remove_file_ignore_not_exists(&concat_list_path).await {
+                    log::warn!("Failed to delete FFmpeg concat list: {}", err);
                 }
             }
 
-            if let Some(total_progress) = self.total_progress.borrow().deref() {
-                if !total_progress.is_finished() {
-                    total_progress.tick();
+            // The downloaded files are kept, so that only the remux is repeated on the next try
+            if let Err(err) = ffmpeg_result {
+                if let Err(err) = remove_file_ignore_not_exists(&final_path).await {
+                    log::warn!("Failed to delete incomplete output file of FFmpeg: {}", err);
                 }
+
+                self.error_cleanup_progress_bar(&progress.progress_bar, sub_progresses_index);
+                return Err(err).with_context(|| LocalError("failed to join the tracks"));
             }
 
-            tokio::time::sleep(TICK_INTERVAL).await;
-        }
-    }
+            for target_path in track_paths.iter().flatten() {
+                if let Err(err) = remove_file_ignore_not_exists(target_path).await {
+                    log::warn!("Failed to delete temporary input file for FFmpeg: {}", err);
+                }
+            }
 
-    pub(crate) fn clear(self) {
-        self.clean_up_total_progress_bar();
-        drop(self.total_progress.take());
-        let _ = self.multi_progress.clear();
-    }
-}
+            remove_block_state_files(&state_paths).await;
+            self.unfinished_files
+                .remove_resumable(track_paths.iter().flatten().chain(&state_paths));
+
+            if task.verify {
+                if let Err(err) = self
+                    .verify_download(&final_path, Some(media_duration), None, None)
+                    .await
+                {
+                    self.error_cleanup_progress_bar(&progress.progress_bar, sub_progresses_index);
+                    return Err(err);
+                }
+            }
 
-impl Drop for Downloader {
-    fn drop(&mut self) {
-        self.clean_up_total_progress_bar();
+            final_path
+        } else {
+            let temp_name = first_target_path
+                .file_name()
+                .unwrap_or(first_target_path.as_os_str())
+                .to_string_lossy();
+            log::info!(
+                "Failed to convert \"{}\" to {} due to FFmpeg not being installed",
+                temp_name,
+                task.container.unwrap_or_default().extension().to_ascii_uppercase()
+            );
+            remove_block_state_files(&state_paths).await;
+            self.unfinished_files
+                .remove_resumable(track_paths.iter().flatten().chain(&state_paths));
+
+            first_target_path.clone()
+        };
+
+        self.clean_up_progress_bar(&progress.progress_bar, sub_progresses_index);
+
+        Ok(downloaded_path)
     }
-}
 
-fn custom_progress_style(progress_style: indicatif::ProgressStyle) -> indicatif::ProgressStyle {
-    use indicatif::{HumanDuration, ProgressState};
-    use number_prefix::NumberPrefix;
+    async fn get_media_playlist(
+        &self,
+        media_playlist_url: &Url,
+        referer: Option<&str>,
+    ) -> Result<m3u8_rs::MediaPlaylist, anyhow::Error> {
+        let m3u8_media_bytes =
+            get_page_bytes(media_playlist_url.as_str(), self.user_agent.as_deref(), referer, None).await?;
+
+        match m3u8_rs::parse_media_playlist_res(&m3u8_media_bytes) {
+            Ok(media_playlist) if media_playlist.i_frames_only => anyhow::bail!("is iframe media playlist"),
+            Ok(media_playlist) => Ok(media_playlist),
+            Err(_) => anyhow::bail!("failed to parse m3u8 media playlist"),
+        }
+    }
 
-    progress_style
-        .with_key("bytes", |state: &ProgressState, w: &mut dyn Write| {
-            let _ = match NumberPrefix::binary(state.pos() as f64) {
-                NumberPrefix::Standalone(number) => write!(w, "{number:.0} B"),
-                NumberPrefix::Prefixed(prefix, number) => write!(w, "{number:.1} {prefix}B"),
-            };
-        })
-        .with_key("total_bytes", |state: &ProgressState, w: &mut dyn Write| {
-            // Only if total bytes are known
-            if state.len() != Some(u64::MAX) {
-                let _ = write!(w, "/");
-                let _ = match NumberPrefix::binary(state.len().unwrap() as f64) {
-                    NumberPrefix::Standalone(number) => write!(w, "{number:.0} B"),
-                    NumberPrefix::Prefixed(prefix, number) => write!(w, "{number:.1} {prefix}B"),
-                };
-            }
-        })
-        .with_key("binary_bytes_per_sec", |state: &ProgressState, w: &mut dyn Write| {
-            let _ = match NumberPrefix::binary(state.per_sec()) {
-                NumberPrefix::Standalone(number) => write!(w, "{number:.0} B/s"),
-                NumberPrefix::Prefixed(prefix, number) => write!(w, "{number:.1} {prefix}B/s"),
-            };
-        })
-        .with_key("percent", |state: &ProgressState, w: &mut dyn Write| {
-            let mut percent = ((state.fraction() * 100.0).floor() as u32).clamp(0, 100);
+    /// Pipes the segments of a track into FFmpeg while they are downloaded, so that
+    /// only the final file is written. Such downloads can not be resumed.
+    async fn pipe_track_to_ffmpeg(
+        &self,
+        task: &InternalDownloadTask,
+        ffmpeg_path: &Path,
+        track: &M3u8Track,
+        final_path: &Path,
+        metadata_args: Vec<String>,
+        progress: &M3u8Progress,
+    ) -> Result<(), anyhow::Error> {
+        let mut ffmpeg_cmd = ffmpeg_command(ffmpeg_path, true);
+        ffmpeg_cmd.args(["-i", "pipe:0", "-map", "0", "-c", "copy", "-c:s"]);
+        ffmpeg_cmd.arg(task.container.unwrap_or_default().subtitle_codec());
 
-            if percent == 100 && !state.is_finished() {
-                percent = 99;
-            }
+        if let Some(metadata) = &task.metadata {
+            ffmpeg_cmd.args(metadata.ffmpeg_args());
+        }
 
-            let _ = write!(w, "{}", percent);
-        })
-        .with_key("bar", |state: &ProgressState, w: &mut dyn Write| {
-            const BAR_WIDTH: usize = 40;
-            const FILLED_STR: &str = "#";
-            const IN_PROGRESS_STR: &str = ">";
-            const TODO_STR: &str = "-";
+        ffmpeg_cmd.args(metadata_args);
+        // An existing file was already rejected unless it should be overwritten
+        ffmpeg_cmd.arg("-y").arg(final_path);
+
+        let mut child = ffmpeg_cmd.spawn().with_context(|| "failed to start FFmpeg")?;
+        let stdin = child.stdin.take().with_context(|| "failed to get stdin of FFmpeg")?;
+        self.unfinished_files.add_incomplete(final_path);
+
+        let write_future = async {
+            let mut input_stream = tokio::io::BufWriter::new(stdin);
+            self.write_segments(
+                task,
+                &track.segments,
+                track.container,
+                &mut input_stream,
+                None,
+                progress,
+            )
+            .await?;
 
-            let mut filled = ((state.fraction() * BAR_WIDTH as f32) as usize).min(BAR_WIDTH);
+            // Closing stdin ends the input of FFmpeg
+            input_stream
+                .shutdown()
+                .await
+                .with_context(|| "failed to close stdin of FFmpeg")
+        };
+        let (write_result, ffmpeg_result) = tokio::join!(write_future, self.wait_for_ffmpeg(&mut child, None));
+        self.unfinished_files.remove_incomplete(final_path);
 
-            if filled == BAR_WIDTH && !state.is_finished() {
-                filled = BAR_WIDTH - 1;
+        // Writing fails as well if FFmpeg exits early, but then its error is more helpful
+        if let Err(err) = ffmpeg_result.and(write_result) {
+            if let Err(err) = remove_file_ignore_not_exists(final_path).await {
+                log::warn!("Failed to delete incomplete output file of FFmpeg: {}", err);
             }
 
-            let non_filled = BAR_WIDTH - filled;
-            let in_progress = 1.min(non_filled);
-            let todo = BAR_WIDTH - filled - in_progress;
+            return Err(err);
+        }
 
-            let _ = write!(
-                w,
-                "{}{}{}",
-                console::style(FILLED_STR.repeat(filled)).cyan(),
-                console::style(IN_PROGRESS_STR.repeat(in_progress)).cyan(),
-                console::style(TODO_STR.repeat(todo)).blue(),
+        Ok(())
+    }
+
+    /// Downloads the segments of one discontinuity block into its own file.
+    /// Blocks that were already finished by an earlier run are skipped.
+    async fn download_m3u8_block(
+        &self,
+        task: &InternalDownloadTask,
+        state_url: &str,
+        segments: &[M3u8Segment],
+        container: HlsContainer,
+        part_file: &PartFile,
+        progress: &M3u8Progress,
+    ) -> Result<(), anyhow::Error> {
+        let total_segments = segments.len();
+
+        if let Some(block_bytes) = part_file.get_finished_block_length(state_url, total_segments).await {
+            let block_duration = segments.iter().map(|segment| segment.duration).sum();
+            progress.add_completed(block_bytes, block_duration);
+            self.update_progress(
+                &progress.progress_bar,
+                progress.received_bytes.get(),
+                progress.total_bytes_estimation.get(),
             );
-        })
-        .with_key("eta", |state: &ProgressState, w: &mut dyn Write| {
-            // Only if total bytes are known
-            if state.len() != Some(u64::MAX) {
-                let _ = write!(w, "({:#})", HumanDuration(state.eta()));
-            }
-        })
-}
+            progress.progress_bar.reset_eta();
 
-pub(crate) async fn get_response<U: IntoUrl>(
-    client: Option<&reqwest_partial_retry::Client>,
-    url: U,
-    user_agent: Option<&str>,
-    referer: Option<&str>,
-    extra_headers: Option<&[(HeaderName, &str)]>,
-) -> Result<reqwest_partial_retry::ResumableResponse, anyhow::Error> {
-    // We need to handle redirects ourself, because reqwest changes the Referer
-    // header on redirection
-    let client = client.unwrap_or(DEFAULT_RETRY_CLIENT_NO_REDIRECT.deref());
-    let mut last_url = url.as_str().to_string();
-    let mut redirect_count = 0u32;
+            return Ok(());
+        }
 
-    loop {
-        let mut request = client.get(last_url);
+        let (target_file, resume_state) = self
+            .open_part_file(part_file, state_url, Some(total_segments as u64), None)
+            .await?;
+        let segments_completed = resume_state
+            .as_ref()
+            .and_then(|resume_state| resume_state.segments_completed)
+            .unwrap_or(0)
+            .min(total_segments);
+        let block_bytes = resume_state
+            .as_ref()
+            .map(|resume_state| resume_state.bytes_written)
+            .unwrap_or(0);
+        let mut resume_state = ResumeState {
+            url: state_url.to_string(),
+            total: Some(total_segments as u64),
+            bytes_written: block_bytes,
+            segments_completed: Some(segments_completed),
+            range_bytes_written: None,
+        };
+        part_file.save_state(&resume_state).await?;
 
-        if let Some(user_agent) = user_agent {
-            request = request.header(reqwest::header::USER_AGENT, user_agent);
+        if segments_completed > 0 {
+            let resumed_duration = segments
+                .iter()
+                .take(segments_completed)
+                .map(|segment| segment.duration)
+                .sum();
+            progress.add_completed(block_bytes, resumed_duration);
+            self.update_progress(
+                &progress.progress_bar,
+                progress.received_bytes.get(),
+                progress.total_bytes_estimation.get(),
+            );
+            progress.progress_bar.reset_eta();
         }
 
-        if let Some(referer) = referer {
-            request = request.header(reqwest::header::REFERER, referer);
-        }
+        let mut output_stream = tokio::io::BufWriter::new(target_file);
+        self.write_segments(
+            task,
+            segments,
+            container,
+            &mut output_stream,
+            Some((part_file, &mut resume_state)),
+            progress,
+        )
+        .await?;
 
-        if let Some(extra_headers) = extra_headers {
-            for (header, value) in extra_headers {
-                request = request.header(header, *value);
+        Self::clean_up_write(output_stream).await?;
+        part_file.finish_block().await?;
+        self.unfinished_files.remove_resumable([&part_file.part_path]);
+        Ok(())
+    }
+
+    async fn open_part_file(
+        &self,
+        part_file: &PartFile,
+        url: &str,
+        total: Option<u64>,
+        range_count: Option<usize>,
+    ) -> Result<(tokio::fs::File, Option<ResumeState>), anyhow::Error> {
+        self.unfinished_files
+            .add_resumable([&part_file.part_path, &part_file.state_path]);
+        part_file.open(url, total, range_count).await
+    }
+
+    async fn finish_part_file(&self, part_file: &PartFile) -> Result<(), anyhow::Error> {
+        part_file.finish().await?;
+        self.unfinished_files
+            .remove_resumable([&part_file.part_path, &part_file.state_path]);
+        Ok(())
+    }
+
+    /// Fetches the segments and writes them in playlist order. With a part file, the
+    /// completed segments of the resume state are skipped and the state is saved after
+    /// every written segment.
+    async fn write_segments<W: AsyncWrite + Unpin>(
+        &self,
+        task: &InternalDownloadTask,
+        segments: &[M3u8Segment],
+        container: HlsContainer,
+        output_stream: &mut W,
+        mut resume: Option<(&PartFile, &mut ResumeState)>,
+        progress: &M3u8Progress,
+    ) -> Result<(), anyhow::Error> {
+        let referer = task.referer.as_deref();
+        let segments_completed = resume
+            .as_ref()
+            .and_then(|(_, resume_state)| resume_state.segments_completed)
+            .unwrap_or(0);
+        let mut encryption_keys = HashMap::new();
+
+        for segment_encryption in segments.iter().skip(segments_completed).flat_map(|segment| {
+            segment
+                .encryption
+                .iter()
+                .chain(segment.init.as_ref().and_then(|init| init.encryption.as_ref()))
+        }) {
+            if !encryption_keys.contains_key(&segment_encryption.key_url) {
+                let key = self
+                    .get_encryption_key(segment_encryption.key_url.clone(), referer)
+                    .await?;
+                encryption_keys.insert(segment_encryption.key_url.clone(), key);
             }
         }
 
-        let response = client
-            .execute_resumable(request.build().with_context(|| "failed to build request")?)
-            .await
-            .with_context(|| "failed to request url")?;
+        // Segments are fetched concurrently, but written in playlist order
+        let segment_stream = futures_util::stream::iter(segments.iter().enumerate().skip(segments_completed))
+            .map(|(segment_index, segment)| {
+                // Blocks are split at every change of the init section, so it is only written at the start
+                let segment_init = segment.init.as_ref().filter(|_| segment_index == 0);
+                let encryption_keys = &encryption_keys;
 
-        let is_redirect_code = [301, 308, 302, 303, 307].contains(&response.status().as_u16());
-        let location_header = response.headers().get(reqwest::header::LOCATION);
+                async move {
+                    let mut segment_bytes = self
+                        .get_segment_bytes(segment.url.clone(), segment.byte_range.clone(), task, |chunk_length| {
+                            progress
+                                .received_bytes
+                                .set(progress.received_bytes.get() + chunk_length);
+                            self.update_progress(
+                                &progress.progress_bar,
+                                progress.received_bytes.get(),
+                                progress.total_bytes_estimation.get(),
+                            );
+                        })
+                        .await?;
+
+                    if let Some(segment_encryption) = &segment.encryption {
+                        let key = &encryption_keys[&segment_encryption.key_url];
+                        segment_bytes = decrypt_aes128_cbc(key, &segment_encryption.iv, &segment_bytes)
+                            .with_context(|| "failed to decrypt m3u8 segment")?
+                            .into();
+                    }
 
-        match (is_redirect_code, location_header) {
-            (true, Some(redirect_url)) => {
-                if redirect_count >= 10 {
-                    anyhow::bail!("more than 10 redirects");
+                    if let Some(segment_init) = segment_init {
+                        let mut init_bytes = self
+                            .get_segment_bytes(
+                                segment_init.url.clone(),
+                                segment_init.byte_range.clone(),
+                                task,
+                                |chunk_length| {
+                                    progress
+                                        .received_bytes
+                                        .set(progress.received_bytes.get() + chunk_length);
+                                },
+                            )
+                            .await
+                            .with_context(|| "failed to get m3u8 init section")?;
+
+                        if let Some(init_encryption) = &segment_init.encryption {
+                            let key = &encryption_keys[&init_encryption.key_url];
+                            init_bytes = decrypt_aes128_cbc(key, &init_encryption.iv, &init_bytes)
+                                .with_context(|| "failed to decrypt m3u8 init section")?
+                                .into();
+                        }
+                        segment_bytes = [init_bytes, segment_bytes].concat().into();
+                    }
+
+                    Ok::<_, anyhow::Error>((segment_index, segment_bytes))
                 }
+            })
+            .buffered(task.concurrent_segments.get() as usize);
+        tokio::pin!(segment_stream);
+        // Subtitle tracks are not resumable, so the first segment is always written in this run
+        let mut first_timestamp_offset = None;
 
-                redirect_count += 1;
-                last_url = redirect_url
-                    .to_str()
-                    .with_context(|| "redirect url could not be converted to string")?
-                    .to_string();
-            }
-            _ => return Ok(response),
-        }
-    }
-}
+        while let Some(result) = segment_stream.next().await {
+            let (segment_index, mut segment_bytes) = result?;
 
-pub(crate) async fn get_response_bytes(response: reqwest::Response) -> Result<bytes::Bytes, anyhow::Error> {
-    response
-        .bytes()
-        .await
-        .with_context(|| "failed to get bytes of response body")
-}
+            // Every segment of a subtitle playlist is a complete WebVTT file with its own timestamp mapping
+            if container == HlsContainer::WebVtt {
+                let timestamp_offset = parse_webvtt_timestamp_offset(&segment_bytes);
 
-pub(crate) async fn get_page_bytes<U: IntoUrl>(
-    url: U,
-    user_agent: Option<&str>,
-    referer: Option<&str>,
-    extra_headers: Option<&[(HeaderName, &str)]>,
-) -> Result<bytes::Bytes, anyhow::Error> {
-    get_response_bytes(
-        get_response(None, url, user_agent, referer, extra_headers)
-            .await?
-            .response(),
-    )
-    .await
-}
+                if segment_index == 0 {
+                    first_timestamp_offset = timestamp_offset;
+                }
 
-pub(crate) async fn get_page_text<U: IntoUrl>(
-    url: U,
-    user_agent: Option<&str>,
-    referer: Option<&str>,
-    extra_headers: Option<&[(HeaderName, &str)]>,
-) -> Result<String, anyhow::Error> {
-    get_response(None, url, user_agent, referer, extra_headers)
-        .await?
-        .response()
-        .text()
-        .await
-        .with_context(|| "failed to parse response body as text")
-}
+                let shift = timestamp_offset
+                    .zip(first_timestamp_offset)
+                    .map(|(offset, first_offset)| offset - first_offset)
+                    .unwrap_or(0.0);
 
-pub(crate) async fn get_page_json<U: IntoUrl>(
-    url: U,
-    user_agent: Option<&str>,
-    referer: Option<&str>,
-    extra_headers: Option<&[(HeaderName, &str)]>,
-) -> Result<serde_json::Value, anyhow::Error> {
-    get_response(None, url, user_agent, referer, extra_headers)
-        .await?
-        .response()
-        .json()
-        .await
-        .with_context(|| "failed to parse response body as json")
-}
+                if segment_index > 0 {
+                    let header_length = segment_bytes.len() - strip_webvtt_header(&segment_bytes).len();
+                    segment_bytes = segment_bytes.slice(header_length..);
+                }
 
-mod retry {
-    // Copied from reqwest_retry::DefaultRetryableStrategy
-    pub(crate) mod strategy {
-        use reqwest::StatusCode;
-        use reqwest_retry::{Retryable, RetryableStrategy};
+                if shift != 0.0 {
+                    segment_bytes = shift_webvtt_cues(&segment_bytes, shift).into();
+                }
+            }
 
-        pub struct CustomRetryStrategy;
+            let segment_length = segment_bytes.len() as u64;
 
-        impl RetryableStrategy for CustomRetryStrategy {
-            fn handle(&self, res: &Result<reqwest::Response, reqwest_middleware::Error>) -> Option<Retryable> {
-                match res {
-                    Ok(success) => default_on_request_success(success),
-                    Err(error) => default_on_request_failure(error),
-                }
+            output_stream
+                .write_all_buf(&mut segment_bytes)
+                .await
+                .with_context(|| "failed writing to download file")?;
+
+            // The state may only be saved after the segment is completely written to the file
+            output_stream
+                .flush()
+                .await
+                .with_context(|| "failed flushing to download file")?;
+
+            progress.add_written(segment_length, segments[segment_index].duration);
+
+            if let Some((part_file, resume_state)) = &mut resume {
+                resume_state.bytes_written += segment_length;
+                resume_state.segments_completed = Some(segment_index + 1);
+                part_file.save_state(resume_state).await?;
             }
         }
 
-        /// Default request success retry strategy.
-        ///
-        /// Will only retry if:
-        /// * The status was 5XX (server error)
-        /// * The status was 408 (request timeout) or 429 (too many requests)
-        ///
-        /// Note that success here means that the request finished without
-        /// interruption, not that it was logically OK.
-        pub fn default_on_request_success(success: &reqwest::Response) -> Option<Retryable> {
-            let status = success.status();
-            if status.is_server_error() {
-                Some(Retryable::Transient)
-            } else if status.is_client_error()
-                && status != StatusCode::REQUEST_TIMEOUT
-                && status != StatusCode::TOO_MANY_REQUESTS
-            {
-                Some(Retryable::Fatal)
-            } else if status.is_success() {
-                None
-            } else if status == StatusCode::REQUEST_TIMEOUT || status == StatusCode::TOO_MANY_REQUESTS {
-                Some(Retryable::Transient)
-            } else if [301, 308, 302, 303, 307].contains(&status.as_u16()) {
-                // NEW: now redirects won't be fatal anymore
-                None
-            } else {
-                Some(Retryable::Fatal)
+        Ok(())
+    }
+
+    async fn get_segment_bytes(
+        &self,
+        segment_url: Url,
+        byte_range: Option<Range<u64>>,
+        task: &InternalDownloadTask,
+        on_chunk: impl Fn(u64),
+    ) -> Result<bytes::Bytes, anyhow::Error> {
+        let Some(byte_range) = byte_range else {
+            let response = get_response(
+                self.client.as_ref(),
+                segment_url,
+                self.user_agent.as_deref(),
+                task.referer.as_deref(),
+                None,
+            )
+            .await
+            .with_context(|| "failed to get segment response")?;
+
+            let mut input_stream = response.bytes_stream_resumable();
+            let mut segment_bytes = bytes::BytesMut::new();
+
+            while let Some(item) = input_stream.next().await {
+                let chunk = item.with_context(|| "failed download")?;
+                on_chunk(chunk.len() as u64);
+                self.limit_rate_of(task, chunk.len() as u64).await;
+                segment_bytes.extend_from_slice(&chunk);
+            }
+
+            return Ok(segment_bytes.freeze());
+        };
+
+        let mut segment_bytes = bytes::BytesMut::new();
+        let mut failed_attempts = 0;
+
+        // Like byte ranges of direct downloads, the resumable stream of the client would
+        // set a Range header of its own, so the rest of the segment is requested again
+        loop {
+            match self
+                .get_segment_range_attempt(&segment_url, &byte_range, task, &on_chunk, &mut segment_bytes)
+                .await
+            {
+                Ok(()) => return Ok(segment_bytes.freeze()),
+                Err(err) if failed_attempts < RANGE_RETRIES => {
+                    failed_attempts += 1;
+                    log::trace!(
+                        "Retrying byte range of segment at {} bytes: {:#}",
+                        segment_bytes.len(),
+                        err
+                    );
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                }
+                Err(err) => return Err(err),
             }
         }
+    }
 
-        /// Default request failure retry strategy.
-        ///
-        /// Will only retry if the request failed due to a network error
-        pub fn default_on_request_failure(error: &reqwest_middleware::Error) -> Option<Retryable> {
-            match error {
-                // If something fails in the middleware we're screwed.
-                reqwest_middleware::Error::Middleware(_) => Some(Retryable::Fatal),
-                reqwest_middleware::Error::Reqwest(error) => {
-                    #[cfg(not(target_arch = "wasm32"))]
-                    let is_connect = error.is_connect();
-                    #[cfg(target_arch = "wasm32")]
-                    let is_connect = false;
-                    if error.is_timeout() || is_connect {
-                        Some(Retryable::Transient)
-                    } else if error.is_body() || error.is_decode() || error.is_builder() || error.is_redirect() {
-                        Some(Retryable::Fatal)
-                    } else if error.is_request() {
-                        // It seems that hyper::Error(IncompleteMessage) is not correctly handled by
-                        // reqwest. Here we check if the Reqwest error was
-                        // originated by hyper and map it consistently.
-                        #[cfg(not(target_arch = "wasm32"))]
-                        if let Some(hyper_error) = get_source_error_type::<hyper::Error>(&error) {
-                            // The hyper::Error(IncompleteMessage) is raised if the HTTP response is well
-                            // formatted but does not contain all the bytes.
-                            // This can happen when the server has started sending back the response but the
-                            // connection is cut halfway thorugh. We can safely
-                            // retry the call, hence marking this error as [`Retryable::Transient`].
-                            // Instead hyper::Error(Canceled) is raised when the connection is
-                            // gracefully closed on the server side.
-                            if hyper_error.is_incomplete_message() || hyper_error.is_canceled() {
-                                Some(Retryable::Transient)
+    async fn get_segment_range_attempt(
+        &self,
+        segment_url: &Url,
+        byte_range: &Range<u64>,
+        task: &InternalDownloadTask,
+        on_chunk: &impl Fn(u64),
+        segment_bytes: &mut bytes::BytesMut,
+    ) -> Result<(), anyhow::Error> {
+        let range_length = byte_range.end - byte_range.start;
+        let range_header = get_range_header(byte_range, segment_bytes.len() as u64);
+        let response = get_response(
+            self.client.as_ref(),
+            segment_url.clone(),
+            self.user_agent.as_deref(),
+            task.referer.as_deref(),
+            Some(&[(reqwest::header::RANGE, &range_header)]),
+        )
+        .await
+        .with_context(|| "failed to get segment response")?;
 
-                            // Try and downcast the hyper error to io::Error if
-                            // that is the
-                            // underlying error, and try and classify it.
-                            } else if let Some(io_error) = get_source_error_type::<std::io::Error>(hyper_error) {
-                                Some(classify_io_error(io_error))
-                            } else {
-                                Some(Retryable::Fatal)
-                            }
-                        } else {
-                            Some(Retryable::Fatal)
-                        }
-                        #[cfg(target_arch = "wasm32")]
-                        Some(Retryable::Fatal)
-                    } else {
-                        // We omit checking if error.is_status() since we check that already.
-                        // However, if Response::error_for_status is used the status will still
-                        // remain in the response object.
-                        None
+        // Some servers ignore the Range header and send the whole resource, which
+        // would be downloaded again for every segment of a single file playlist
+        let is_whole_resource =
+            byte_range.start == 0 && segment_bytes.is_empty() && response.content_length() == Some(byte_range.end);
+
+        if response.status() != reqwest::StatusCode::PARTIAL_CONTENT && !is_whole_resource {
+            anyhow::bail!("server does not support the byte range of the segment");
+        }
+
+        let mut input_stream = response.response().bytes_stream();
+
+        while (segment_bytes.len() as u64) < range_length {
+            let item = tokio::time::timeout(RANGE_STREAM_TIMEOUT, input_stream.next())
+                .await
+                .with_context(|| "download stalled")?;
+            let Some(item) = item else {
+                anyhow::bail!("segment ended after {} of {} bytes", segment_bytes.len(), range_length);
+            };
+            let mut chunk = item.with_context(|| "failed download")?;
+            chunk.truncate((range_length - segment_bytes.len() as u64) as usize);
+            on_chunk(chunk.len() as u64);
+            self.limit_rate_of(task, chunk.len() as u64).await;
+            segment_bytes.extend_from_slice(&chunk);
+        }
+
+        Ok(())
+    }
+
+    async fn get_encryption_key(&self, key_url: Url, referer: Option<&str>) -> Result<[u8; 16], anyhow::Error> {
+        let key_bytes = get_response_bytes(
+            get_response(self.client.as_ref(), key_url, self.user_agent.as_deref(), referer, None)
+                .await
+                .with_context(|| "failed to get m3u8 key response")?
+                .response(),
+        )
+        .await?;
+
+        key_bytes
+            .as_ref()
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("invalid m3u8 key length: {}", key_bytes.len()))
+    }
+
+    /// Waits until the bytes fit into the global and the per-download limit
+    async fn limit_rate_of(&self, task: &InternalDownloadTask, bytes: u64) {
+        if let Some(rate_limiter) = &self.rate_limiter {
+            rate_limiter.consume(bytes).await;
+        }
+
+        if let Some(rate_limiter) = &task.rate_limiter {
+            rate_limiter.consume(bytes).await;
+        }
+    }
+
+    async fn clean_up_write(mut output_stream: tokio::io::BufWriter<tokio::fs::File>) -> Result<(), anyhow::Error> {
+        if let Err(err) = output_stream.flush().await {
+            return Err(err).with_context(|| "failed flushing to download file");
+        }
+
+        if let Err(err) = output_stream.get_mut().sync_all().await {
+            return Err(err).with_context(|| "failed syncing download file to disk");
+        }
+
+        Ok(())
+    }
+
+    /// Checks that a finished download is complete and readable. Otherwise, the file
+    /// is deleted, so that it is downloaded again on the next try. Short direct
+    /// downloads are only deleted if a minimum duration was requested.
+    async fn verify_download(
+        &self,
+        path: &Path,
+        expected_duration: Option<f64>,
+        min_duration: Option<f64>,
+        expected_size: Option<u64>,
+    ) -> Result<(), anyhow::Error> {
+        let result = self
+            .probe_download(path, expected_duration, min_duration, expected_size)
+            .await;
+
+        if result.is_err() {
+            if let Err(err) = remove_file_ignore_not_exists(path).await {
+                log::warn!("Failed to delete download that failed verification: {}", err);
+            }
+        }
+
+        result.with_context(|| LocalError("verification of download failed"))
+    }
+
+    async fn probe_download(
+        &self,
+        path: &Path,
+        expected_duration: Option<f64>,
+        min_duration: Option<f64>,
+        expected_size: Option<u64>,
+    ) -> Result<(), anyhow::Error> {
+        if let Some(expected_size) = expected_size {
+            let size = tokio::fs::metadata(path)
+                .await
+                .with_context(|| "failed to get metadata of download file")?
+                .len();
+
+            if size != expected_size {
+                anyhow::bail!("file has {} of {} bytes", size, expected_size);
+            }
+        }
+
+        let Some(ffmpeg_path) = &self.ffmpeg_path else {
+            log::debug!("Skipping probe of download, because FFmpeg is not installed");
+            return Ok(());
+        };
+
+        // All packets are read without decoding them, which fails for broken files.
+        // The automatically downloaded FFmpeg comes without ffprobe.
+        let mut ffmpeg_cmd = ffmpeg_command(ffmpeg_path, false);
+        ffmpeg_cmd
+            .arg("-i")
+            .arg(path)
+            .args(["-map", "0", "-c", "copy", "-f", "null", "-"]);
+
+        let mut child = ffmpeg_cmd.spawn().with_context(|| "failed to start FFmpeg")?;
+        let duration = self
+            .wait_for_ffmpeg(&mut child, None)
+            .await
+            .with_context(|| "file is not readable")?
+            .with_context(|| "failed to get duration of file")?;
+        // Missing segments make the file shorter than the playlist
+        match get_min_duration(expected_duration, min_duration) {
+            Some(min_duration) if duration < min_duration => anyhow::bail!(
+                "file is {:.1} seconds long, but at least {:.1} seconds were expected",
+                duration,
+                min_duration
+            ),
+            None if duration < MIN_VIDEO_DURATION => log::warn!(
+                "\"{}\" is only {:.1} seconds long and might be the placeholder of a removed video",
+                path.file_name().unwrap_or(path.as_os_str()).to_string_lossy(),
+                duration
+            ),
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    /// Waits for FFmpeg while reading its output and returns the last position it
+    /// reported. The remux progress is shown in the message of the progress bar and
+    /// the last lines of the log are part of the error.
+    async fn wait_for_ffmpeg(
+        &self,
+        child: &mut tokio::process::Child,
+        progress: Option<(&indicatif::ProgressBar, f64)>,
+    ) -> Result<Option<f64>, anyhow::Error> {
+        const STDERR_TAIL_LINES: usize = 10;
+
+        let stdout = child.stdout.take();
+        let stderr = child.stderr.take();
+        let message = progress.map(|(progress_bar, _)| progress_bar.message());
+
+        let progress_future = async {
+            let mut last_out_time = None;
+            let Some(stdout) = stdout else {
+                return last_out_time;
+            };
+            let mut lines = tokio::io::BufReader::new(stdout).lines();
+
+            while let Ok(Some(line)) = lines.next_line().await {
+                let Some(out_time) = parse_ffmpeg_out_time(&line) else {
+                    continue;
+                };
+
+                last_out_time = Some(out_time);
+
+                if let (Some((progress_bar, duration)), Some(message)) = (progress, &message) {
+                    if duration > 0.0 {
+                        let percent = ((out_time / duration) * 100.0).clamp(0.0, 99.0) as u32;
+                        progress_bar.set_message(format!("{} | Remuxing {}%", message, percent));
                     }
                 }
             }
+
+            last_out_time
+        };
+        let stderr_future = async {
+            let mut stderr_tail = VecDeque::with_capacity(STDERR_TAIL_LINES);
+            let Some(stderr) = stderr else {
+                return stderr_tail;
+            };
+            let mut lines = tokio::io::BufReader::new(stderr).lines();
+
+            while let Ok(Some(line)) = lines.next_line().await {
+                if self.debug {
+                    log::debug!("FFmpeg: {}", line);
+                }
+
+                if stderr_tail.len() == STDERR_TAIL_LINES {
+                    stderr_tail.pop_front();
+                }
+
+                stderr_tail.push_back(line);
+            }
+
+            stderr_tail
+        };
+
+        let (last_out_time, stderr_tail, exit_status) = tokio::join!(progress_future, stderr_future, child.wait());
+
+        if let (Some((progress_bar, _)), Some(message)) = (progress, message) {
+            progress_bar.set_message(message);
+        }
+
+        let exit_status = exit_status.with_context(|| "FFmpeg was not running")?;
+
+        if exit_status.success() {
+            return Ok(last_out_time);
+        }
+
+        let reason = match exit_status.code() {
+            Some(code) => format!("FFmpeg failed with exit code {}", code),
+            None => "FFmpeg failed due to signal termination".to_owned(),
+        };
+
+        if stderr_tail.is_empty() {
+            anyhow::bail!(reason);
+        }
+
+        anyhow::bail!("{}:\n{}", reason, Vec::from(stderr_tail).join("\n"))
+    }
+
+    fn update_progress(&self, progress_bar: &indicatif::ProgressBar, downloaded: u64, total_bytes: Option<u64>) {
+        progress_bar.update(|state| {
+            if !(state.len() == Some(u64::MAX) && total_bytes.is_none()) {
+                state.set_len(total_bytes.unwrap_or(0).max(downloaded));
+            }
+
+            state.set_pos(downloaded);
+        });
+
+        self.update_progress_total(true, true)
+    }
+
+    fn update_progress_total(&self, bytes: bool, message: bool) {
+        if !bytes && !message {
+            return;
+        }
+
+        let sub_progresses_lock = self.sub_progresses.borrow();
+
+        let updated_bytes = if bytes {
+            let total_downloaded: u64 = sub_progresses_lock
+                .iter()
+                .map(|pb| pb.position())
+                .reduce(|acc, e| acc.saturating_add(e))
+                .unwrap();
+            let total_length: u64 = sub_progresses_lock
+                .iter()
+                .filter_map(|pb| pb.length())
+                .reduce(|acc, e| acc.saturating_add(e))
+                .unwrap();
+            Some((total_downloaded, total_length))
+        } else {
+            None
+        };
+
+        let updated_message = if message {
+            let total_finished = sub_progresses_lock.iter().filter(|pb| pb.is_finished()).count();
+            let total_bars = sub_progresses_lock.len();
+            Some(format!("Total {total_finished}/{total_bars}"))
+        } else {
+            None
+        };
+
+        drop(sub_progresses_lock);
+
+        let total_progress_lock = self.total_progress.borrow();
+        let total_progress = total_progress_lock.as_ref().unwrap();
+
+        if let Some((total_downloaded, total_length)) = updated_bytes {
+            total_progress.update(|state| {
+                state.set_len(total_length);
+                state.set_pos(total_downloaded);
+            });
+        }
+
+        if let Some(message) = updated_message {
+            total_progress.set_message(message);
+        }
+
+        drop(total_progress_lock);
+    }
+
+    fn create_total_progress_bar() -> indicatif::ProgressBar {
+        indicatif::ProgressBar::new(u64::MAX)
+            .with_style(custom_progress_style(
+                indicatif::ProgressStyle::with_template(
+                    "[{elapsed_precise}] {wide_msg} {binary_bytes_per_sec:>14} {bytes:>10}{total_bytes:<11} [{bar}] {eta:>5} {percent:>3}%",
+                )
+                .unwrap()
+            ))
+            .with_message("Total 0/1")
+    }
+
+    fn create_progress_bar(&self, name: String, bytes: u64, episode: Option<&str>) -> (usize, indicatif::ProgressBar) {
+        let pb = indicatif::ProgressBar::new(bytes)
+            .with_style(custom_progress_style(
+                indicatif::ProgressStyle::with_template(
+                    "[{elapsed_precise}] {wide_msg} {binary_bytes_per_sec:>14} {bytes:>10}{total_bytes:<11} [{bar}] {eta:>5} {percent:>3}%",
+                )
+                .unwrap()
+            ))
+            .with_message(name);
+        self.post_prepare_progress_bar(pb, episode)
+    }
+
+    fn create_progress_bar_unknown_bytes(
+        &self,
+        name: String,
+        episode: Option<&str>,
+    ) -> (usize, indicatif::ProgressBar) {
+        let pb = indicatif::ProgressBar::new(0)
+            .with_style(custom_progress_style(
+                indicatif::ProgressStyle::with_template(
+                    "[{elapsed_precise}] {wide_msg} {binary_bytes_per_sec:>14} {bytes:>10}",
+                )
+                .unwrap(),
+            ))
+            .with_message(name);
+        self.post_prepare_progress_bar(pb, episode)
+    }
+
+    fn post_prepare_progress_bar(
+        &self,
+        progress_bar: indicatif::ProgressBar,
+        episode: Option<&str>,
+    ) -> (usize, indicatif::ProgressBar) {
+        let mut total_progress_lock = self.total_progress.borrow_mut();
+
+        if total_progress_lock.is_none() {
+            let new_total_progress = Self::create_total_progress_bar();
+            *total_progress_lock = Some(self.multi_progress.add(new_total_progress));
+        }
+
+        let pb = self
+            .multi_progress
+            .insert_before(total_progress_lock.as_ref().unwrap(), progress_bar);
+
+        drop(total_progress_lock);
+
+        let mut sub_progresses_lock = self.sub_progresses.borrow_mut();
+        let sub_progresses_index = sub_progresses_lock.len();
+        sub_progresses_lock.push(ProgressBarOrResult::ProgressBar(pb.clone()));
+        drop(sub_progresses_lock);
+        self.sub_progress_episodes.borrow_mut().push(episode.map(str::to_owned));
+
+        self.emit_download_event(
+            sub_progresses_index,
+            "started",
+            serde_json::json!({
+                "name": pb.message(),
+                "total_bytes": get_known_length(&pb),
+            }),
+        );
+
+        pb.tick();
+        self.update_progress_total(true, true);
+
+        (sub_progresses_index, pb)
+    }
+
+    fn clean_up_progress_bar(&self, progress_bar: &indicatif::ProgressBar, sub_progresses_index: usize) {
+        progress_bar.finish();
+
+        let position = progress_bar.position();
+        let mut sub_progresses_lock = self.sub_progresses.borrow_mut();
+        sub_progresses_lock[sub_progresses_index] = ProgressBarOrResult::Finished { length: position };
+        drop(sub_progresses_lock);
+
+        self.update_progress_total(true, true);
+    }
+
+    fn error_cleanup_progress_bar(&self, progress_bar: &indicatif::ProgressBar, sub_progresses_index: usize) {
+        progress_bar.abandon();
+
+        let position = progress_bar.position();
+        let length = progress_bar.length();
+        let mut sub_progresses_lock = self.sub_progresses.borrow_mut();
+        sub_progresses_lock[sub_progresses_index] = ProgressBarOrResult::Abandoned { position, length };
+        drop(sub_progresses_lock);
+    }
+
+    fn clean_up_total_progress_bar(&self) {
+        if let Some(total_progress) = self.total_progress.borrow().deref() {
+            let all_finished = self.sub_progresses.borrow().iter().all(|pb| pb.is_finished());
+
+            if all_finished {
+                total_progress.finish();
+            } else {
+                total_progress.abandon();
+            }
+        }
+    }
+
+    /// This function never finishes. It should be used in a select! expression.
+    pub(crate) async fn tick(&self) {
+        const TICK_INTERVAL: Duration = Duration::from_millis(100);
+        const PROGRESS_EVENT_TICKS: u32 = 10;
+
+        let mut ticks = 0u32;
+
+        loop {
+            let emit_progress = ticks % PROGRESS_EVENT_TICKS == 0;
+            ticks = ticks.wrapping_add(1);
+
+            for (sub_progresses_index, sub_progress) in self.sub_progresses.borrow().iter().enumerate() {
+                if let ProgressBarOrResult::ProgressBar(pb) = &sub_progress {
+                    if !pb.is_finished() {
+                        pb.tick();
+
+                        if emit_progress {
+                            self.emit_download_event(
+                                sub_progresses_index,
+                                "progress",
+                                serde_json::json!({
+                                    "downloaded_bytes": pb.position(),
+                                    "total_bytes": get_known_length(pb),
+                                }),
+                            );
+                        }
+                    }
+                }
+            }
+
+            if let Some(total_progress) = self.total_progress.borrow().deref() {
+                if !total_progress.is_finished() {
+                    total_progress.tick();
+                }
+            }
+
+            tokio::time::sleep(TICK_INTERVAL).await;
+        }
+    }
+
+    /// Writes a progress event as a JSON line to stdout, if enabled
+    pub(crate) fn emit_event(&self, event: &str, episode: Option<&str>, fields: serde_json::Value) {
+        if self.progress_mode != ProgressMode::Json {
+            return;
+        }
+
+        let mut json = serde_json::json!({
+            "event": event,
+            "episode": episode,
+        });
+
+        if let (Some(object), serde_json::Value::Object(fields)) = (json.as_object_mut(), fields) {
+            object.extend(fields);
+        }
+
+        println!("{}", json);
+    }
+
+    fn emit_download_event(&self, sub_progresses_index: usize, event: &str, mut fields: serde_json::Value) {
+        if self.progress_mode != ProgressMode::Json {
+            return;
+        }
+
+        let episode = self.sub_progress_episodes.borrow()[sub_progresses_index].clone();
+
+        if let Some(object) = fields.as_object_mut() {
+            object.insert("id".to_owned(), sub_progresses_index.into());
+        }
+
+        self.emit_event(event, episode.as_deref(), fields);
+    }
+
+    pub(crate) fn clear(self) {
+        self.clean_up_total_progress_bar();
+        drop(self.total_progress.take());
+        let _ = self.multi_progress.clear();
+    }
+}
+
+impl Drop for Downloader {
+    fn drop(&mut self) {
+        self.clean_up_total_progress_bar();
+    }
+}
+
+/// Length of a progress bar, unless it is still a placeholder for an unknown size
+fn get_known_length(progress_bar: &indicatif::ProgressBar) -> Option<u64> {
+    progress_bar
+        .length()
+        .filter(|length| *length != 0 && *length != u64::MAX)
+}
+
+fn custom_progress_style(progress_style: indicatif::ProgressStyle) -> indicatif::ProgressStyle {
+    use indicatif::{HumanDuration, ProgressState};
+    use number_prefix::NumberPrefix;
+
+    progress_style
+        .with_key("bytes", |state: &ProgressState, w: &mut dyn Write| {
+            let _ = match NumberPrefix::binary(state.pos() as f64) {
+                NumberPrefix::Standalone(number) => write!(w, "{number:.0} B"),
+                NumberPrefix::Prefixed(prefix, number) => write!(w, "{number:.1} {prefix}B"),
+            };
+        })
+        .with_key("total_bytes", |state: &ProgressState, w: &mut dyn Write| {
+            // Only if total bytes are known
+            if state.len() != Some(u64::MAX) {
+                let _ = write!(w, "/");
+                let _ = match NumberPrefix::binary(state.len().unwrap() as f64) {
+                    NumberPrefix::Standalone(number) => write!(w, "{number:.0} B"),
+                    NumberPrefix::Prefixed(prefix, number) => write!(w, "{number:.1} {prefix}B"),
+                };
+            }
+        })
+        .with_key("binary_bytes_per_sec", |state: &ProgressState, w: &mut dyn Write| {
+            let _ = match NumberPrefix::binary(state.per_sec()) {
+                NumberPrefix::Standalone(number) => write!(w, "{number:.0} B/s"),
+                NumberPrefix::Prefixed(prefix, number) => write!(w, "{number:.1} {prefix}B/s"),
+            };
+        })
+        .with_key("percent", |state: &ProgressState, w: &mut dyn Write| {
+            let mut percent = ((state.fraction() * 100.0).floor() as u32).clamp(0, 100);
+
+            if percent == 100 && !state.is_finished() {
+                percent = 99;
+            }
+
+            let _ = write!(w, "{}", percent);
+        })
+        .with_key("bar", |state: &ProgressState, w: &mut dyn Write| {
+            const BAR_WIDTH: usize = 40;
+            const FILLED_STR: &str = "#";
+            const IN_PROGRESS_STR: &str = ">";
+            const TODO_STR: &str = "-";
+
+            let mut filled = ((state.fraction() * BAR_WIDTH as f32) as usize).min(BAR_WIDTH);
+
+            if filled == BAR_WIDTH && !state.is_finished() {
+                filled = BAR_WIDTH - 1;
+            }
+
+            let non_filled = BAR_WIDTH - filled;
+            let in_progress = 1.min(non_filled);
+            let todo = BAR_WIDTH - filled - in_progress;
+
+            let _ = write!(
+                w,
+                "{}{}{}",
+                console::style(FILLED_STR.repeat(filled)).cyan(),
+                console::style(IN_PROGRESS_STR.repeat(in_progress)).cyan(),
+                console::style(TODO_STR.repeat(todo)).blue(),
+            );
+        })
+        .with_key("eta", |state: &ProgressState, w: &mut dyn Write| {
+            // Only if total bytes are known
+            if state.len() != Some(u64::MAX) {
+                let _ = write!(w, "({:#})", HumanDuration(state.eta()));
+            }
+        })
+}
+
+pub(crate) async fn get_response<U: IntoUrl>(
+    client: Option<&reqwest_partial_retry::Client>,
+    url: U,
+    user_agent: Option<&str>,
+    referer: Option<&str>,
+    extra_headers: Option<&[(HeaderName, &str)]>,
+) -> Result<reqwest_partial_retry::ResumableResponse, anyhow::Error> {
+    // We need to handle redirects ourself, because reqwest changes the Referer
+    // header on redirection
+    let client = client.unwrap_or(DEFAULT_RETRY_CLIENT_NO_REDIRECT.deref());
+    let mut last_url = url.as_str().to_string();
+    let mut redirect_count = 0u32;
+
+    loop {
+        let mut request = client.get(last_url);
+
+        if let Some(user_agent) = user_agent {
+            request = request.header(reqwest::header::USER_AGENT, user_agent);
+        }
+
+        if let Some(referer) = referer {
+            request = request.header(reqwest::header::REFERER, referer);
+        }
+
+        if let Some(extra_headers) = extra_headers {
+            for (header, value) in extra_headers {
+                request = request.header(header, *value);
+            }
+        }
+
+        let response = client
+            .execute_resumable(request.build().with_context(|| "failed to build request")?)
+            .await
+            .with_context(|| "failed to request url")?;
+
+        let is_redirect_code = [301, 308, 302, 303, 307].contains(&response.status().as_u16());
+        let location_header = response.headers().get(reqwest::header::LOCATION);
+
+        match (is_redirect_code, location_header) {
+            (true, Some(redirect_url)) => {
+                if redirect_count >= 10 {
+                    anyhow::bail!("more than 10 redirects");
+                }
+
+                redirect_count += 1;
+                last_url = redirect_url
+                    .to_str()
+                    .with_context(|| "redirect url could not be converted to string")?
+                    .to_string();
+            }
+            _ => return Ok(response),
+        }
+    }
+}
+
+pub(crate) async fn get_response_bytes(response: reqwest::Response) -> Result<bytes::Bytes, anyhow::Error> {
+    response
+        .bytes()
+        .await
+        .with_context(|| "failed to get bytes of response body")
+}
+
+pub(crate) async fn get_page_bytes<U: IntoUrl>(
+    url: U,
+    user_agent: Option<&str>,
+    referer: Option<&str>,
+    extra_headers: Option<&[(HeaderName, &str)]>,
+) -> Result<bytes::Bytes, anyhow::Error> {
+    get_response_bytes(
+        get_response(None, url, user_agent, referer, extra_headers)
+            .await?
+            .response(),
+    )
+    .await
+}
+
+pub(crate) async fn get_page_text<U: IntoUrl>(
+    url: U,
+    user_agent: Option<&str>,
+    referer: Option<&str>,
+    extra_headers: Option<&[(HeaderName, &str)]>,
+) -> Result<String, anyhow::Error> {
+    get_response(None, url, user_agent, referer, extra_headers)
+        .await?
+        .response()
+        .text()
+        .await
+        .with_context(|| "failed to parse response body as text")
+}
+
+pub(crate) async fn get_page_json<U: IntoUrl>(
+    url: U,
+    user_agent: Option<&str>,
+    referer: Option<&str>,
+    extra_headers: Option<&[(HeaderName, &str)]>,
+) -> Result<serde_json::Value, anyhow::Error> {
+    get_response(None, url, user_agent, referer, extra_headers)
+        .await?
+        .response()
+        .json()
+        .await
+        .with_context(|| "failed to parse response body as json")
+}
+
+mod retry {
+    // Copied from reqwest_retry::DefaultRetryableStrategy
+    pub(crate) mod strategy {
+        use reqwest::StatusCode;
+        use reqwest_retry::{Retryable, RetryableStrategy};
+
+        pub struct CustomRetryStrategy;
+
+        impl RetryableStrategy for CustomRetryStrategy {
+            fn handle(&self, res: &Result<reqwest::Response, reqwest_middleware::Error>) -> Option<Retryable> {
+                match res {
+                    Ok(success) => default_on_request_success(success),
+                    Err(error) => default_on_request_failure(error),
+                }
+            }
+        }
+
+        /// Default request success retry strategy.
+        ///
+        /// Will only retry if:
+        /// * The status was 5XX (server error)
+        /// * The status was 408 (request timeout) or 429 (too many requests)
+        ///
+        /// Note that success here means that the request finished without
+        /// interruption, not that it was logically OK.
+        pub fn default_on_request_success(success: &reqwest::Response) -> Option<Retryable> {
+            let status = success.status();
+            if status.is_server_error() {
+                Some(Retryable::Transient)
+            } else if status.is_client_error()
+                && status != StatusCode::REQUEST_TIMEOUT
+                && status != StatusCode::TOO_MANY_REQUESTS
+            {
+                Some(Retryable::Fatal)
+            } else if status.is_success() {
+                None
+            } else if status == StatusCode::REQUEST_TIMEOUT || status == StatusCode::TOO_MANY_REQUESTS {
+                Some(Retryable::Transient)
+            } else if [301, 308, 302, 303, 307].contains(&status.as_u16()) {
+                // NEW: now redirects won't be fatal anymore
+                None
+            } else {
+                Some(Retryable::Fatal)
+            }
+        }
+
+        /// Default request failure retry strategy.
+        ///
+        /// Will only retry if the request failed due to a network error
+        pub fn default_on_request_failure(error: &reqwest_middleware::Error) -> Option<Retryable> {
+            match error {
+                // If something fails in the middleware we're screwed.
+                reqwest_middleware::Error::Middleware(_) => Some(Retryable::Fatal),
+                reqwest_middleware::Error::Reqwest(error) => {
+                    #[cfg(not(target_arch = "wasm32"))]
+                    let is_connect = error.is_connect();
+                    #[cfg(target_arch = "wasm32")]
+                    let is_connect = false;
+                    if error.is_timeout() || is_connect {
+                        Some(Retryable::Transient)
+                    } else if error.is_body() || error.is_decode() || error.is_builder() || error.is_redirect() {
+                        Some(Retryable::Fatal)
+                    } else if error.is_request() {
+                        // It seems that hyper::Error(IncompleteMessage) is not correctly handled by
+                        // reqwest. Here we check if the Reqwest error was
+                        // originated by hyper and map it consistently.
+                        #[cfg(not(target_arch = "wasm32"))]
+                        if let Some(hyper_error) = get_source_error_type::<hyper::Error>(&error) {
+                            // The hyper::Error(IncompleteMessage) is raised if the HTTP response is well
+                            // formatted but does not contain all the bytes.
+                            // This can happen when the server has started sending back the response but the
+                            // connection is cut halfway thorugh. We can safely
+                            // retry the call, hence marking this error as [`Retryable::Transient`].
+                            // Instead hyper::Error(Canceled) is raised when the connection is
+                            // gracefully closed on the server side.
+                            if hyper_error.is_incomplete_message() || hyper_error.is_canceled() {
+                                Some(Retryable::Transient)
+
+                            // Try and downcast the hyper error to io::Error if
+                            // that is the
+                            // underlying error, and try and classify it.
+                            } else if let Some(io_error) = get_source_error_type::<std::io::Error>(hyper_error) {
+                                Some(classify_io_error(io_error))
+                            } else {
+                                Some(Retryable::Fatal)
+                            }
+                        } else {
+                            Some(Retryable::Fatal)
+                        }
+                        #[cfg(target_arch = "wasm32")]
+                        Some(Retryable::Fatal)
+                    } else {
+                        // We omit checking if error.is_status() since we check that already.
+                        // However, if Response::error_for_status is used the status will still
+                        // remain in the response object.
+                        None
+                    }
+                }
+            }
+        }
+
+        #[cfg(not(target_arch = "wasm32"))]
+        fn classify_io_error(error: &std::io::Error) -> Retryable {
+            match error.kind() {
+                std::io::ErrorKind::ConnectionReset | std::io::ErrorKind::ConnectionAborted => Retryable::Transient,
+                _ => Retryable::Fatal,
+            }
+        }
+
+        /// Downcasts the given err source into T.
+        #[cfg(not(target_arch = "wasm32"))]
+        fn get_source_error_type<T: std::error::Error + 'static>(err: &dyn std::error::Error) -> Option<&T> {
+            let mut source = err.source();
+
+            while let Some(err) = source {
+                if let Some(err) = err.downcast_ref::<T>() {
+                    return Some(err);
+                }
+
+                source = err.source();
+            }
+            None
+        }
+    }
+}
+
+/// Requests the rest of a byte range after the bytes that were already written
+fn get_range_header(range: &Range<u64>, written: u64) -> String {
+    format!("bytes={}-{}", range.start + written, range.end - 1)
+}
+
+/// The query of segment playlist URLs often contains expiring tokens
+fn get_url_without_query(url: &Url) -> String {
+    let mut url = url.clone();
+    url.set_query(None);
+    url.set_fragment(None);
+    url.to_string()
+}
+
+/// Start of a `Content-Range: bytes <start>-<end>/<size>` header
+fn parse_content_range_start(content_range: &str) -> Option<u64> {
+    content_range
+        .strip_prefix("bytes ")?
+        .split_once('-')?
+        .0
+        .trim()
+        .parse()
+        .ok()
+}
+
+fn is_mpd_url(url: &Url) -> bool {
+    url.path_segments()
+        .and_then(|segments| segments.last())
+        .map(|last| {
+            let lower = last.to_ascii_lowercase();
+            lower.ends_with(".mpd") && lower.len() != ".mpd".len()
+        })
+        .unwrap_or(false)
+}
+
+fn is_m3u8_url(url: &Url) -> bool {
+    url.path_segments()
+        .and_then(|segments| segments.last())
+        .map(|last| {
+            let lower = last.to_ascii_lowercase();
+            (lower.ends_with(".m3u8") && lower.len() != ".m3u8".len())
+                || (lower.ends_with(".m3u") && lower.len() != ".m3u".len())
+        })
+        .unwrap_or(false)
+}
+
+/// Policy for choosing a variant of an m3u8 master playlist
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum VariantQuality {
+    Best,
+    Worst,
+    /// Best variant with at most this height in pixels
+    MaxHeight(u64),
+    /// Best variant with at most this bandwidth in bits per second
+    MaxBandwidth(u64),
+}
+
+impl VariantQuality {
+    /// Selects a non-iframe variant. If no variant satisfies a maximum, the
+    /// lowest quality variant is used instead.
+    fn select_variant<'a>(&self, variants: &'a [m3u8_rs::VariantStream]) -> Option<&'a m3u8_rs::VariantStream> {
+        self.select(variants.iter().filter(|variant| !variant.is_i_frame), |variant| {
+            StreamQuality {
+                resolution: variant
+                    .resolution
+                    .map(|resolution| (resolution.width, resolution.height)),
+                average_bandwidth: variant.average_bandwidth,
+                bandwidth: variant.bandwidth,
+            }
+        })
+    }
+
+    /// Selects a video representation of an mpd manifest by the same rules as m3u8 variants
+    fn select_representation<'a>(
+        &self,
+        representations: impl Iterator<Item = &'a dash::Representation> + Clone,
+    ) -> Option<&'a dash::Representation> {
+        self.select(representations, |representation| StreamQuality {
+            resolution: representation.width.zip(representation.height),
+            average_bandwidth: None,
+            bandwidth: representation.bandwidth,
+        })
+    }
+
+    fn select<'a, T>(
+        &self,
+        candidates: impl Iterator<Item = &'a T> + Clone,
+        quality: impl Fn(&T) -> StreamQuality,
+    ) -> Option<&'a T> {
+        let compare = |a: &&T, b: &&T| quality(a).cmp_quality(&quality(b));
+        let worst = || candidates.clone().min_by(compare);
+
+        let satisfying = match *self {
+            VariantQuality::Best => return candidates.clone().max_by(compare),
+            VariantQuality::Worst => return worst(),
+            VariantQuality::MaxHeight(max_height) => candidates
+                .clone()
+                .filter(|candidate| {
+                    quality(candidate)
+                        .resolution
+                        .is_some_and(|(_, height)| height <= max_height)
+                })
+                .max_by(compare),
+            VariantQuality::MaxBandwidth(max_bandwidth) => candidates
+                .clone()
+                .filter(|candidate| {
+                    let quality = quality(candidate);
+                    quality.average_bandwidth.unwrap_or(quality.bandwidth) <= max_bandwidth
+                })
+                .max_by(compare),
+        };
+
+        satisfying.or_else(worst)
+    }
+}
+
+impl Display for VariantQuality {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VariantQuality::Best => write!(f, "best"),
+            VariantQuality::Worst => write!(f, "worst"),
+            VariantQuality::MaxHeight(max_height) => write!(f, "{}p", max_height),
+            VariantQuality::MaxBandwidth(max_bandwidth) => write!(f, "{}k", max_bandwidth / 1000),
+        }
+    }
+}
+
+impl FromStr for VariantQuality {
+    type Err = anyhow::Error;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let input_lower = input.trim().to_ascii_lowercase();
+
+        if input_lower == "best" {
+            return Ok(VariantQuality::Best);
+        }
+
+        if input_lower == "worst" {
+            return Ok(VariantQuality::Worst);
+        }
+
+        let parse_number = |number: &str| {
+            number
+                .parse::<u64>()
+                .with_context(|| format!("failed to parse \"{}\" as quality", input))
+        };
+
+        if let Some(max_bandwidth) = input_lower.strip_suffix('k') {
+            return Ok(VariantQuality::MaxBandwidth(parse_number(max_bandwidth)? * 1000));
+        }
+
+        if let Some(max_bandwidth) = input_lower.strip_suffix('m') {
+            return Ok(VariantQuality::MaxBandwidth(parse_number(max_bandwidth)? * 1000 * 1000));
+        }
+
+        let max_height = input_lower.strip_suffix('p').unwrap_or(&input_lower);
+        Ok(VariantQuality::MaxHeight(parse_number(max_height)?))
+    }
+}
+
+/// The attributes of an m3u8 variant or mpd representation that its quality is judged by
+struct StreamQuality {
+    /// Width and height in pixels
+    resolution: Option<(u64, u64)>,
+    average_bandwidth: Option<u64>,
+    bandwidth: u64,
+}
+
+impl StreamQuality {
+    /// Orders streams by resolution, then by bandwidth
+    fn cmp_quality(&self, other: &StreamQuality) -> std::cmp::Ordering {
+        if let (Some(res_a), Some(res_b)) = (self.resolution, other.resolution) {
+            let res_a_pixels = res_a.0 * res_a.1;
+            let res_b_pixels = res_b.0 * res_b.1;
+
+            if res_a_pixels != res_b_pixels {
+                return res_a_pixels.cmp(&res_b_pixels);
+            }
+        }
+
+        if let (Some(bw_a), Some(bw_b)) = (self.average_bandwidth, other.average_bandwidth) {
+            return bw_a.cmp(&bw_b);
+        }
+
+        self.bandwidth.cmp(&other.bandwidth)
+    }
+}
+
+/// A transfer rate in bytes per second, written like `500K` or `2M`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ByteRate(NonZeroU64);
+
+impl ByteRate {
+    fn bytes_per_second(&self) -> u64 {
+        self.0.get()
+    }
+}
+
+impl Display for ByteRate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let bytes = self.bytes_per_second();
+
+        for (suffix, unit) in [("G", 1024 * 1024 * 1024), ("M", 1024 * 1024), ("K", 1024)] {
+            if bytes % unit == 0 {
+                return write!(f, "{}{}", bytes / unit, suffix);
+            }
+        }
+
+        write!(f, "{}", bytes)
+    }
+}
+
+impl FromStr for ByteRate {
+    type Err = anyhow::Error;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let input_upper = input.trim().to_ascii_uppercase();
+        let number = input_upper.strip_suffix("/S").unwrap_or(&input_upper);
+        let number = number.strip_suffix('B').unwrap_or(number);
+
+        let (number, unit) = match number.chars().last() {
+            Some('K') => (&number[..number.len() - 1], 1024.0),
+            Some('M') => (&number[..number.len() - 1], 1024.0 * 1024.0),
+            Some('G') => (&number[..number.len() - 1], 1024.0 * 1024.0 * 1024.0),
+            _ => (number, 1.0),
+        };
+
+        let bytes = number
+            .parse::<f64>()
+            .ok()
+            .filter(|number| number.is_finite())
+            .map(|number| (number * unit).round() as u64)
+            .and_then(NonZeroU64::new)
+            .with_context(|| format!("failed to parse \"{}\" as rate", input))?;
+
+        Ok(ByteRate(bytes))
+    }
+}
+
+/// Token bucket that limits the throughput of everything that consumes from it.
+/// Consumers may take more than is available and then wait until the debt is paid off,
+/// so concurrent downloads are queued behind each other.
+struct TokenBucket {
+    bytes_per_second: f64,
+    tokens: Cell<f64>,
+    last_refill: Cell<tokio::time::Instant>,
+}
+
+impl TokenBucket {
+    fn new(rate: ByteRate) -> Self {
+        let bytes_per_second = rate.bytes_per_second() as f64;
+
+        TokenBucket {
+            bytes_per_second,
+            // Allows a burst of up to one second
+            tokens: Cell::new(bytes_per_second),
+            last_refill: Cell::new(tokio::time::Instant::now()),
+        }
+    }
+
+    async fn consume(&self, bytes: u64) {
+        let wait_duration = self.take(bytes, tokio::time::Instant::now());
+
+        if !wait_duration.is_zero() {
+            tokio::time::sleep(wait_duration).await;
+        }
+    }
+
+    /// Refills the bucket for the time since the last call and takes the bytes
+    /// out of it. Returns how long to wait until the bucket is no longer in debt.
+    fn take(&self, bytes: u64, now: tokio::time::Instant) -> Duration {
+        let elapsed = now.duration_since(self.last_refill.get()).as_secs_f64();
+        let tokens = (self.tokens.get() + elapsed * self.bytes_per_second).min(self.bytes_per_second) - bytes as f64;
+
+        self.tokens.set(tokens);
+        self.last_refill.set(now);
+
+        if tokens < 0.0 {
+            Duration::from_secs_f64(-tokens / self.bytes_per_second)
+        } else {
+            Duration::ZERO
+        }
+    }
+}
+
+/// State shared by the connections of a multi-connection direct download
+struct RangedDownload<'a> {
+    url: &'a Url,
+    task: &'a InternalDownloadTask,
+    part_file: &'a PartFile,
+    content_length: u64,
+    resume_state: RefCell<ResumeState>,
+    /// Prevents concurrent writes to the resume state file
+    state_lock: tokio::sync::Mutex<()>,
+    downloaded: Cell<u64>,
+    progress_bar: indicatif::ProgressBar,
+}
+
+impl RangedDownload<'_> {
+    /// Saves the resume state with the written bytes of one range. The bytes
+    /// have to be flushed to the part file before.
+    async fn save_range_state(&self, range_index: usize, range_written: u64) -> Result<(), anyhow::Error> {
+        let _state_guard = self.state_lock.lock().await;
+        let resume_state = {
+            let mut resume_state = self.resume_state.borrow_mut();
+
+            if let Some(range_bytes_written) = &mut resume_state.range_bytes_written {
+                range_bytes_written[range_index] = range_written;
+                resume_state.bytes_written = range_bytes_written.iter().sum();
+            }
+
+            resume_state.clone()
+        };
+
+        self.part_file.save_state(&resume_state).await
+    }
+}
+
+/// Splits a file into at most `max_count` byte ranges of about the same length,
+/// none of them shorter than `min_length` unless the file itself is.
+fn split_byte_ranges(length: u64, max_count: u64, min_length: u64) -> Vec<Range<u64>> {
+    if length == 0 {
+        return Vec::new();
+    }
+
+    let count = max_count.min(length / min_length.max(1)).max(1);
+    let range_length = length / count;
+
+    (0..count)
+        .map(|range_index| {
+            let start = range_index * range_length;
+            let end = if range_index == count - 1 {
+                length
+            } else {
+                start + range_length
+            };
+
+            start..end
+        })
+        .collect()
+}
+
+/// A media playlist segment with all attributes resolved for downloading
+struct M3u8Segment {
+    url: Url,
+    byte_range: Option<Range<u64>>,
+    duration: f64,
+    encryption: Option<SegmentEncryption>,
+    init: Option<SegmentInit>,
+}
+
+/// Shared progress of all discontinuity blocks of an m3u8 download. The total
+/// size is estimated from the duration of the already written segments.
+struct M3u8Progress {
+    progress_bar: indicatif::ProgressBar,
+    total_duration: f64,
+    downloaded_bytes: Cell<u64>,
+    downloaded_duration: Cell<f64>,
+    /// Includes bytes of segments that are not written yet
+    received_bytes: Cell<u64>,
+    total_bytes_estimation: Cell<Option<u64>>,
+}
+
+impl M3u8Progress {
+    fn add_completed(&self, bytes: u64, duration: f64) {
+        self.received_bytes.set(self.received_bytes.get() + bytes);
+        self.add_written(bytes, duration);
+    }
+
+    fn add_written(&self, bytes: u64, duration: f64) {
+        self.downloaded_bytes.set(self.downloaded_bytes.get() + bytes);
+        self.downloaded_duration.set(self.downloaded_duration.get() + duration);
+
+        if self.downloaded_duration.get() > 0.0 {
+            self.total_bytes_estimation.set(Some(
+                ((self.downloaded_bytes.get() as f64 * self.total_duration) / self.downloaded_duration.get()).ceil()
+                    as u64,
+            ));
+        }
+    }
+}
+
+/// The container of the downloaded m3u8 segments, which determines the
+/// extension of the temporary file before remuxing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum HlsContainer {
+    MpegTs,
+    /// Fragmented MP4 (CMAF) segments with an `EXT-X-MAP` init section
+    Fmp4,
+    /// Packed audio, mostly used by audio renditions
+    Aac,
+    /// Subtitle renditions
+    WebVtt,
+    /// WebM segments of mpd manifests
+    WebM,
+}
+
+impl HlsContainer {
+    fn detect(media_playlist: &m3u8_rs::MediaPlaylist, segment_inits: &[Option<SegmentInit>]) -> Self {
+        if segment_inits.iter().any(Option::is_some) {
+            return HlsContainer::Fmp4;
+        }
+
+        let first_segment_extension = media_playlist.segments.first().and_then(|segment| {
+            let path = segment.uri.split(['?', '#']).next().unwrap_or_default();
+            Path::new(path)
+                .extension()
+                .map(|extension| extension.to_string_lossy().to_ascii_lowercase())
+        });
+
+        match first_segment_extension.as_deref() {
+            Some("aac") => HlsContainer::Aac,
+            Some("vtt" | "webvtt") => HlsContainer::WebVtt,
+            _ => HlsContainer::MpegTs,
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            HlsContainer::MpegTs => "ts",
+            HlsContainer::Fmp4 => "m4s",
+            HlsContainer::Aac => "aac",
+            HlsContainer::WebVtt => "vtt",
+            HlsContainer::WebM => "webm",
+        }
+    }
+}
+
+/// The segments of one media playlist and the files they are written to
+struct M3u8Track {
+    state_url: String,
+    segments: Vec<M3u8Segment>,
+    blocks: Vec<Range<usize>>,
+    container: HlsContainer,
+    /// One file per discontinuity block
+    part_files: Vec<PartFile>,
+}
+
+impl M3u8Track {
+    fn new(
+        state_url: String,
+        segments: Vec<M3u8Segment>,
+        blocks: Vec<Range<usize>>,
+        container: HlsContainer,
+        final_path: &Path,
+        suffix: Option<&str>,
+        resumable: bool,
+    ) -> Result<Self, anyhow::Error> {
+        if blocks.is_empty() {
+            anyhow::bail!("media has no segments");
+        }
+
+        let part_files = (0..blocks.len())
+            .map(|block_index| {
+                let mut extension = String::new();
+
+                if let Some(suffix) = suffix {
+                    let _ = write!(extension, "{}.", suffix);
+                }
+
+                if block_index > 0 {
+                    let _ = write!(extension, "{}.", block_index);
+                }
+
+                extension.push_str(container.extension());
+                // Subtitles are small and their cues are shifted relative to the first segment
+                PartFile::new(
+                    final_path.with_extension(extension),
+                    resumable && container != HlsContainer::WebVtt,
+                )
+            })
+            .collect();
+
+        Ok(M3u8Track {
+            state_url,
+            segments,
+            blocks,
+            container,
+            part_files,
+        })
+    }
+
+    fn from_media_playlist(
+        media_playlist: &m3u8_rs::MediaPlaylist,
+        media_playlist_url: &Url,
+        final_path: &Path,
+        suffix: Option<&str>,
+        resumable: bool,
+    ) -> Result<Self, anyhow::Error> {
+        let segment_encryptions = get_segment_encryptions(media_playlist, media_playlist_url)?;
+        let segment_inits = get_segment_inits(media_playlist, media_playlist_url, &segment_encryptions)?;
+        let segment_byte_ranges = get_segment_byte_ranges(media_playlist)?;
+        let blocks = get_discontinuity_blocks(&media_playlist.segments);
+        let container = HlsContainer::detect(media_playlist, &segment_inits);
+        let segments = media_playlist
+            .segments
+            .iter()
+            .zip(segment_encryptions)
+            .zip(segment_inits)
+            .zip(segment_byte_ranges)
+            .map(|(((segment, encryption), init), byte_range)| {
+                Ok(M3u8Segment {
+                    url: media_playlist_url
+                        .join(&segment.uri)
+                        .with_context(|| "failed to create m3u8 segment url")?,
+                    byte_range,
+                    duration: segment.duration as f64,
+                    encryption,
+                    init,
+                })
+            })
+            .collect::<Result<Vec<_>, anyhow::Error>>()?;
+
+        M3u8Track::new(
+            get_url_without_query(media_playlist_url),
+            segments,
+            blocks,
+            container,
+            final_path,
+            suffix,
+            resumable,
+        )
+    }
+
+    fn from_representation(
+        representation: &dash::Representation,
+        mpd_url: &Url,
+        final_path: &Path,
+        suffix: Option<&str>,
+        resumable: bool,
+    ) -> Result<Self, anyhow::Error> {
+        let is_webm = representation
+            .mime_type
+            .as_deref()
+            .is_some_and(|mime_type| mime_type.ends_with("/webm"));
+        let container = if is_webm {
+            HlsContainer::WebM
+        } else {
+            HlsContainer::Fmp4
+        };
+        let init = representation.init.as_ref().map(|init| SegmentInit {
+            url: init.url.clone(),
+            byte_range: init.byte_range.clone(),
+            encryption: None,
+        });
+        let segments = representation
+            .segments
+            .iter()
+            .map(|segment| M3u8Segment {
+                url: segment.url.clone(),
+                byte_range: segment.byte_range.clone(),
+                duration: segment.duration,
+                encryption: None,
+                init: init.clone(),
+            })
+            .collect::<Vec<_>>();
+        // Mpd periods are not split, so all segments form a single block
+        let blocks = if segments.is_empty() {
+            Vec::new()
+        } else {
+            std::iter::once(0..segments.len()).collect()
+        };
+
+        M3u8Track::new(
+            format!("{}#{}", get_url_without_query(mpd_url), representation.id),
+            segments,
+            blocks,
+            container,
+            final_path,
+            suffix,
+            resumable,
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RenditionKind {
+    Audio,
+    Subtitles,
+}
+
+impl RenditionKind {
+    fn name(&self) -> &'static str {
+        match self {
+            RenditionKind::Audio => "audio",
+            RenditionKind::Subtitles => "subtitles",
+        }
+    }
+}
+
+/// An alternative audio or subtitle track (`EXT-X-MEDIA`) of the selected variant
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct HlsRendition {
+    kind: RenditionKind,
+    url: Url,
+    name: String,
+    language: Option<String>,
+}
+
+/// Collects the renditions of the audio and subtitle groups referenced by the
+/// variant. Renditions without an URI are already part of the variant stream.
+fn get_renditions(
+    master_playlist: &m3u8_rs::MasterPlaylist,
+    variant: &m3u8_rs::VariantStream,
+    master_playlist_url: &Url,
+) -> Result<Vec<HlsRendition>, anyhow::Error> {
+    let mut renditions = Vec::new();
+
+    for alternative in &master_playlist.alternatives {
+        let (kind, group_id) = match alternative.media_type {
+            m3u8_rs::AlternativeMediaType::Audio => (RenditionKind::Audio, variant.audio.as_deref()),
+            m3u8_rs::AlternativeMediaType::Subtitles => (RenditionKind::Subtitles, variant.subtitles.as_deref()),
+            _ => continue,
+        };
+
+        if group_id != Some(alternative.group_id.as_str()) {
+            continue;
+        }
+
+        let Some(uri) = &alternative.uri else {
+            continue;
+        };
+
+        renditions.push(HlsRendition {
+            kind,
+            url: master_playlist_url
+                .join(uri)
+                .with_context(|| "failed to create m3u8 rendition url")?,
+            name: alternative.name.clone(),
+            language: alternative.language.clone(),
+        });
+    }
+
+    Ok(renditions)
+}
+
+/// Whether the variant stream itself contains audio, which is the case if its
+/// audio group has a rendition without URI or if it has no audio group at all
+fn variant_has_audio(master_playlist: &m3u8_rs::MasterPlaylist, variant: &m3u8_rs::VariantStream) -> bool {
+    let Some(audio_group_id) = &variant.audio else {
+        return true;
+    };
+
+    master_playlist.alternatives.iter().any(|alternative| {
+        alternative.media_type == m3u8_rs::AlternativeMediaType::Audio
+            && &alternative.group_id == audio_group_id
+            && alternative.uri.is_none()
+    })
+}
+
+/// FFmpeg arguments that set the language and title of the rendition streams.
+/// The variant stream is mapped first and is expected to contain no subtitles.
+fn get_rendition_metadata_args(renditions: &[HlsRendition], variant_has_audio: bool) -> Vec<String> {
+    let mut args = Vec::new();
+    let mut audio_index = usize::from(variant_has_audio);
+    let mut subtitles_index = 0;
+
+    for rendition in renditions {
+        let stream_specifier = match rendition.kind {
+            RenditionKind::Audio => {
+                audio_index += 1;
+                format!("s:a:{}", audio_index - 1)
+            }
+            RenditionKind::Subtitles => {
+                subtitles_index += 1;
+                format!("s:s:{}", subtitles_index - 1)
+            }
+        };
+
+        if let Some(language) = &rendition.language {
+            args.push(format!("-metadata:{}", stream_specifier));
+            args.push(format!("language={}", to_iso639_2(language)));
+        }
+
+        args.push(format!("-metadata:{}", stream_specifier));
+        args.push(format!("title={}", rendition.name));
+    }
+
+    args
+}
+
+/// HLS uses two-letter language codes, but the MP4 muxer only accepts ISO 639-2
+fn to_iso639_2(language: &str) -> String {
+    let language_lower = language.to_ascii_lowercase();
+    let primary_language = language_lower.split(['-', '_']).next().unwrap_or_default();
+
+    let language_code = match primary_language {
+        "ar" => "ara",
+        "de" => "ger",
+        "en" => "eng",
+        "es" => "spa",
+        "fr" => "fre",
+        "it" => "ita",
+        "ja" => "jpn",
+        "ko" => "kor",
+        "nl" => "dut",
+        "pl" => "pol",
+        "pt" => "por",
+        "ru" => "rus",
+        "tr" => "tur",
+        "zh" => "chi",
+        _ => return language_lower,
+    };
+
+    language_code.to_owned()
+}
+
+/// The `X-TIMESTAMP-MAP` of a WebVTT segment maps its cue times to the MPEG-TS
+/// timestamps of the video. Returns by how many seconds the cues are shifted.
+fn parse_webvtt_timestamp_offset(segment: &[u8]) -> Option<f64> {
+    let segment = String::from_utf8_lossy(segment);
+    let timestamp_map = segment
+        .lines()
+        .take_while(|line| !line.trim().is_empty())
+        .find_map(|line| line.trim().strip_prefix("X-TIMESTAMP-MAP="))?;
+    let mut local = None;
+    let mut mpegts = None;
+
+    for field in timestamp_map.split(',') {
+        match field.trim().split_once(':') {
+            Some(("LOCAL", value)) => local = parse_webvtt_timestamp(value),
+            Some(("MPEGTS", value)) => mpegts = value.parse::<u64>().ok(),
+            _ => {}
+        }
+    }
+
+    Some(mpegts? as f64 / 90_000.0 - local?)
+}
+
+/// Shifts the start and end time of every cue
+fn shift_webvtt_cues(segment: &[u8], shift: f64) -> Vec<u8> {
+    let segment = String::from_utf8_lossy(segment);
+    let mut shifted = String::with_capacity(segment.len());
+
+    for line in segment.split_inclusive('\n') {
+        let cue_timing = line.split_once(" --> ").and_then(|(start, rest)| {
+            let (end, settings) = rest.split_at(rest.find(char::is_whitespace).unwrap_or(rest.len()));
+            let start = parse_webvtt_timestamp(start.trim())?;
+            let end = parse_webvtt_timestamp(end)?;
+            Some((start, end, settings))
+        });
+
+        match cue_timing {
+            Some((start, end, settings)) => {
+                let _ = write!(
+                    shifted,
+                    "{} --> {}{}",
+                    format_webvtt_timestamp(start + shift),
+                    format_webvtt_timestamp(end + shift),
+                    settings
+                );
+            }
+            None => shifted.push_str(line),
+        }
+    }
+
+    shifted.into_bytes()
+}
+
+/// Parses `hh:mm:ss.ttt` or `mm:ss.ttt` into seconds
+fn parse_webvtt_timestamp(timestamp: &str) -> Option<f64> {
+    let mut seconds = 0.0;
+
+    for part in timestamp.split(':') {
+        seconds = seconds * 60.0 + part.parse::<f64>().ok()?;
+    }
+
+    Some(seconds)
+}
+
+fn format_webvtt_timestamp(seconds: f64) -> String {
+    let millis = (seconds.max(0.0) * 1000.0).round() as u64;
+
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1000 % 60,
+        millis % 1000
+    )
+}
+
+/// Removes the `WEBVTT` header block, which may only appear once per file
+fn strip_webvtt_header(segment: &[u8]) -> &[u8] {
+    if !segment.starts_with(b"WEBVTT") && !segment.starts_with("\u{feff}WEBVTT".as_bytes()) {
+        return segment;
+    }
+
+    let header_end = segment
+        .windows(2)
+        .position(|window| window == b"\n\n")
+        .map(|position| position + 1)
+        .or_else(|| {
+            segment
+                .windows(4)
+                .position(|window| window == b"\r\n\r\n")
+                .map(|position| position + 2)
+        });
+
+    match header_end {
+        Some(header_end) => &segment[header_end..],
+        None => &[],
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct SegmentInit {
+    url: Url,
+    byte_range: Option<Range<u64>>,
+    /// An init section is encrypted with the `EXT-X-KEY` that applies to its `EXT-X-MAP`
+    encryption: Option<SegmentEncryption>,
+}
+
+/// Resolves the `EXT-X-MAP` init section of every segment. Like `EXT-X-KEY`,
+/// it applies to all following segments until the next one.
+fn get_segment_inits(
+    media_playlist: &m3u8_rs::MediaPlaylist,
+    media_playlist_url: &Url,
+    segment_encryptions: &[Option<SegmentEncryption>],
+) -> Result<Vec<Option<SegmentInit>>, anyhow::Error> {
+    let mut current_init = None;
+    let mut segment_inits = Vec::with_capacity(media_playlist.segments.len());
+
+    for (segment, encryption) in media_playlist.segments.iter().zip(segment_encryptions) {
+        if let Some(map) = &segment.map {
+            let url = media_playlist_url
+                .join(&map.uri)
+                .with_context(|| "failed to create m3u8 init section url")?;
+            let byte_range = map.byte_range.as_ref().map(|byte_range| {
+                let offset = byte_range.offset.unwrap_or(0);
+                offset..offset + byte_range.length
+            });
+
+            current_init = Some(SegmentInit {
+                url,
+                byte_range,
+                encryption: encryption.clone(),
+            });
         }
 
-        #[cfg(not(target_arch = "wasm32"))]
-        fn classify_io_error(error: &std::io::Error) -> Retryable {
-            match error.kind() {
-                std::io::ErrorKind::ConnectionReset | std::io::ErrorKind::ConnectionAborted => Retryable::Transient,
-                _ => Retryable::Fatal,
+        segment_inits.push(current_init.clone());
+    }
+
+    Ok(segment_inits)
+}
+
+/// Resolves the `EXT-X-BYTERANGE` of every segment. A byte range without an
+/// offset starts right after the previous sub-range of the same resource.
+fn get_segment_byte_ranges(media_playlist: &m3u8_rs::MediaPlaylist) -> Result<Vec<Option<Range<u64>>>, anyhow::Error> {
+    let mut previous: Option<(&str, u64)> = None;
+    let mut segment_byte_ranges = Vec::with_capacity(media_playlist.segments.len());
+
+    for segment in &media_playlist.segments {
+        let segment_byte_range = match &segment.byte_range {
+            Some(byte_range) => {
+                let offset = match (byte_range.offset, previous) {
+                    (Some(offset), _) => offset,
+                    (None, Some((previous_uri, previous_end))) if previous_uri == segment.uri => previous_end,
+                    (None, _) => anyhow::bail!("m3u8 byte range is missing an offset"),
+                };
+
+                Some(offset..offset + byte_range.length)
             }
+            None => None,
+        };
+
+        previous = segment_byte_range
+            .as_ref()
+            .map(|byte_range| (segment.uri.as_str(), byte_range.end));
+        segment_byte_ranges.push(segment_byte_range);
+    }
+
+    Ok(segment_byte_ranges)
+}
+
+/// Splits the segments at every `EXT-X-DISCONTINUITY` and every change of the
+/// `EXT-X-MAP` init section into blocks, which are written to separate files and
+/// concatenated by FFmpeg afterwards.
+fn get_discontinuity_blocks(segments: &[m3u8_rs::MediaSegment]) -> Vec<Range<usize>> {
+    let mut blocks = Vec::new();
+    let mut block_start = 0;
+    let mut current_map = segments.first().and_then(|segment| segment.map.as_ref());
+
+    for (segment_index, segment) in segments.iter().enumerate().skip(1) {
+        let map_changed = segment.map.is_some() && segment.map.as_ref() != current_map;
+
+        if segment.discontinuity || map_changed {
+            blocks.push(block_start..segment_index);
+            block_start = segment_index;
         }
 
-        /// Downcasts the given err source into T.
-        #[cfg(not(target_arch = "wasm32"))]
-        fn get_source_error_type<T: std::error::Error + 'static>(err: &dyn std::error::Error) -> Option<&T> {
-            let mut source = err.source();
+        if segment.map.is_some() {
+            current_map = segment.map.as_ref();
+        }
+    }
 
-            while let Some(err) = source {
-                if let Some(err) = err.downcast_ref::<T>() {
-                    return Some(err);
+    if block_start < segments.len() {
+        blocks.push(block_start..segments.len());
+    }
+
+    blocks
+}
+
+async fn remove_block_state_files(state_paths: &[PathBuf]) {
+    for state_path in state_paths {
+        if let Err(err) = remove_file_ignore_not_exists(state_path).await {
+            log::warn!("Failed to delete download state file: {}", err);
+        }
+    }
+}
+
+/// Escapes a path for a file directive of the FFmpeg concat demuxer
+fn escape_ffconcat_path(path: &Path) -> String {
+    format!("'{}'", path.to_string_lossy().replace('\'', "'\\''"))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct SegmentEncryption {
+    key_url: Url,
+    iv: [u8; 16],
+}
+
+/// Resolves the AES-128 key and IV of every segment. An `EXT-X-KEY` tag applies
+/// to all following segments until the next one.
+fn get_segment_encryptions(
+    media_playlist: &m3u8_rs::MediaPlaylist,
+    media_playlist_url: &Url,
+) -> Result<Vec<Option<SegmentEncryption>>, anyhow::Error> {
+    let mut current_key: Option<&m3u8_rs::Key> = None;
+    let mut segment_encryptions = Vec::with_capacity(media_playlist.segments.len());
+
+    for (segment_index, segment) in media_playlist.segments.iter().enumerate() {
+        if let Some(key) = &segment.key {
+            current_key = Some(key);
+        }
+
+        let segment_encryption = match current_key {
+            None => None,
+            Some(key) => match &key.method {
+                m3u8_rs::KeyMethod::None => None,
+                m3u8_rs::KeyMethod::AES128 => {
+                    let key_uri = key.uri.as_deref().with_context(|| "m3u8 key is missing uri")?;
+                    let key_url = media_playlist_url
+                        .join(key_uri)
+                        .with_context(|| "failed to create m3u8 key url")?;
+                    let iv = match key.iv.as_deref() {
+                        Some(iv) => parse_iv(iv)?,
+                        None => (media_playlist.media_sequence as u128 + segment_index as u128).to_be_bytes(),
+                    };
+
+                    Some(SegmentEncryption { key_url, iv })
                 }
+                m3u8_rs::KeyMethod::SampleAES => anyhow::bail!("unsupported m3u8 encryption method: SAMPLE-AES"),
+                m3u8_rs::KeyMethod::Other(method) => anyhow::bail!("unsupported m3u8 encryption method: {}", method),
+            },
+        };
 
-                source = err.source();
-            }
-            None
+        segment_encryptions.push(segment_encryption);
+    }
+
+    Ok(segment_encryptions)
+}
+
+fn parse_iv(iv: &str) -> Result<[u8; 16], anyhow::Error> {
+    let hex = iv.strip_prefix("0x").or_else(|| iv.strip_prefix("0X")).unwrap_or(iv);
+
+    if hex.is_empty() || hex.len() > 32 {
+        anyhow::bail!("invalid m3u8 iv: {}", iv);
+    }
+
+    u128::from_str_radix(hex, 16)
+        .map(u128::to_be_bytes)
+        .with_context(|| format!("invalid m3u8 iv: {}", iv))
+}
+
+fn decrypt_aes128_cbc(key: &[u8; 16], iv: &[u8; 16], data: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+    use aes::cipher::generic_array::GenericArray;
+    use aes::cipher::{BlockDecrypt, KeyInit};
+
+    const BLOCK_SIZE: usize = 16;
+
+    if data.is_empty() || data.len() % BLOCK_SIZE != 0 {
+        anyhow::bail!("encrypted data length is not a multiple of the block size");
+    }
+
+    let cipher = aes::Aes128::new(GenericArray::from_slice(key));
+    let mut decrypted = data.to_vec();
+    let mut previous_block = *iv;
+
+    for block in decrypted.chunks_exact_mut(BLOCK_SIZE) {
+        let mut encrypted_block = [0u8; BLOCK_SIZE];
+        encrypted_block.copy_from_slice(block);
+        cipher.decrypt_block(GenericArray::from_mut_slice(block));
+
+        for (byte, previous_byte) in block.iter_mut().zip(previous_block) {
+            *byte ^= previous_byte;
         }
+
+        previous_block = encrypted_block;
+    }
+
+    // Remove PKCS#7 padding
+    let padding_length = *decrypted.last().unwrap() as usize;
+
+    if padding_length == 0
+        || padding_length > BLOCK_SIZE
+        || !decrypted[decrypted.len() - padding_length..]
+            .iter()
+            .all(|&byte| byte as usize == padding_length)
+    {
+        anyhow::bail!("invalid padding");
     }
+
+    decrypted.truncate(decrypted.len() - padding_length);
+    Ok(decrypted)
 }
 
-fn is_m3u8_url(url: &Url) -> bool {
-    url.path_segments()
-        .and_then(|segments| segments.last())
-        .map(|last| {
-            let lower = last.to_ascii_lowercase();
-            (lower.ends_with(".m3u8") && lower.len() != ".m3u8".len())
-                || (lower.ends_with(".m3u") && lower.len() != ".m3u".len())
-        })
-        .unwrap_or(false)
+fn path_with_added_extension(path: &Path, extension: &str) -> PathBuf {
+    let mut file_name = path
+        .file_name()
+        .map(|file_name| file_name.to_owned())
+        .unwrap_or_default();
+    file_name.push(".");
+    file_name.push(extension);
+    path.with_file_name(file_name)
 }
 
-fn prepare_series_name_for_file(name: &str) -> Option<String> {
+pub(crate) fn prepare_series_name_for_file(name: &str) -> Option<String> {
     use regex::Regex;
 
     const NAME_LIMIT: usize = 160;
@@ -1101,15 +4368,8 @@ fn prepare_series_name_for_file(name: &str) -> Option<String> {
     let no_multiple_space = MULTIPLE_SPACE.replace_all(&no_extra, " ");
     let no_dot_or_space_at_ends = no_multiple_space.trim_matches(|c: char| c == ' ' || c == '.');
 
-    // Not needed, because we still append something to the filename
-    //
-    // static WINDOWS_RESERVED_REGEX: Lazy<Regex> = Lazy::new(||
-    // Regex::new(r"^(?:con|prn|aux|nul|com\d|lpt\d)$").unwrap());
-    // let no_reserved = if
-    // WINDOWS_RESERVED_REGEX.is_match(&no_dot_or_space_at_ends) {     format!("
-    // {no_dot_or_space_at_ends}_") } else {
-    //     no_dot_or_space_at_ends.to_owned()
-    // };
+    // Not needed for file names, because we still append something to them.
+    // Directory names are checked with `avoid_windows_reserved_name`.
 
     if no_dot_or_space_at_ends.is_empty() {
         None
@@ -1128,6 +4388,34 @@ fn prepare_series_name_for_file(name: &str) -> Option<String> {
     }
 }
 
+/// Windows can not create files or directories with names like `CON` or `aux.txt`.
+/// Such names get an underscore appended to their first part.
+pub(crate) fn avoid_windows_reserved_name(name: &str) -> Cow<'_, str> {
+    use regex::Regex;
+
+    static WINDOWS_RESERVED_REGEX: Lazy<Regex> =
+        Lazy::new(|| Regex::new(r"(?i)^(con|prn|aux|nul|com\d|lpt\d)(\..*)?$").unwrap());
+
+    WINDOWS_RESERVED_REGEX.replace(name, "${1}_${2}")
+}
+
+/// Directory of an episode relative to the save directory, movies have their own folder
+fn get_series_directory(anime_name: Option<&str>, episode_info: &EpisodeInfo) -> PathBuf {
+    let mut directory = PathBuf::new();
+
+    if let Some(anime_name) = anime_name {
+        directory.push(avoid_windows_reserved_name(anime_name).as_ref());
+    }
+
+    match episode_info.season_number {
+        Some(0) => directory.push("Movies"),
+        Some(season) => directory.push(format!("Season {:02}", season)),
+        None => {}
+    }
+
+    directory
+}
+
 pub(crate) fn get_episode_name(
     anime_name: Option<&str>,
     language: Option<&VideoType>,
@@ -1170,7 +4458,7 @@ pub(crate) fn get_episode_name(
     output_name
 }
 
-fn format_episode_number(episode_number: &EpisodeNumber, alignment_episode_number: Option<usize>) -> String {
+pub(crate) fn format_episode_number(episode_number: &EpisodeNumber, alignment_episode_number: Option<usize>) -> String {
     match episode_number {
         EpisodeNumber::Number(episode_number) => {
             format!("{episode_number:0>fill$}", fill = alignment_episode_number.unwrap_or(2))
@@ -1198,8 +4486,21 @@ fn format_episode_number(episode_number: &EpisodeNumber, alignment_episode_numbe
 
 #[cfg(test)]
 mod tests {
-    use crate::download::format_episode_number;
-    use crate::downloaders::EpisodeNumber;
+    use std::path::{Path, PathBuf};
+    use std::time::Duration;
+
+    use url::Url;
+
+    use crate::download::{
+        avoid_windows_reserved_name, decrypt_aes128_cbc, escape_ffconcat_path, format_episode_number,
+        get_discontinuity_blocks, get_min_duration, get_range_header, get_rendition_metadata_args, get_renditions,
+        get_segment_byte_ranges, get_segment_encryptions, get_segment_inits, get_series_directory,
+        get_url_without_query, is_local_error, parse_content_range_start, parse_ffmpeg_out_time, parse_iv,
+        parse_webvtt_timestamp_offset, path_with_added_extension, shift_webvtt_cues, split_byte_ranges,
+        strip_webvtt_header, to_iso639_2, variant_has_audio, ByteRate, LocalError, MediaMetadata, PartFile,
+        RenditionKind, ResumeState, SegmentEncryption, TokenBucket, UnfinishedFiles, VariantQuality,
+    };
+    use crate::downloaders::{EpisodeInfo, EpisodeNumber, Language, SeriesInfo, VideoType};
 
     #[test]
     fn test_fix_filename() {
@@ -1263,4 +4564,748 @@ mod tests {
             assert_eq!(format_episode_number(&input.0, input.1), output.to_string());
         }
     }
+
+    #[test]
+    fn test_media_metadata_args() {
+        let series_info = SeriesInfo {
+            title: "Detektiv Conan".to_owned(),
+            description: Some("Shinichi Kudo".to_owned()),
+            status: None,
+            year: Some(1996),
+            poster_url: None,
+            backdrop_url: None,
+        };
+        let episode_info = EpisodeInfo {
+            name: Some("Der Achterbahnmord".to_owned()),
+            season_number: Some(1),
+            episode_number: EpisodeNumber::Number(1),
+            max_episode_number_in_season: Some(30),
+        };
+
+        assert_eq!(
+            MediaMetadata::new(&series_info, &episode_info, VideoType::Dub(Language::German)).ffmpeg_args(),
+            [
+                "-metadata",
+                "show=Detektiv Conan",
+                "-metadata",
+                "title=Der Achterbahnmord",
+                "-metadata",
+                "season_number=1",
+                "-metadata",
+                "episode_sort=1",
+                "-metadata",
+                "episode_id=S01E01",
+                "-metadata",
+                "description=Shinichi Kudo",
+                "-metadata",
+                "synopsis=Shinichi Kudo",
+                "-metadata:s:a:0",
+                "language=ger",
+            ]
+        );
+
+        let series_info = SeriesInfo {
+            description: None,
+            ..series_info
+        };
+        let episode_info = EpisodeInfo {
+            name: None,
+            season_number: None,
+            episode_number: EpisodeNumber::String("1.5".to_owned()),
+            ..episode_info
+        };
+
+        assert_eq!(
+            MediaMetadata::new(&series_info, &episode_info, VideoType::Sub(Language::English)).ffmpeg_args(),
+            ["-metadata", "show=Detektiv Conan", "-metadata", "episode_id=E01.5"]
+        );
+    }
+
+    #[test]
+    fn test_parse_ffmpeg_out_time() {
+        assert_eq!(parse_ffmpeg_out_time("out_time_us=12500000"), Some(12.5));
+        assert_eq!(parse_ffmpeg_out_time("out_time_ms=1000000"), Some(1.0));
+        assert_eq!(parse_ffmpeg_out_time("out_time_us=-5000"), Some(0.0));
+        assert_eq!(parse_ffmpeg_out_time("out_time_us=N/A"), None);
+        assert_eq!(parse_ffmpeg_out_time("out_time=00:00:12.500000"), None);
+        assert_eq!(parse_ffmpeg_out_time("progress=continue"), None);
+    }
+
+    #[test]
+    fn test_get_min_duration() {
+        assert_eq!(get_min_duration(None, None), None);
+        assert_eq!(get_min_duration(None, Some(60.0)), Some(60.0));
+        assert_eq!(get_min_duration(Some(1400.0), Some(60.0)), Some(1370.0));
+        assert!(get_min_duration(Some(1.0), None).unwrap() < 0.0);
+    }
+
+    #[test]
+    fn test_avoid_windows_reserved_name() {
+        assert_eq!(avoid_windows_reserved_name("CON"), "CON_");
+        assert_eq!(avoid_windows_reserved_name("aux.txt"), "aux_.txt");
+        assert_eq!(avoid_windows_reserved_name("Com1"), "Com1_");
+        assert_eq!(avoid_windows_reserved_name("Conan"), "Conan");
+        assert_eq!(avoid_windows_reserved_name("Season 01"), "Season 01");
+    }
+
+    #[test]
+    fn test_get_series_directory() {
+        let episode_info = |season_number| EpisodeInfo {
+            name: None,
+            season_number,
+            episode_number: EpisodeNumber::Number(1),
+            max_episode_number_in_season: None,
+        };
+
+        assert_eq!(
+            get_series_directory(Some("Detektiv Conan"), &episode_info(Some(2))),
+            PathBuf::from("Detektiv Conan").join("Season 02")
+        );
+        assert_eq!(
+            get_series_directory(Some("Detektiv Conan"), &episode_info(Some(0))),
+            PathBuf::from("Detektiv Conan").join("Movies")
+        );
+        assert_eq!(
+            get_series_directory(Some("Yuruyuri"), &episode_info(None)),
+            PathBuf::from("Yuruyuri")
+        );
+        assert_eq!(
+            get_series_directory(None, &episode_info(Some(12))),
+            PathBuf::from("Season 12")
+        );
+    }
+
+    #[test]
+    fn test_path_with_added_extension() {
+        assert_eq!(
+            path_with_added_extension(Path::new("/tmp/Series - S01E01.ts"), "part"),
+            Path::new("/tmp/Series - S01E01.ts.part")
+        );
+        assert_eq!(
+            path_with_added_extension(Path::new("episode.mp4.part"), "state"),
+            Path::new("episode.mp4.part.state")
+        );
+    }
+
+    #[test]
+    fn test_resume_state() {
+        let state = ResumeState {
+            url: "https://example.com/master.m3u8?t=1".to_string(),
+            total: Some(120),
+            bytes_written: 1_048_576,
+            segments_completed: Some(12),
+            range_bytes_written: None,
+        };
+
+        assert_eq!(ResumeState::from_json(&state.to_json()), Some(state.clone()));
+        assert!(!state.is_compatible("https://example.com/master.m3u8?t=1", None));
+        assert!(state.is_compatible("https://example.com/master.m3u8?t=1", Some(120)));
+        assert!(!state.is_compatible("https://example.com/master.m3u8?t=2", Some(120)));
+
+        let direct_state = ResumeState {
+            url: "https://example.com/video.mp4?t=1".to_string(),
+            total: Some(300),
+            bytes_written: 150,
+            segments_completed: None,
+            range_bytes_written: None,
+        };
+
+        assert!(direct_state.is_compatible("https://example.com/video.mp4?t=1", None));
+        assert!(direct_state.is_compatible("https://example.com/video.mp4?t=2", Some(300)));
+        assert!(!direct_state.is_compatible("https://example.com/video.mp4?t=2", Some(301)));
+        assert!(!direct_state.is_compatible("https://example.com/video.mp4?t=2", None));
+        assert_eq!(ResumeState::from_json(&serde_json::json!({ "url": "x" })), None);
+
+        let ranged_state = ResumeState {
+            url: "https://example.com/video.mp4".to_string(),
+            total: Some(300),
+            bytes_written: 150,
+            segments_completed: None,
+            range_bytes_written: Some(vec![100, 50, 0]),
+        };
+
+        assert_eq!(
+            ResumeState::from_json(&ranged_state.to_json()),
+            Some(ranged_state.clone())
+        );
+    }
+
+    #[test]
+    fn test_parse_content_range_start() {
+        assert_eq!(parse_content_range_start("bytes 1024-2047/4096"), Some(1024));
+        assert_eq!(parse_content_range_start("bytes 0-99/*"), Some(0));
+        assert_eq!(parse_content_range_start("bytes */4096"), None);
+        assert_eq!(parse_content_range_start("items 0-9/10"), None);
+    }
+
+    #[test]
+    fn test_get_url_without_query() {
+        assert_eq!(
+            get_url_without_query(&Url::parse("https://example.com/hls/index.m3u8?t=1&e=2#x").unwrap()),
+            "https://example.com/hls/index.m3u8"
+        );
+    }
+
+    #[test]
+    fn test_get_range_header() {
+        assert_eq!(get_range_header(&(100..200), 0), "bytes=100-199");
+        // A retried range resumes after the bytes that were already written
+        assert_eq!(get_range_header(&(100..200), 40), "bytes=140-199");
+    }
+
+    #[test]
+    fn test_is_local_error() {
+        use anyhow::Context;
+
+        let err = anyhow::Error::from(LocalError("download target file already exists"));
+        assert!(is_local_error(&err));
+        let err = Err::<(), _>(std::io::Error::from(std::io::ErrorKind::PermissionDenied))
+            .with_context(|| "failed to open download target file")
+            .unwrap_err();
+        assert!(is_local_error(&err));
+        assert!(!is_local_error(&anyhow::anyhow!("failed to parse m3u8")));
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        let token_bucket = TokenBucket::new("1000".parse::<ByteRate>().unwrap());
+        let start = token_bucket.last_refill.get();
+
+        // The bucket starts full with one second of bytes
+        assert_eq!(token_bucket.take(600, start), Duration::ZERO);
+        assert_eq!(token_bucket.take(600, start), Duration::from_millis(200));
+        // Half a second refills 500 bytes, which pays the debt of 200 bytes
+        assert_eq!(
+            token_bucket.take(300, start + Duration::from_millis(500)),
+            Duration::ZERO
+        );
+        // The bucket never holds more than its capacity
+        assert_eq!(token_bucket.take(1000, start + Duration::from_secs(60)), Duration::ZERO);
+        // A request larger than the capacity waits until the whole debt is paid
+        let later = start + Duration::from_secs(120);
+        assert_eq!(token_bucket.take(3000, later), Duration::from_secs(2));
+        assert_eq!(token_bucket.take(0, later + Duration::from_secs(2)), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_split_byte_ranges() {
+        assert_eq!(split_byte_ranges(100, 4, 10), vec![0..25, 25..50, 50..75, 75..100]);
+        assert_eq!(split_byte_ranges(101, 3, 10), vec![0..33, 33..66, 66..101]);
+        assert_eq!(split_byte_ranges(25, 4, 10), vec![0..12, 12..25]);
+        assert_eq!(split_byte_ranges(5, 4, 10).len(), 1);
+        assert_eq!(split_byte_ranges(100, 1, 10).len(), 1);
+        assert!(split_byte_ranges(0, 4, 10).is_empty());
+    }
+
+    #[test]
+    fn test_parse_iv() {
+        assert_eq!(
+            parse_iv("0x000102030405060708090A0B0C0D0E0F").unwrap(),
+            [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15]
+        );
+        assert_eq!(parse_iv("0X1").unwrap(), 1u128.to_be_bytes());
+        assert!(parse_iv("0x").is_err());
+        assert!(parse_iv("0xZZ").is_err());
+        assert!(parse_iv("0x000102030405060708090A0B0C0D0E0F10").is_err());
+    }
+
+    #[test]
+    fn test_decrypt_aes128_cbc() {
+        use aes::cipher::generic_array::GenericArray;
+        use aes::cipher::{BlockEncrypt, KeyInit};
+
+        // NIST SP 800-38A, F.2.1 CBC-AES128.Encrypt, first block
+        let key = 0x2b7e151628aed2a6abf7158809cf4f3cu128.to_be_bytes();
+        let iv = 0x000102030405060708090a0b0c0d0e0fu128.to_be_bytes();
+        let plaintext = 0x6bc1bee22e409f96e93d7e117393172au128.to_be_bytes();
+        let ciphertext = 0x7649abac8119b246cee98e9b12e9197du128.to_be_bytes();
+
+        // Append the encrypted PKCS#7 padding block
+        let mut padding_block = [16u8; 16];
+        for (byte, previous_byte) in padding_block.iter_mut().zip(ciphertext) {
+            *byte ^= previous_byte;
+        }
+        aes::Aes128::new(GenericArray::from_slice(&key))
+            .encrypt_block(GenericArray::from_mut_slice(&mut padding_block));
+
+        let data = [ciphertext, padding_block].concat();
+        assert_eq!(decrypt_aes128_cbc(&key, &iv, &data).unwrap(), plaintext);
+        assert!(decrypt_aes128_cbc(&key, &iv, &ciphertext).is_err());
+        assert!(decrypt_aes128_cbc(&key, &iv, &data[..20]).is_err());
+    }
+
+    #[test]
+    fn test_get_segment_encryptions() {
+        let media_playlist_url = url::Url::parse("https://example.com/hls/index.m3u8").unwrap();
+        let segment = |key: Option<m3u8_rs::Key>| m3u8_rs::MediaSegment {
+            uri: "segment.ts".to_string(),
+            key,
+            ..Default::default()
+        };
+        let media_playlist = m3u8_rs::MediaPlaylist {
+            media_sequence: 7,
+            segments: vec![
+                segment(None),
+                segment(Some(m3u8_rs::Key {
+                    method: m3u8_rs::KeyMethod::AES128,
+                    uri: Some("key.bin".to_string()),
+                    ..Default::default()
+                })),
+                segment(None),
+                segment(Some(m3u8_rs::Key {
+                    method: m3u8_rs::KeyMethod::AES128,
+                    uri: Some("/other.key".to_string()),
+                    iv: Some("0x10".to_string()),
+                    ..Default::default()
+                })),
+                segment(Some(m3u8_rs::Key {
+                    method: m3u8_rs::KeyMethod::None,
+                    ..Default::default()
+                })),
+            ],
+            ..Default::default()
+        };
+
+        let encryptions = get_segment_encryptions(&media_playlist, &media_playlist_url).unwrap();
+        let encryptions = encryptions
+            .iter()
+            .map(|encryption| {
+                encryption
+                    .as_ref()
+                    .map(|encryption| (encryption.key_url.as_str(), encryption.iv))
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            encryptions,
+            [
+                None,
+                Some(("https://example.com/hls/key.bin", 8u128.to_be_bytes())),
+                Some(("https://example.com/hls/key.bin", 9u128.to_be_bytes())),
+                Some(("https://example.com/other.key", 16u128.to_be_bytes())),
+                None,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_get_segment_inits() {
+        let media_playlist_url = url::Url::parse("https://example.com/hls/index.m3u8").unwrap();
+        let segment = |map: Option<m3u8_rs::Map>| m3u8_rs::MediaSegment {
+            uri: "segment.m4s".to_string(),
+            map,
+            ..Default::default()
+        };
+        let media_playlist = m3u8_rs::MediaPlaylist {
+            segments: vec![
+                segment(Some(m3u8_rs::Map {
+                    uri: "init.mp4".to_string(),
+                    ..Default::default()
+                })),
+                segment(None),
+                segment(Some(m3u8_rs::Map {
+                    uri: "../init2.mp4".to_string(),
+                    byte_range: Some(m3u8_rs::ByteRange {
+                        length: 100,
+                        offset: Some(50),
+                    }),
+                })),
+            ],
+            ..Default::default()
+        };
+
+        let inits = get_segment_inits(&media_playlist, &media_playlist_url, &[None, None, None]).unwrap();
+        let inits = inits
+            .iter()
+            .map(|init| init.as_ref().map(|init| (init.url.as_str(), init.byte_range.clone())))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            inits,
+            [
+                Some(("https://example.com/hls/init.mp4", None)),
+                Some(("https://example.com/hls/init.mp4", None)),
+                Some(("https://example.com/init2.mp4", Some(50..150))),
+            ]
+        );
+
+        let media_playlist = m3u8_rs::MediaPlaylist {
+            segments: vec![segment(None)],
+            ..Default::default()
+        };
+        assert_eq!(
+            get_segment_inits(&media_playlist, &media_playlist_url, &[None]).unwrap(),
+            [None]
+        );
+
+        let encryption = SegmentEncryption {
+            key_url: media_playlist_url.join("key.bin").unwrap(),
+            iv: [1; 16],
+        };
+        let media_playlist = m3u8_rs::MediaPlaylist {
+            segments: vec![segment(Some(m3u8_rs::Map {
+                uri: "init.mp4".to_string(),
+                ..Default::default()
+            }))],
+            ..Default::default()
+        };
+        let inits = get_segment_inits(&media_playlist, &media_playlist_url, &[Some(encryption.clone())]).unwrap();
+        assert_eq!(inits[0].as_ref().unwrap().encryption, Some(encryption));
+    }
+
+    #[test]
+    fn test_get_segment_byte_ranges() {
+        let segment = |uri: &str, byte_range: Option<(u64, Option<u64>)>| m3u8_rs::MediaSegment {
+            uri: uri.to_string(),
+            byte_range: byte_range.map(|(length, offset)| m3u8_rs::ByteRange { length, offset }),
+            ..Default::default()
+        };
+        let media_playlist = m3u8_rs::MediaPlaylist {
+            segments: vec![
+                segment("video.ts", Some((100, Some(0)))),
+                segment("video.ts", Some((200, None))),
+                segment("video.ts", Some((50, Some(1000)))),
+                segment("other.ts", None),
+            ],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            get_segment_byte_ranges(&media_playlist).unwrap(),
+            [Some(0..100), Some(100..300), Some(1000..1050), None]
+        );
+
+        let media_playlist = m3u8_rs::MediaPlaylist {
+            segments: vec![
+                segment("video.ts", Some((100, Some(0)))),
+                segment("other.ts", Some((100, None))),
+            ],
+            ..Default::default()
+        };
+        assert!(get_segment_byte_ranges(&media_playlist).is_err());
+    }
+
+    #[test]
+    fn test_get_discontinuity_blocks() {
+        let segment = |discontinuity: bool| m3u8_rs::MediaSegment {
+            discontinuity,
+            ..Default::default()
+        };
+
+        assert!(get_discontinuity_blocks(&[]).is_empty());
+        // A discontinuity before the first segment does not start a new block
+        assert_eq!(
+            get_discontinuity_blocks(&[segment(true), segment(false), segment(true)]),
+            [0..2, 2..3]
+        );
+        assert_eq!(
+            get_discontinuity_blocks(&[
+                segment(false),
+                segment(false),
+                segment(true),
+                segment(false),
+                segment(true)
+            ]),
+            [0..2, 2..4, 4..5]
+        );
+
+        // A repeated init section does not start a new block, a changed one does
+        let segment_with_map = |uri: &str| m3u8_rs::MediaSegment {
+            map: Some(m3u8_rs::Map {
+                uri: uri.to_string(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        assert_eq!(
+            get_discontinuity_blocks(&[
+                segment_with_map("init.mp4"),
+                segment(false),
+                segment_with_map("init.mp4"),
+                segment_with_map("init2.mp4"),
+                segment(false)
+            ]),
+            [0..3, 3..5]
+        );
+    }
+
+    #[test]
+    fn test_escape_ffconcat_path() {
+        assert_eq!(
+            escape_ffconcat_path(Path::new("/tmp/Ep. 1 (Ger Sub).1.ts")),
+            "'/tmp/Ep. 1 (Ger Sub).1.ts'"
+        );
+        assert_eq!(escape_ffconcat_path(Path::new("Joe's.ts")), "'Joe'\\''s.ts'");
+    }
+
+    #[test]
+    fn test_parse_variant_quality() {
+        let tests = [
+            ("best", VariantQuality::Best),
+            ("Worst", VariantQuality::Worst),
+            ("720p", VariantQuality::MaxHeight(720)),
+            ("480", VariantQuality::MaxHeight(480)),
+            ("3000k", VariantQuality::MaxBandwidth(3_000_000)),
+            ("5M", VariantQuality::MaxBandwidth(5_000_000)),
+        ];
+
+        for (input, expected) in tests {
+            assert_eq!(
+                input.parse::<VariantQuality>().unwrap(),
+                expected,
+                "failed for {}",
+                input
+            );
+        }
+
+        assert!("high".parse::<VariantQuality>().is_err());
+        assert!("p".parse::<VariantQuality>().is_err());
+    }
+
+    #[test]
+    fn test_parse_byte_rate() {
+        let tests = [
+            ("500K", 500 * 1024, "500K"),
+            ("2m", 2 * 1024 * 1024, "2M"),
+            ("1.5M", 1536 * 1024, "1536K"),
+            ("1GB/s", 1024 * 1024 * 1024, "1G"),
+            ("1000", 1000, "1000"),
+        ];
+
+        for (input, expected_bytes, expected_display) in tests {
+            let rate = input.parse::<ByteRate>().unwrap();
+            assert_eq!(rate.bytes_per_second(), expected_bytes, "failed for {}", input);
+            assert_eq!(rate.to_string(), expected_display, "failed for {}", input);
+        }
+
+        assert!("0".parse::<ByteRate>().is_err());
+        assert!("fast".parse::<ByteRate>().is_err());
+        assert!("-1M".parse::<ByteRate>().is_err());
+    }
+
+    #[test]
+    fn test_select_variant() {
+        let variant = |uri: &str, height: u64, bandwidth: u64, is_i_frame: bool| m3u8_rs::VariantStream {
+            is_i_frame,
+            uri: uri.to_string(),
+            bandwidth,
+            resolution: Some(m3u8_rs::Resolution {
+                width: height * 16 / 9,
+                height,
+            }),
+            ..Default::default()
+        };
+        let variants = [
+            variant("480.m3u8", 480, 1_000_000, false),
+            variant("1080.m3u8", 1080, 5_000_000, false),
+            variant("iframe.m3u8", 2160, 500_000, true),
+            variant("720.m3u8", 720, 3_000_000, false),
+        ];
+        let select = |quality: VariantQuality| quality.select_variant(&variants).map(|variant| variant.uri.as_str());
+
+        assert_eq!(select(VariantQuality::Best), Some("1080.m3u8"));
+        assert_eq!(select(VariantQuality::Worst), Some("480.m3u8"));
+        assert_eq!(select(VariantQuality::MaxHeight(720)), Some("720.m3u8"));
+        assert_eq!(select(VariantQuality::MaxHeight(1000)), Some("720.m3u8"));
+        assert_eq!(select(VariantQuality::MaxHeight(360)), Some("480.m3u8"));
+        assert_eq!(select(VariantQuality::MaxBandwidth(4_000_000)), Some("720.m3u8"));
+        assert_eq!(select(VariantQuality::MaxBandwidth(100)), Some("480.m3u8"));
+        assert_eq!(VariantQuality::Best.select_variant(&variants[2..3]), None);
+    }
+
+    #[test]
+    fn test_get_renditions() {
+        let master_playlist_url = url::Url::parse("https://example.com/hls/master.m3u8").unwrap();
+        let alternative =
+            |media_type: m3u8_rs::AlternativeMediaType, group_id: &str, uri: Option<&str>, language: &str| {
+                m3u8_rs::AlternativeMedia {
+                    media_type,
+                    uri: uri.map(str::to_string),
+                    group_id: group_id.to_string(),
+                    language: Some(language.to_string()),
+                    name: language.to_uppercase(),
+                    ..Default::default()
+                }
+            };
+        let master_playlist = m3u8_rs::MasterPlaylist {
+            alternatives: vec![
+                alternative(m3u8_rs::AlternativeMediaType::Audio, "aud", None, "ja"),
+                alternative(m3u8_rs::AlternativeMediaType::Audio, "aud", Some("audio/de.m3u8"), "de"),
+                alternative(
+                    m3u8_rs::AlternativeMediaType::Audio,
+                    "other",
+                    Some("audio/en.m3u8"),
+                    "en",
+                ),
+                alternative(
+                    m3u8_rs::AlternativeMediaType::Subtitles,
+                    "subs",
+                    Some("subs/de.m3u8"),
+                    "de",
+                ),
+            ],
+            ..Default::default()
+        };
+        let variant = m3u8_rs::VariantStream {
+            uri: "video.m3u8".to_string(),
+            audio: Some("aud".to_string()),
+            subtitles: Some("subs".to_string()),
+            ..Default::default()
+        };
+
+        let renditions = get_renditions(&master_playlist, &variant, &master_playlist_url).unwrap();
+        let renditions_summary = renditions
+            .iter()
+            .map(|rendition| (rendition.kind, rendition.url.as_str(), rendition.language.as_deref()))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            renditions_summary,
+            [
+                (
+                    RenditionKind::Audio,
+                    "https://example.com/hls/audio/de.m3u8",
+                    Some("de")
+                ),
+                (
+                    RenditionKind::Subtitles,
+                    "https://example.com/hls/subs/de.m3u8",
+                    Some("de")
+                ),
+            ]
+        );
+        assert!(variant_has_audio(&master_playlist, &variant));
+        assert_eq!(
+            get_rendition_metadata_args(&renditions, true),
+            [
+                "-metadata:s:a:1",
+                "language=ger",
+                "-metadata:s:a:1",
+                "title=DE",
+                "-metadata:s:s:0",
+                "language=ger",
+                "-metadata:s:s:0",
+                "title=DE",
+            ]
+        );
+
+        let variant = m3u8_rs::VariantStream {
+            audio: Some("other".to_string()),
+            ..variant
+        };
+        assert!(!variant_has_audio(&master_playlist, &variant));
+    }
+
+    #[test]
+    fn test_to_iso639_2() {
+        assert_eq!(to_iso639_2("de"), "ger");
+        assert_eq!(to_iso639_2("en-US"), "eng");
+        assert_eq!(to_iso639_2("JA"), "jpn");
+        assert_eq!(to_iso639_2("deu"), "deu");
+        assert_eq!(to_iso639_2("Ger"), "ger");
+    }
+
+    #[test]
+    fn test_parse_webvtt_timestamp_offset() {
+        assert_eq!(
+            parse_webvtt_timestamp_offset(
+                b"WEBVTT\nX-TIMESTAMP-MAP=MPEGTS:900000,LOCAL:00:00:00.000\n\n00:01.000 --> 00:02.000\nHi\n"
+            ),
+            Some(10.0)
+        );
+        assert_eq!(
+            parse_webvtt_timestamp_offset(b"WEBVTT\r\nX-TIMESTAMP-MAP=LOCAL:00:00:02.500,MPEGTS:450000\r\n\r\n"),
+            Some(2.5)
+        );
+        assert_eq!(
+            parse_webvtt_timestamp_offset(b"WEBVTT\n\n00:01.000 --> 00:02.000\nHi\n"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_shift_webvtt_cues() {
+        assert_eq!(
+            shift_webvtt_cues(
+                b"\n00:01.000 --> 00:02.500 align:start\nHi\n\n59:59.000 --> 01:00:01.000\nBye\n",
+                1.5
+            ),
+            b"\n00:00:02.500 --> 00:00:04.000 align:start\nHi\n\n01:00:00.500 --> 01:00:02.500\nBye\n"
+        );
+        assert_eq!(
+            shift_webvtt_cues(b"00:00:01.000 --> 00:00:02.000\r\nHi\r\n", -2.0),
+            b"00:00:00.000 --> 00:00:00.000\r\nHi\r\n"
+        );
+    }
+
+    #[test]
+    fn test_strip_webvtt_header() {
+        assert_eq!(
+            strip_webvtt_header(
+                b"WEBVTT\nX-TIMESTAMP-MAP=LOCAL:00:00:00.000,MPEGTS:0\n\n00:01.000 --> 00:02.000\nHi\n"
+            ),
+            b"\n00:01.000 --> 00:02.000\nHi\n"
+        );
+        assert_eq!(
+            strip_webvtt_header(b"WEBVTT\r\n\r\n00:01.000 --> 00:02.000\r\nHi\r\n"),
+            b"\r\n00:01.000 --> 00:02.000\r\nHi\r\n"
+        );
+        assert_eq!(strip_webvtt_header(b"WEBVTT\n"), b"");
+        assert_eq!(
+            strip_webvtt_header(b"00:01.000 --> 00:02.000\n"),
+            b"00:01.000 --> 00:02.000\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_finished_block_length() {
+        let dir = std::env::temp_dir().join(format!("sdl-block-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let part_file = PartFile::new(dir.join("Episode.1.ts"), true);
+        let state = ResumeState {
+            url: "https://example.com/index.m3u8#1".to_string(),
+            total: Some(3),
+            bytes_written: 4,
+            segments_completed: Some(3),
+            range_bytes_written: None,
+        };
+        std::fs::write(&part_file.final_path, b"abcd").unwrap();
+
+        // A leftover file without state is not a finished block
+        assert_eq!(part_file.get_finished_block_length(&state.url, 3).await, None);
+
+        std::fs::write(&part_file.state_path, state.to_json().to_string()).unwrap();
+        assert_eq!(part_file.get_finished_block_length(&state.url, 3).await, Some(4));
+        assert_eq!(part_file.get_finished_block_length(&state.url, 4).await, None);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_unfinished_files() {
+        let dir = std::env::temp_dir().join(format!("sdl-unfinished-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let part_path = dir.join("Episode.mp4.part");
+        let output_path = dir.join("Episode.mp4");
+        let finished_path = dir.join("Other Episode.mp4.part");
+
+        for path in [&part_path, &output_path, &finished_path] {
+            std::fs::write(path, b"").unwrap();
+        }
+
+        let unfinished_files = UnfinishedFiles::default();
+        unfinished_files.add_resumable([&part_path, &finished_path]);
+        unfinished_files.remove_resumable([&finished_path]);
+        unfinished_files.add_incomplete(&output_path);
+
+        unfinished_files.clean_up(false).await;
+        assert!(part_path.exists());
+        assert!(!output_path.exists());
+
+        unfinished_files.clean_up(true).await;
+        assert!(!part_path.exists());
+        assert!(finished_path.exists());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
 }
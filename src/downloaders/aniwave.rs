@@ -9,14 +9,16 @@ use tokio::sync::mpsc::UnboundedSender;
 
 use super::{
     AllOrSpecific, DownloadRequest, DownloadSettings, DownloadTask, EpisodeInfo, EpisodeNumber, EpisodesRequest,
-    InstantiatedDownloader, Language, SeriesInfo, VideoType,
+    FallbackSource, InstantiatedDownloader, Language, SeriesInfo, VideoType,
 };
+use crate::archive::ArchiveEntry;
 use crate::downloaders::utils::sleep_random;
 use crate::downloaders::Downloader;
 use crate::extractors::{
     exists_extractor_with_name, extract_video_url_with_extractor_from_source,
     extract_video_url_with_extractor_from_url_unchecked, extractor_supports_source,
 };
+use crate::report::get_episode_label;
 /*
 Aniwave.to is now Aniwave.se! TODO: Fix Aniwave code !
 */
@@ -108,6 +110,8 @@ impl InstantiatedDownloader for Aniwave<'_> {
             description,
             status: None, // too lazy but possible
             year: None,   // too lazy but possible
+            poster_url: None,
+            backdrop_url: None,
         })
     }
 
@@ -153,6 +157,10 @@ impl TryFrom<&str> for ParsedUrl {
 }
 
 impl ParsedUrl {
+    fn get_archive_entry(&self, episode_number: EpisodeNumber, video_type: VideoType) -> ArchiveEntry {
+        ArchiveEntry::new("aniwave", &self.anime_id, None, episode_number, video_type)
+    }
+
     fn get_anime_url(&self) -> String {
         format!("https://aniwave.se/anime-watch/{}", self.anime_id)
     }
@@ -240,6 +248,11 @@ impl<'driver, 'url, F: FnMut() -> Duration> Scraper<'driver, 'url, F> {
         let mut got_error = false;
 
         for episode in episodes_to_download {
+            let episode_label = get_episode_label(
+                None,
+                &EpisodeNumber::String(episode.text().await.unwrap_or_default().trim().to_owned()),
+                None,
+            );
             let is_active = episode
                 .class_name()
                 .await
@@ -259,6 +272,7 @@ impl<'driver, 'url, F: FnMut() -> Duration> Scraper<'driver, 'url, F> {
 
                 if let Err(err) = episode.click().await {
                     log::warn!("Failed to click on next episode: {}", err);
+                    self.settings.report_failed(episode_label, &anyhow::Error::from(err));
                     got_error = true;
                     continue;
                 }
@@ -268,6 +282,7 @@ impl<'driver, 'url, F: FnMut() -> Duration> Scraper<'driver, 'url, F> {
 
             if let Err(err) = self.send_stream_to_downloader().await {
                 log::warn!("Failed to download episode: {}", err);
+                self.settings.report_failed(episode_label, &err);
                 got_error = true;
             }
 
@@ -430,19 +445,44 @@ impl<'driver, 'url, F: FnMut() -> Duration> Scraper<'driver, 'url, F> {
             .get_server_elements()
             .await
             .with_context(|| "failed to find episode in requested language")?;
+        let archive_entry = self
+            .parsed_url
+            .get_archive_entry(episode_info.episode_number.clone(), video_type);
+
+        if self.settings.is_archived(&archive_entry) {
+            log::info!("Skipping \"{}\", which is already in the download archive", archive_entry);
+            self.settings.report_skipped(
+                get_episode_label(None, &episode_info.episode_number, Some(&video_type)),
+                "already in the download archive",
+            );
+            return Ok(());
+        }
 
-        'server_loop: for server_element in server_elements {
+        let mut servers = Vec::new();
+
+        for server_element in server_elements {
             let Ok(stream_platform_name) = server_element.text().await else {
                 log::trace!("Failed to find name of stream platform");
-                continue 'server_loop;
+                continue;
             };
 
-            let stream_platform_name = stream_platform_name.trim();
+            let stream_platform_name = stream_platform_name.trim().to_owned();
 
-            if !exists_extractor_with_name(stream_platform_name) {
-                continue 'server_loop;
+            if !exists_extractor_with_name(&stream_platform_name) {
+                continue;
             }
 
+            servers.push((stream_platform_name, server_element));
+        }
+
+        let servers = self
+            .settings
+            .hoster_preference
+            .sort(servers, |(stream_platform_name, _)| stream_platform_name);
+
+        'server_loop: for (server_index, (stream_platform_name, server_element)) in servers.iter().enumerate() {
+            let stream_platform_name = stream_platform_name.as_str();
+
             let is_active = server_element
                 .class_name()
                 .await
@@ -504,8 +544,16 @@ impl<'driver, 'url, F: FnMut() -> Duration> Scraper<'driver, 'url, F> {
 
                 match extracted_video {
                     Ok(extracted_video) => {
+                        let fallback_sources = self
+                            .get_fallback_sources(server_element, &servers[server_index + 1..])
+                            .await;
                         self.sender
-                            .send(DownloadTask::new(episode_info, video_type, extracted_video))
+                            .send(
+                                DownloadTask::new(episode_info, video_type, extracted_video)
+                                    .archive_entry(archive_entry.clone())
+                                    .hoster(stream_platform_name)
+                                    .fallback_sources(fallback_sources),
+                            )
                             .unwrap();
                         return Ok(());
                     }
@@ -521,6 +569,58 @@ impl<'driver, 'url, F: FnMut() -> Duration> Scraper<'driver, 'url, F> {
         anyhow::bail!("failed to get video url for episode")
     }
 
+    /// The video frames of the other servers, whose video URLs are only extracted if the download fails.
+    /// Every server has to be clicked to find its frame, so only the preferred hosters are used.
+    async fn get_fallback_sources(
+        &self,
+        active_server_element: &WebElement,
+        servers: &[(String, WebElement)],
+    ) -> Vec<FallbackSource> {
+        let servers = servers
+            .iter()
+            .filter(|(stream_platform_name, _)| self.settings.hoster_preference.is_preferred(stream_platform_name))
+            .collect::<Vec<_>>();
+
+        if servers.is_empty() {
+            return Vec::new();
+        }
+
+        let referer = self.driver.current_url().await.ok().map(|url| url.to_string());
+        let mut fallback_sources = Vec::new();
+
+        for (stream_platform_name, server_element) in servers {
+            if server_element.click().await.is_err() {
+                log::trace!("Failed to click server element of '{stream_platform_name}' stream server");
+                continue;
+            }
+
+            sleep_random(2000..=3000).await;
+
+            let Ok(video_frame) = self.driver.find(By::Css("div#player > iframe")).await else {
+                log::trace!("Failed to find video frame of '{stream_platform_name}' stream server");
+                continue;
+            };
+
+            let Ok(Some(iframe_url)) = video_frame.attr("src").await else {
+                log::trace!("Failed to find src attribute of iframe");
+                continue;
+            };
+
+            fallback_sources.push(FallbackSource {
+                hoster: stream_platform_name.clone(),
+                url: iframe_url,
+                referer: referer.clone(),
+            });
+        }
+
+        // The page stays on the server of the download
+        if active_server_element.click().await.is_err() {
+            log::trace!("Failed to click server element of the downloaded stream server");
+        }
+
+        fallback_sources
+    }
+
     fn get_server_selectors(video_type: &VideoType) -> Option<Vec<(VideoType, By)>> {
         let supported_video_types_and_selector = [
             (
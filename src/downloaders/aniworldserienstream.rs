@@ -9,12 +9,14 @@ use thirtyfour::{By, WebDriver, WebElement};
 use tokio::sync::mpsc::UnboundedSender;
 
 use super::{
-    AllOrSpecific, DownloadRequest, DownloadSettings, DownloadTask, EpisodeInfo, EpisodeNumber, InstantiatedDownloader,
-    Language, SeriesInfo, VideoType,
+    AllOrSpecific, DownloadRequest, DownloadSettings, DownloadTask, EpisodeInfo, EpisodeNumber, FallbackSource,
+    InstantiatedDownloader, Language, SeriesInfo, VideoType,
 };
+use crate::archive::ArchiveEntry;
 use crate::downloaders::utils::sleep_random;
 use crate::downloaders::{Downloader, EpisodesRequest};
-use crate::extractors::extract_video_url_with_extractor_from_url_unchecked;
+use crate::extractors::exists_extractor_with_name;
+use crate::report::get_episode_label;
 
 static URL_REGEX: Lazy<Regex> = Lazy::new(|| {
     Regex::new(r#"(?i)^https?://(?:www\.)?(?:(aniworld)\.to/anime|(s)\.to/serie|(serienstream)\.to/serie)/stream/([^/\s]+)(?:/(?:(?:staffel-([1-9][0-9]*)(?:/(?:episode-([1-9][0-9]*)/?)?)?)|(?:(filme)(?:/(?:film-([1-9][0-9]*)/?)?)?))?)?$"#)
@@ -71,11 +73,31 @@ impl InstantiatedDownloader for AniWorldSerienStream<'_> {
             }
         });
 
+        let year = self
+            .execute_optional_string(r#"return document.querySelector('span[itemprop="startDate"]')?.innerText;"#)
+            .await
+            .and_then(|year| year.parse().ok());
+        let poster_url = self
+            .execute_optional_string(
+                r#"const img = document.querySelector(".seriesCoverBox img");
+                return img ? new URL(img.dataset.src || img.getAttribute("src"), document.baseURI).href : null;"#,
+            )
+            .await;
+        let backdrop_url = self
+            .execute_optional_string(
+                r#"const backdrop = document.querySelector(".backdrop");
+                const match = backdrop && getComputedStyle(backdrop).backgroundImage.match(/url\("?(.*?)"?\)/);
+                return match ? match[1] : null;"#,
+            )
+            .await;
+
         Ok(SeriesInfo {
             title,
             description,
             status: None,
-            year: None,
+            year,
+            poster_url,
+            backdrop_url,
         })
     }
 
@@ -90,6 +112,20 @@ impl InstantiatedDownloader for AniWorldSerienStream<'_> {
     }
 }
 
+impl AniWorldSerienStream<'_> {
+    /// Runs a script on the current page, missing elements are returned as `None`
+    async fn execute_optional_string(&self, script: &str) -> Option<String> {
+        let script_ret = self.driver.execute(script, vec![]).await.ok()?;
+        let trimmed = script_ret.json().as_str()?.trim();
+
+        if trimmed.is_empty() {
+            None
+        } else {
+            Some(trimmed.to_owned())
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 struct ParsedUrl {
     site: Site,
@@ -161,6 +197,16 @@ impl TryFrom<&str> for ParsedUrl {
 }
 
 impl ParsedUrl {
+    fn get_archive_entry(&self, season: u32, episode: u32, video_type: VideoType) -> ArchiveEntry {
+        ArchiveEntry::new(
+            self.site.get_archive_name(),
+            &self.name,
+            Some(season),
+            EpisodeNumber::Number(episode),
+            video_type,
+        )
+    }
+
     fn get_series_url(&self) -> String {
         format!("{}/{}", self.site.get_base_url(), self.name)
     }
@@ -189,6 +235,13 @@ enum Site {
 }
 
 impl Site {
+    fn get_archive_name(&self) -> &'static str {
+        match self {
+            Site::AniWorld => "aniworld",
+            Site::SerienStream => "serienstream",
+        }
+    }
+
     fn get_base_url(&self) -> &'static str {
         match self {
             Site::AniWorld => "https://aniworld.to/anime/stream",
@@ -334,6 +387,8 @@ impl<'driver, 'url, F: FnMut() -> Duration> Scraper<'driver, 'url, F> {
         if episodes.contains(episode) {
             if let Err(err) = self.scrape_episode(season, episode, goto).await {
                 log::warn!("Failed to get video url for S{season:02}E{episode:03}: {err:#}");
+                self.settings
+                    .report_failed(get_episode_label(Some(season), &EpisodeNumber::Number(episode), None), &err);
                 got_error = true;
             }
         }
@@ -348,6 +403,21 @@ impl<'driver, 'url, F: FnMut() -> Duration> Scraper<'driver, 'url, F> {
 }
 
     async fn scrape_episode(&mut self, season: u32, episode: u32, goto: bool) -> Result<(), anyhow::Error> {
+        // Only one of the requested languages is downloaded, the others are fallbacks
+        let is_archived = self.language_selectors.iter().any(|(video_type, _)| {
+            self.settings
+                .is_archived(&self.parsed_url.get_archive_entry(season, episode, *video_type))
+        });
+
+        if is_archived {
+            log::info!("Skipping S{season:02}E{episode:03}, which is already in the download archive");
+            self.settings.report_skipped(
+                get_episode_label(Some(season), &EpisodeNumber::Number(episode), None),
+                "already in the download archive",
+            );
+            return Ok(());
+        }
+
         if goto {
             self.driver
                 .goto(self.parsed_url.get_episode_url(season, episode))
@@ -524,6 +594,7 @@ impl<'driver, 'url, F: FnMut() -> Duration> Scraper<'driver, 'url, F> {
         }
 
         let current_url = self.driver.current_url().await.unwrap();
+        let mut sources = Vec::new();
 
         for stream in available_streams {
             let Some(link_target) = stream.attr("data-link-target").await.unwrap() else {
@@ -550,32 +621,43 @@ impl<'driver, 'url, F: FnMut() -> Duration> Scraper<'driver, 'url, F> {
                 .trim()
                 .to_owned();
 
-            log::trace!("Trying to use '{stream_platform_name}' stream server...");
+            if !exists_extractor_with_name(&stream_platform_name) {
+                log::trace!("Failed to find extractor for stream platform: {}", stream_platform_name);
+                continue;
+            }
 
-            let extracted_video = extract_video_url_with_extractor_from_url_unchecked(
-                redirect_link.as_str(),
-                &stream_platform_name,
-                None,
-                Some(current_url.as_str().to_owned()),
-            )
-            .await;
+            sources.push(FallbackSource {
+                hoster: stream_platform_name,
+                url: redirect_link.to_string(),
+                referer: Some(current_url.to_string()),
+            });
+        }
 
-            match extracted_video {
-                Some(Ok(extracted_video)) => {
+        let sources = self.settings.hoster_preference.sort(sources, |source| &source.hoster);
+
+        for (i, source) in sources.iter().enumerate() {
+            log::trace!("Trying to use '{}' stream server...", source.hoster);
+
+            match source.extract().await {
+                Ok(extracted_video) => {
+                    let archive_entry = self
+                        .parsed_url
+                        .get_archive_entry(current_season, current_episode, video_type);
                     self.sender
-                        .send(DownloadTask::new(episode_info, video_type, extracted_video))
+                        .send(
+                            DownloadTask::new(episode_info, video_type, extracted_video)
+                                .archive_entry(archive_entry)
+                                .hoster(&source.hoster)
+                                .fallback_sources(sources[i + 1..].to_vec()),
+                        )
                         .unwrap();
                     self.settings.maybe_ddos_wait().await;
                     return Ok(());
                 }
-                Some(Err(err)) => {
+                Err(err) => {
                     log::trace!("Failed to extract video url from stream: {:#}", err);
                     self.settings.maybe_ddos_wait().await;
                 }
-                None => {
-                    log::trace!("Failed to find extractor for stream platform: {}", stream_platform_name);
-                    continue;
-                }
             }
         }
 
@@ -1,8 +1,10 @@
 use std::fmt::Display;
 use std::num::NonZeroU32;
 use std::ops::{Deref, RangeInclusive};
+use std::sync::Arc;
 use std::time::Duration;
 
+use anyhow::Context;
 use clap::ValueEnum;
 use enum_dispatch::enum_dispatch;
 use enum_iterator::Sequence;
@@ -10,7 +12,9 @@ use tokio::sync::mpsc::UnboundedSender;
 
 use self::aniwave::Aniwave;
 use self::aniworldserienstream::AniWorldSerienStream;
-use crate::extractors::ExtractedVideo;
+use crate::archive::{ArchiveEntry, DownloadArchive};
+use crate::extractors::{extract_video_url_with_extractor_from_url_unchecked, ExtractedVideo, HosterPreference};
+use crate::report::DownloadReport;
 
 pub mod aniwave;
 pub mod aniworldserienstream;
@@ -90,6 +94,8 @@ pub struct SeriesInfo {
     pub description: Option<String>,
     pub status: Option<SeriesStatus>,
     pub year: Option<u32>,
+    pub poster_url: Option<String>,
+    pub backdrop_url: Option<String>,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -266,6 +272,9 @@ impl AllOrSpecific {
 pub struct DownloadSettings<F: FnMut() -> Duration> {
     pub ddos_wait_episodes: Option<NonZeroU32>,
     pub ddos_wait_time: F,
+    pub download_archive: Option<Arc<DownloadArchive>>,
+    pub hoster_preference: HosterPreference,
+    pub download_report: Option<Arc<DownloadReport>>,
     counter: u32,
 }
 
@@ -274,10 +283,48 @@ impl<F: FnMut() -> Duration> DownloadSettings<F> {
         Self {
             ddos_wait_episodes,
             ddos_wait_time,
+            download_archive: None,
+            hoster_preference: HosterPreference::default(),
+            download_report: None,
             counter: 0,
         }
     }
 
+    pub fn download_archive(mut self, download_archive: Option<Arc<DownloadArchive>>) -> Self {
+        self.download_archive = download_archive;
+        self
+    }
+
+    pub fn hoster_preference(mut self, hoster_preference: HosterPreference) -> Self {
+        self.hoster_preference = hoster_preference;
+        self
+    }
+
+    /// Episodes that are skipped or fail while scraping are added to the report
+    pub fn download_report(mut self, download_report: Option<Arc<DownloadReport>>) -> Self {
+        self.download_report = download_report;
+        self
+    }
+
+    fn report_skipped(&self, episode: String, reason: &str) {
+        if let Some(download_report) = &self.download_report {
+            download_report.skipped(episode, reason);
+        }
+    }
+
+    fn report_failed(&self, episode: String, err: &anyhow::Error) {
+        if let Some(download_report) = &self.download_report {
+            download_report.failed(episode, err);
+        }
+    }
+
+    /// Whether the episode was already downloaded and can be skipped before extracting it
+    fn is_archived(&self, archive_entry: &ArchiveEntry) -> bool {
+        self.download_archive
+            .as_ref()
+            .is_some_and(|download_archive| download_archive.contains(archive_entry))
+    }
+
     async fn maybe_ddos_wait(&mut self) {
         if let Some(counter_match) = &self.ddos_wait_episodes {
             self.counter += 1;
@@ -296,6 +343,9 @@ pub struct DownloadTask {
     pub language: VideoType,
     pub download_url: String,
     pub referer: Option<String>,
+    pub archive_entry: Option<ArchiveEntry>,
+    pub hoster: Option<String>,
+    pub fallback_sources: Vec<FallbackSource>,
 }
 
 impl DownloadTask {
@@ -305,8 +355,45 @@ impl DownloadTask {
             language,
             download_url: extracted_video.url,
             referer: extracted_video.referer,
+            archive_entry: None,
+            hoster: None,
+            fallback_sources: Vec::new(),
         }
     }
+
+    pub fn archive_entry(mut self, archive_entry: ArchiveEntry) -> Self {
+        self.archive_entry = Some(archive_entry);
+        self
+    }
+
+    /// Name of the stream platform the video was extracted from
+    pub fn hoster(mut self, hoster: &str) -> Self {
+        self.hoster = Some(hoster.to_owned());
+        self
+    }
+
+    /// Other hosters of the episode, tried in order if the download fails
+    pub fn fallback_sources(mut self, fallback_sources: Vec<FallbackSource>) -> Self {
+        self.fallback_sources = fallback_sources;
+        self
+    }
+}
+
+/// A stream of the episode on another hoster. The video URL is only extracted
+/// when it is needed, because it may expire before the download starts.
+#[derive(Debug, Clone)]
+pub struct FallbackSource {
+    pub hoster: String,
+    pub url: String,
+    pub referer: Option<String>,
+}
+
+impl FallbackSource {
+    pub async fn extract(&self) -> Result<ExtractedVideo, anyhow::Error> {
+        extract_video_url_with_extractor_from_url_unchecked(&self.url, &self.hoster, None, self.referer.clone())
+            .await
+            .with_context(|| format!("failed to find extractor for stream platform: {}", self.hoster))?
+    }
 }
 
 #[derive(Debug, Clone)]
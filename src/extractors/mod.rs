@@ -281,6 +281,50 @@ pub trait Extractor {
     async fn extract_video_url(from: ExtractFrom) -> Result<ExtractedVideo, anyhow::Error>;
 }
 
+/// Order in which the hosters of an episode are tried. Hosters are compared by
+/// the display name of their extractor, so every alias of a hoster matches.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HosterPreference {
+    preferred: Vec<&'static str>,
+    excluded: Vec<&'static str>,
+}
+
+impl HosterPreference {
+    /// Hosters that are tried first, in this order
+    pub fn preferred(mut self, preferred: Vec<&'static str>) -> Self {
+        self.preferred = preferred;
+        self
+    }
+
+    /// Hosters that are never used
+    pub fn excluded(mut self, excluded: Vec<&'static str>) -> Self {
+        self.excluded = excluded;
+        self
+    }
+
+    /// Removes excluded hosters and moves the preferred ones to the front.
+    /// All other hosters keep their order.
+    pub fn sort<T>(&self, mut items: Vec<T>, hoster: impl Fn(&T) -> &str) -> Vec<T> {
+        items.retain(|item| !self.is_excluded(hoster(item)));
+        items.sort_by_key(|item| {
+            let name = normalized_name(hoster(item));
+            self.preferred
+                .iter()
+                .position(|preferred| Some(*preferred) == name)
+                .unwrap_or(self.preferred.len())
+        });
+        items
+    }
+
+    pub fn is_preferred(&self, hoster: &str) -> bool {
+        normalized_name(hoster).is_some_and(|name| self.preferred.contains(&name))
+    }
+
+    pub fn is_excluded(&self, hoster: &str) -> bool {
+        normalized_name(hoster).is_some_and(|name| self.excluded.contains(&name))
+    }
+}
+
 pub mod utils {
     use std::collections::{HashMap, VecDeque};
     use std::num::NonZeroUsize;
@@ -464,3 +508,24 @@ pub mod utils {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::extractors::HosterPreference;
+
+    #[test]
+    fn test_hoster_preference() {
+        let hosters = vec!["VOE", "Doodstream", "Vidoza", "Unknown", "MoonF"];
+        let hoster_preference = HosterPreference::default()
+            .preferred(vec!["Filemoon", "Vidoza"])
+            .excluded(vec!["Doodstream"]);
+
+        assert_eq!(
+            hoster_preference.sort(hosters.clone(), |hoster| hoster),
+            vec!["MoonF", "Vidoza", "VOE", "Unknown"]
+        );
+        assert!(hoster_preference.is_preferred("MoonF"));
+        assert!(!hoster_preference.is_preferred("VOE"));
+        assert_eq!(HosterPreference::default().sort(hosters.clone(), |hoster| hoster), hosters);
+    }
+}
@@ -0,0 +1,139 @@
+use std::path::Path;
+use std::process::Stdio;
+
+use anyhow::Context;
+
+use crate::downloaders::{EpisodeInfo, EpisodeNumber, SeriesInfo, VideoType};
+
+/// A shell command that is run after every successful download. The downloaded
+/// file and the episode are passed in `SDL_*` environment variables.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ExecHook {
+    command: String,
+}
+
+impl ExecHook {
+    pub(crate) fn new(command: String) -> Self {
+        Self { command }
+    }
+
+    pub(crate) async fn run(
+        &self,
+        path: &Path,
+        series_info: &SeriesInfo,
+        episode_info: &EpisodeInfo,
+        language: VideoType,
+    ) -> Result<(), anyhow::Error> {
+        let mut shell_cmd = if cfg!(windows) {
+            let mut shell_cmd = tokio::process::Command::new("cmd");
+            shell_cmd.arg("/C");
+            shell_cmd
+        } else {
+            let mut shell_cmd = tokio::process::Command::new("sh");
+            shell_cmd.arg("-c");
+            shell_cmd
+        };
+
+        // Output of the command would break the progress bars
+        let output = shell_cmd
+            .arg(&self.command)
+            .envs(get_hook_env(path, series_info, episode_info, language))
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .output()
+            .await
+            .with_context(|| "failed to start exec command")?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            let exit_code = output
+                .status
+                .code()
+                .map(|code| code.to_string())
+                .unwrap_or_else(|| "unknown".to_owned());
+
+            if stderr.trim().is_empty() {
+                anyhow::bail!("exec command failed with exit code {}", exit_code);
+            } else {
+                anyhow::bail!(
+                    "exec command failed with exit code {}:\n{}",
+                    exit_code,
+                    stderr.trim_end()
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn get_hook_env(
+    path: &Path,
+    series_info: &SeriesInfo,
+    episode_info: &EpisodeInfo,
+    language: VideoType,
+) -> Vec<(&'static str, String)> {
+    let episode = match &episode_info.episode_number {
+        EpisodeNumber::Number(number) => number.to_string(),
+        EpisodeNumber::String(string) => string.clone(),
+    };
+
+    vec![
+        ("SDL_PATH", path.display().to_string()),
+        ("SDL_SERIES", series_info.title.clone()),
+        (
+            "SDL_SEASON",
+            episode_info
+                .season_number
+                .map(|season| season.to_string())
+                .unwrap_or_default(),
+        ),
+        ("SDL_EPISODE", episode),
+        ("SDL_TITLE", episode_info.name.clone().unwrap_or_default()),
+        ("SDL_LANGUAGE", language.to_string()),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::downloaders::{EpisodeInfo, EpisodeNumber, Language, SeriesInfo, VideoType};
+    use crate::hook::get_hook_env;
+
+    #[test]
+    fn test_get_hook_env() {
+        let series_info = SeriesInfo {
+            title: "Higurashi".to_owned(),
+            description: None,
+            status: None,
+            year: None,
+            poster_url: None,
+            backdrop_url: None,
+        };
+        let episode_info = EpisodeInfo {
+            name: None,
+            season_number: None,
+            episode_number: EpisodeNumber::String("1.5".to_owned()),
+            max_episode_number_in_season: None,
+        };
+
+        assert_eq!(
+            get_hook_env(
+                Path::new("Higurashi - 1.5.mp4"),
+                &series_info,
+                &episode_info,
+                VideoType::Sub(Language::German)
+            ),
+            vec![
+                ("SDL_PATH", "Higurashi - 1.5.mp4".to_owned()),
+                ("SDL_SERIES", "Higurashi".to_owned()),
+                ("SDL_SEASON", String::new()),
+                ("SDL_EPISODE", "1.5".to_owned()),
+                ("SDL_TITLE", String::new()),
+                ("SDL_LANGUAGE", "GerSub".to_owned()),
+            ]
+        );
+    }
+}
@@ -1,7 +1,13 @@
 #![allow(warnings, unused)]
 
+pub(crate) mod archive;
+pub(crate) mod dash;
 pub(crate) mod download;
 pub mod downloaders;
 pub mod extractors;
+pub(crate) mod hook;
 pub(crate) mod logger;
+pub(crate) mod nfo;
+pub(crate) mod report;
+pub(crate) mod template;
 pub(crate) mod utils;
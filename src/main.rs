@@ -2,28 +2,42 @@
 use std::borrow::Cow;
 use std::ops::Deref;
 use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::Arc;
 
+use archive::DownloadArchive;
 use chrono::Local;
 use clap::Parser;
 use cli::{Args, Extractor};
-use download::{DownloadManager, Downloader, InternalDownloadTask};
+use download::{DownloadManager, Downloader, InternalDownloadTask, UnfinishedFiles};
 use downloaders::{DownloadRequest, DownloadTask, InstantiatedDownloader};
 use extractors::{extract_video_url, extract_video_url_with_extractor_from_url};
 use ffmpeg::Ffmpeg;
+use hook::ExecHook;
 use logger::log_wrapper::{LogWrapper, SetLogWrapper};
+use report::DownloadReport;
 use tokio_stream::wrappers::UnboundedReceiverStream;
 
+pub(crate) mod archive;
 pub(crate) mod chrome;
 pub(crate) mod cli;
+pub(crate) mod dash;
 pub(crate) mod dirs;
 pub(crate) mod download;
 pub(crate) mod downloaders;
 pub(crate) mod extractors;
 pub(crate) mod ffmpeg;
+pub(crate) mod hook;
 pub(crate) mod logger;
 pub(crate) mod mpv;
+pub(crate) mod nfo;
+pub(crate) mod report;
+pub(crate) mod template;
 pub(crate) mod utils;
 
+/// Exit code of shells for processes that were terminated by SIGINT
+const EXIT_CODE_INTERRUPTED: i32 = 130;
+
 #[tokio::main(flavor = "current_thread")]
 async fn main() {
     // Parse arguments
@@ -46,7 +60,7 @@ async fn main() {
     };
 
     // Get save directory
-    let save_directory = match dirs::get_save_directory(None) {
+    let save_directory = match dirs::get_save_directory(args.output_dir.clone()) {
         Ok(dir) => dir,
         Err(err) => {
             log::error!("Failed to get save directory: {:#}", err);
@@ -86,6 +100,19 @@ async fn main() {
         std::process::exit(1);
     }
 
+    // The series sidecars need a directory that only holds the series
+    if args.write_nfo
+        && !args.series_folders
+        && !args.output.as_ref().is_some_and(|output| output.has_series_directory())
+    {
+        log::error!("--write-nfo needs --series-folders or an output template that starts with a {{series}} directory");
+        std::process::exit(1);
+    }
+
+    // Listen for Ctrl-C before the setup, so that an interrupted setup does not leave ChromeDriver running
+    let ctrl_c = wait_for_ctrl_c();
+    tokio::pin!(ctrl_c);
+
     // Set up FFmpeg, and ChromeDriver if needed
     let asset_downloader = Downloader::new(&mut log_wrapper, debug, None, None, None);
     let ffmpeg = Ffmpeg::new(data_dir.clone());
@@ -98,9 +125,11 @@ async fn main() {
         let (chrome, ffmpeg_install_result) = tokio::select! {
             biased;
 
-            result = chrome_ffmpeg_future => result,
+            result = chrome_ffmpeg_future => Some(result),
             _ = asset_downloader.tick() => unreachable!(),
-        };
+            _ = &mut ctrl_c => None,
+        }
+        .unwrap_or_else(|| exit_interrupted_during_setup());
 
         let chrome = match chrome {
             Ok(chrome) => chrome,
@@ -115,23 +144,36 @@ async fn main() {
         let ffmpeg_install_result = tokio::select! {
             biased;
 
-            result = ffmpeg.auto_download(&asset_downloader) => result,
+            result = ffmpeg.auto_download(&asset_downloader) => Some(result),
             _ = asset_downloader.tick() => unreachable!(),
-        };
+            _ = &mut ctrl_c => None,
+        }
+        .unwrap_or_else(|| exit_interrupted_during_setup());
 
         (None, ffmpeg_install_result)
     };
 
-    // Do much of the bulk work
-    let should_error_quit = do_after_chrome_driver(
+    // Do much of the bulk work, Ctrl-C drops it, which stops scraping and all downloads
+    let delete_partial = args.delete_partial;
+    let unfinished_files = Rc::new(UnfinishedFiles::default());
+    let bulk_work_future = do_after_chrome_driver(
         ffmpeg_install_result,
         asset_downloader,
         chrome.as_mut().map(|(chrome, _)| chrome),
         log_wrapper,
         save_directory,
+        unfinished_files.clone(),
         args,
-    )
-    .await;
+    );
+
+    let exit_code = tokio::select! {
+        exit_code = bulk_work_future => exit_code,
+        _ = &mut ctrl_c => {
+            log::warn!("Interrupted, cleaning up");
+            unfinished_files.clean_up(delete_partial).await;
+            EXIT_CODE_INTERRUPTED
+        }
+    };
 
     // Quit ChromeDriver
     if let Some((chrome, mut chrome_process)) = chrome {
@@ -144,8 +186,21 @@ async fn main() {
         }
     }
 
-    if should_error_quit {
-        std::process::exit(1);
+    if exit_code != 0 {
+        std::process::exit(exit_code);
+    }
+}
+
+/// Called after the setup futures were dropped, which kills a ChromeDriver that was already started
+fn exit_interrupted_during_setup() -> ! {
+    log::warn!("Interrupted during setup");
+    std::process::exit(EXIT_CODE_INTERRUPTED);
+}
+
+async fn wait_for_ctrl_c() {
+    if let Err(err) = tokio::signal::ctrl_c().await {
+        log::warn!("Failed to listen for Ctrl-C: {}", err);
+        std::future::pending::<()>().await;
     }
 }
 
@@ -155,18 +210,32 @@ async fn do_after_chrome_driver(
     chrome: Option<&mut thirtyfour::WebDriver>,
     mut log_wrapper: SetLogWrapper,
     save_directory: PathBuf,
+    unfinished_files: Rc<UnfinishedFiles>,
     args: Args,
-) -> bool {
+) -> i32 {
     let debug = args.debug;
     let extractor = args.extractor.as_ref();
     let url = args.url.deref();
     let max_concurrent = args.concurrent_downloads.inner().copied();
+    let concurrent_segments = args.concurrent_segments;
+    let connections = args.connections;
+    let quality = args.quality;
+    let container = args.container;
+    let pipe_to_ffmpeg = args.pipe_to_ffmpeg;
+    let verify = !args.no_verify;
+    let min_duration = args.min_duration;
+    let output_template = args.output.clone();
+    let series_folders = args.series_folders;
+    let embed_metadata = args.embed_metadata;
+    let write_nfo = args.write_nfo;
+    let exec_hook = args.exec.clone().map(ExecHook::new);
+    let report_json = args.report_json.clone();
 
     let ffmpeg_path = match ffmpeg_install_result {
         Ok(path) => path,
         Err(err) => {
             log::error!("Failed to get path to FFmpeg: {:#}", err);
-            return true;
+            return 1;
         }
     };
 
@@ -178,13 +247,19 @@ async fn do_after_chrome_driver(
         None => None,
     };
     let episodes_downloader = if !args.mpv {
-        Some(Downloader::new(
-            &mut log_wrapper,
-            debug,
-            Some(ffmpeg_path),
-            user_agent,
-            Some(args.retries.inner().copied()),
-        ))
+        Some(
+            Downloader::new(
+                &mut log_wrapper,
+                debug,
+                Some(ffmpeg_path),
+                user_agent,
+                Some(args.retries.inner().copied()),
+            )
+            .limit_rate(args.limit_rate)
+            .limit_rate_per_download(args.limit_rate_per_download)
+            .progress_mode(args.progress)
+            .unfinished_files(unfinished_files),
+        )
     } else {
         None
     };
@@ -200,7 +275,7 @@ async fn do_after_chrome_driver(
             Some(Ok(video_url)) => video_url,
             Some(Err(err)) => {
                 log::error!("Failed to extract video url: {:#}", err);
-                return true;
+                return 1;
             }
             None => unreachable!(),
         };
@@ -215,12 +290,12 @@ async fn do_after_chrome_driver(
                 Cow::Owned(format!("{}-{}", timestamp, i))
             };
 
-            let mp4_name = format!("{}.mp4", name);
-            let mp4_exists = match save_directory.join(&mp4_name).try_exists() {
+            let video_name = format!("{}.{}", name, container.unwrap_or_default().extension());
+            let video_exists = match save_directory.join(&video_name).try_exists() {
                 Ok(exists) => exists,
                 Err(err) => {
-                    log::error!("Failed to check if the file \"{}\" exists: {}", mp4_name, err);
-                    return true;
+                    log::error!("Failed to check if the file \"{}\" exists: {}", video_name, err);
+                    return 1;
                 }
             };
 
@@ -229,11 +304,11 @@ async fn do_after_chrome_driver(
                 Ok(exists) => exists,
                 Err(err) => {
                     log::error!("Failed to check if the file \"{}\" exists: {}", ts_name, err);
-                    return true;
+                    return 1;
                 }
             };
 
-            if !mp4_exists && !ts_exists {
+            if !video_exists && !ts_exists {
                 break save_directory.join(name.deref());
             }
 
@@ -241,7 +316,7 @@ async fn do_after_chrome_driver(
                 Some(next_i) => i = next_i,
                 None => {
                     log::error!("Failed to find a name for the file");
-                    return true;
+                    return 1;
                 }
             }
         };
@@ -250,17 +325,24 @@ async fn do_after_chrome_driver(
             let download_future = episodes_downloader.download_to_file(
                 InternalDownloadTask::new(output_path, extracted_video.url)
                     .output_path_has_extension(false)
-                    .referer(extracted_video.referer),
+                    .referer(extracted_video.referer)
+                    .concurrent_segments(concurrent_segments)
+                    .connections(connections)
+                    .quality(quality)
+                    .container(container)
+                    .pipe_to_ffmpeg(pipe_to_ffmpeg)
+                    .verify(verify)
+                    .min_duration(min_duration),
             );
 
             tokio::select! {
                 biased;
 
-                result = download_future => result,
+                result = download_future => result.map(|_| ()),
                 _ = episodes_downloader.tick() => unreachable!(),
             }
         } else {
-            mpv::start_mpv(&extracted_video.url, debug)
+            mpv::start_mpv(&extracted_video.url, quality, debug)
         };
 
         if let Err(err) = result {
@@ -270,18 +352,32 @@ async fn do_after_chrome_driver(
                 log::error!("Failed mpv: {:#}", err);
             }
 
-            return true;
+            return 1;
         }
     } else {
         let series_downloader = downloaders::find_downloader_for_url(chrome.unwrap(), debug, url)
             .await
             .unwrap();
-        let download_settings = args.get_download_settings();
+        let download_archive = match &args.download_archive {
+            Some(download_archive_path) => match DownloadArchive::load(download_archive_path.clone()).await {
+                Ok(download_archive) => Some(Arc::new(download_archive)),
+                Err(err) => {
+                    log::error!("Failed to load download archive: {:#}", err);
+                    return 1;
+                }
+            },
+            None => None,
+        };
+        let download_report = Arc::new(DownloadReport::default());
+        let download_settings = args
+            .get_download_settings()
+            .download_archive(download_archive.clone())
+            .download_report(Some(download_report.clone()));
         let series_info = match series_downloader.get_series_info().await {
             Ok(info) => info,
             Err(err) => {
                 log::error!("Failed to get series info: {:#}", err);
-                return true;
+                return 1;
             }
         };
 
@@ -293,21 +389,51 @@ async fn do_after_chrome_driver(
         if let Some(episodes_downloader) = episodes_downloader {
             let (download_manager, sender) =
                 DownloadManager::new(episodes_downloader, max_concurrent, save_directory, series_info);
+            let download_manager = download_manager
+                .concurrent_segments(concurrent_segments)
+                .connections(connections)
+                .quality(quality)
+                .container(container)
+                .pipe_to_ffmpeg(pipe_to_ffmpeg)
+                .verify(verify)
+                .min_duration(min_duration)
+                .download_archive(download_archive)
+                .output_template(output_template)
+                .series_folders(series_folders)
+                .embed_metadata(embed_metadata)
+                .write_nfo(write_nfo)
+                .exec_hook(exec_hook)
+                .download_report(Some(download_report.clone()));
 
             let (downloader_result, _) = tokio::join!(
                 series_downloader.download(download_request, download_settings, sender),
                 download_manager.progress_downloads(),
             );
 
-            if let Err(err) = downloader_result {
+            if let Err(err) = &downloader_result {
                 log::error!("Failed to download series: {:#}", err);
-                return true;
             }
+
+            if !download_report.is_empty() {
+                log::info!("Summary: {}", download_report.summary());
+            }
+
+            if let Some(report_path) = &report_json {
+                if let Err(err) = download_report.write_json(report_path).await {
+                    log::error!("Failed to write report: {:#}", err);
+                }
+            }
+
+            // Errors outside of single episodes, e.g. of a whole season, are not in the report
+            return match download_report.exit_code() {
+                0 if downloader_result.is_err() => 1,
+                exit_code => exit_code,
+            };
         } else {
             let (tx, rx) = tokio::sync::mpsc::unbounded_channel::<DownloadTask>();
             let rx_stream = UnboundedReceiverStream::new(rx);
 
-            let mpv_future = mpv::start_mpv_with_ipc(rx_stream, series_info, debug);
+            let mpv_future = mpv::start_mpv_with_ipc(rx_stream, series_info, quality, debug);
             tokio::pin!(mpv_future);
 
             let (downloader_errored, mpv_result) = tokio::select! {
@@ -325,9 +451,9 @@ async fn do_after_chrome_driver(
                 log::error!("Failed mpv: {:#}", err);
             }
 
-            return downloader_errored || mpv_result.is_err();
+            return i32::from(downloader_errored || mpv_result.is_err());
         }
     }
 
-    false
+    0
 }
@@ -9,10 +9,10 @@ use tokio::io::AsyncWriteExt;
 use tokio_stream::wrappers::UnboundedReceiverStream;
 use tokio_util::compat::FuturesAsyncWriteCompatExt;
 
-use crate::download::get_episode_name;
+use crate::download::{get_episode_name, VariantQuality};
 use crate::downloaders::{DownloadTask, SeriesInfo};
 
-pub(crate) fn start_mpv(url: &str, debug: bool) -> Result<(), anyhow::Error> {
+pub(crate) fn start_mpv(url: &str, quality: VariantQuality, debug: bool) -> Result<(), anyhow::Error> {
     let mut mpv_cmd = tokio::process::Command::new(mpv_name());
 
     if !debug {
@@ -23,6 +23,7 @@ pub(crate) fn start_mpv(url: &str, debug: bool) -> Result<(), anyhow::Error> {
     let title = "sdl";
 
     mpv_cmd
+        .args(hls_bitrate_arg(quality))
         .arg("--{")
         .arg(format!("--force-media-title={title}"))
         .arg(url)
@@ -35,6 +36,7 @@ pub(crate) fn start_mpv(url: &str, debug: bool) -> Result<(), anyhow::Error> {
 pub(crate) async fn start_mpv_with_ipc(
     mut rx_stream: UnboundedReceiverStream<DownloadTask>,
     series_info: SeriesInfo,
+    quality: VariantQuality,
     debug: bool,
 ) -> Result<(), anyhow::Error> {
     let ipc_path_mpv = if cfg!(unix) {
@@ -78,6 +80,7 @@ pub(crate) async fn start_mpv_with_ipc(
 
     mpv_cmd
         .arg(format!("--input-ipc-server={ipc_path_mpv}"))
+        .args(hls_bitrate_arg(quality))
         .arg("--{")
         .arg(format!("--force-media-title={first_title}"))
         .arg(first_url)
@@ -165,3 +168,19 @@ fn mpv_name() -> &'static str {
         "mpv.exe"
     }
 }
+
+/// mpv can only select HLS variants by bitrate. Nothing is passed for the best
+/// quality, which mpv selects by default.
+fn hls_bitrate_arg(quality: VariantQuality) -> Option<String> {
+    let hls_bitrate = match quality {
+        VariantQuality::Best => return None,
+        VariantQuality::Worst => "min".to_string(),
+        VariantQuality::MaxBandwidth(max_bandwidth) => max_bandwidth.to_string(),
+        VariantQuality::MaxHeight(_) => {
+            log::warn!("mpv cannot select the quality by height, using the best quality instead");
+            return None;
+        }
+    };
+
+    Some(format!("--hls-bitrate={hls_bitrate}"))
+}
@@ -0,0 +1,141 @@
+use std::fmt::Write;
+use std::path::Path;
+
+use anyhow::Context;
+
+use crate::downloaders::{EpisodeInfo, EpisodeNumber, SeriesInfo, SeriesStatus};
+
+const XML_HEADER: &str = "<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"yes\"?>\n";
+
+/// Writes `tvshow.nfo` into the directory of the series, as read by Kodi and Jellyfin
+pub(crate) async fn write_tvshow_nfo(directory: &Path, series_info: &SeriesInfo) -> Result<(), anyhow::Error> {
+    tokio::fs::create_dir_all(directory)
+        .await
+        .with_context(|| "failed to create series directory")?;
+    tokio::fs::write(directory.join("tvshow.nfo"), get_tvshow_nfo(series_info))
+        .await
+        .with_context(|| "failed to write tvshow.nfo")
+}
+
+/// Writes the `.nfo` file of an episode, which has to have the name of the video file
+pub(crate) async fn write_episode_nfo(
+    path: &Path,
+    series_info: &SeriesInfo,
+    episode_info: &EpisodeInfo,
+) -> Result<(), anyhow::Error> {
+    tokio::fs::write(path, get_episode_nfo(series_info, episode_info))
+        .await
+        .with_context(|| "failed to write episode nfo")
+}
+
+fn get_tvshow_nfo(series_info: &SeriesInfo) -> String {
+    let mut nfo = format!("{}<tvshow>\n", XML_HEADER);
+    push_element(&mut nfo, "title", &series_info.title);
+
+    if let Some(description) = &series_info.description {
+        push_element(&mut nfo, "plot", description);
+    }
+
+    if let Some(year) = series_info.year {
+        push_element(&mut nfo, "year", &year.to_string());
+    }
+
+    if let Some(status) = series_info.status {
+        let status = match status {
+            SeriesStatus::Airing | SeriesStatus::OnHiatus => "Continuing",
+            SeriesStatus::Completed | SeriesStatus::Cancelled => "Ended",
+        };
+        push_element(&mut nfo, "status", status);
+    }
+
+    nfo.push_str("</tvshow>\n");
+    nfo
+}
+
+fn get_episode_nfo(series_info: &SeriesInfo, episode_info: &EpisodeInfo) -> String {
+    let mut nfo = format!("{}<episodedetails>\n", XML_HEADER);
+    let episode = match &episode_info.episode_number {
+        EpisodeNumber::Number(number) => number.to_string(),
+        EpisodeNumber::String(string) => string.clone(),
+    };
+
+    match &episode_info.name {
+        Some(name) => push_element(&mut nfo, "title", name),
+        None => push_element(&mut nfo, "title", &format!("Episode {}", episode)),
+    }
+
+    push_element(&mut nfo, "showtitle", &series_info.title);
+
+    // Movies are in season 0, which media servers show as specials
+    if let Some(season) = episode_info.season_number {
+        push_element(&mut nfo, "season", &season.to_string());
+    }
+
+    if let EpisodeNumber::Number(number) = episode_info.episode_number {
+        push_element(&mut nfo, "episode", &number.to_string());
+    }
+
+    nfo.push_str("</episodedetails>\n");
+    nfo
+}
+
+fn push_element(nfo: &mut String, name: &str, value: &str) {
+    let _ = writeln!(nfo, "  <{name}>{}</{name}>", escape_xml(value));
+}
+
+fn escape_xml(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+
+    for char in value.chars() {
+        match char {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            // Not allowed in XML 1.0 at all
+            char if char.is_control() && !matches!(char, '\n' | '\r' | '\t') => {}
+            char => escaped.push(char),
+        }
+    }
+
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::downloaders::{EpisodeInfo, EpisodeNumber, SeriesInfo, SeriesStatus};
+    use crate::nfo::{get_episode_nfo, get_tvshow_nfo};
+
+    #[test]
+    fn test_nfo() {
+        let series_info = SeriesInfo {
+            title: "Tom & Jerry".to_owned(),
+            description: Some("Cat <3 mouse".to_owned()),
+            status: Some(SeriesStatus::Completed),
+            year: Some(1940),
+            poster_url: None,
+            backdrop_url: None,
+        };
+
+        assert_eq!(
+            get_tvshow_nfo(&series_info),
+            "<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"yes\"?>\n<tvshow>\n  <title>Tom &amp; \
+             Jerry</title>\n  <plot>Cat &lt;3 mouse</plot>\n  <year>1940</year>\n  <status>Ended</status>\n</tvshow>\n"
+        );
+
+        let episode_info = EpisodeInfo {
+            name: None,
+            season_number: Some(0),
+            episode_number: EpisodeNumber::Number(3),
+            max_episode_number_in_season: None,
+        };
+
+        assert_eq!(
+            get_episode_nfo(&series_info, &episode_info),
+            "<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"yes\"?>\n<episodedetails>\n  <title>Episode \
+             3</title>\n  <showtitle>Tom &amp; Jerry</showtitle>\n  <season>0</season>\n  \
+             <episode>3</episode>\n</episodedetails>\n"
+        );
+    }
+}
@@ -0,0 +1,243 @@
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use anyhow::Context;
+
+use crate::download::format_episode_number;
+use crate::downloaders::{EpisodeNumber, Language, VideoType};
+
+/// Exit code if some episodes failed and others did not
+pub(crate) const EXIT_CODE_PARTIAL_FAILURE: i32 = 2;
+/// Exit code if every episode failed
+pub(crate) const EXIT_CODE_TOTAL_FAILURE: i32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OutcomeStatus {
+    Succeeded,
+    Skipped,
+    Failed,
+}
+
+impl OutcomeStatus {
+    fn name(&self) -> &'static str {
+        match self {
+            OutcomeStatus::Succeeded => "succeeded",
+            OutcomeStatus::Skipped => "skipped",
+            OutcomeStatus::Failed => "failed",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct EpisodeOutcome {
+    episode: String,
+    status: OutcomeStatus,
+    reason: Option<String>,
+    path: Option<PathBuf>,
+}
+
+/// Outcome of every episode of a run, collected from the scrapers and the download manager
+#[derive(Debug, Default)]
+pub(crate) struct DownloadReport {
+    outcomes: Mutex<Vec<EpisodeOutcome>>,
+}
+
+impl DownloadReport {
+    pub(crate) fn succeeded(&self, episode: String, path: &Path) {
+        self.add(EpisodeOutcome {
+            episode,
+            status: OutcomeStatus::Succeeded,
+            reason: None,
+            path: Some(path.to_owned()),
+        });
+    }
+
+    pub(crate) fn skipped(&self, episode: String, reason: &str) {
+        self.add(EpisodeOutcome {
+            episode,
+            status: OutcomeStatus::Skipped,
+            reason: Some(reason.to_owned()),
+            path: None,
+        });
+    }
+
+    pub(crate) fn failed(&self, episode: String, err: &anyhow::Error) {
+        self.add(EpisodeOutcome {
+            episode,
+            status: OutcomeStatus::Failed,
+            reason: Some(format!("{:#}", err)),
+            path: None,
+        });
+    }
+
+    fn add(&self, outcome: EpisodeOutcome) {
+        self.outcomes.lock().unwrap().push(outcome);
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.outcomes.lock().unwrap().is_empty()
+    }
+
+    /// Outcomes in the order they were reported
+    fn outcomes(&self) -> Vec<EpisodeOutcome> {
+        self.outcomes.lock().unwrap().clone()
+    }
+
+    fn count(&self, status: OutcomeStatus) -> usize {
+        self.outcomes
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|outcome| outcome.status == status)
+            .count()
+    }
+
+    /// Table of all episodes, failures only show the first line of their reason
+    pub(crate) fn summary(&self) -> String {
+        let outcomes = self.outcomes();
+        let episode_width = outcomes
+            .iter()
+            .map(|outcome| outcome.episode.chars().count())
+            .max()
+            .unwrap_or(0);
+        let mut summary = format!(
+            "{} succeeded, {} skipped, {} failed",
+            self.count(OutcomeStatus::Succeeded),
+            self.count(OutcomeStatus::Skipped),
+            self.count(OutcomeStatus::Failed)
+        );
+
+        for outcome in outcomes {
+            let reason = outcome.reason.as_deref().and_then(|reason| reason.lines().next());
+            let line = format!(
+                "  {:<episode_width$}  {:<9}  {}",
+                outcome.episode,
+                outcome.status.name(),
+                reason.unwrap_or_default()
+            );
+            summary.push('\n');
+            summary.push_str(line.trim_end());
+        }
+
+        summary
+    }
+
+    pub(crate) fn to_json(&self) -> serde_json::Value {
+        let episodes = self
+            .outcomes()
+            .into_iter()
+            .map(|outcome| {
+                serde_json::json!({
+                    "episode": outcome.episode,
+                    "status": outcome.status.name(),
+                    "reason": outcome.reason,
+                    "path": outcome.path.map(|path| path.display().to_string()),
+                })
+            })
+            .collect::<Vec<_>>();
+
+        serde_json::json!({
+            "succeeded": self.count(OutcomeStatus::Succeeded),
+            "skipped": self.count(OutcomeStatus::Skipped),
+            "failed": self.count(OutcomeStatus::Failed),
+            "episodes": episodes,
+        })
+    }
+
+    pub(crate) async fn write_json(&self, path: &Path) -> Result<(), anyhow::Error> {
+        let json = serde_json::to_string_pretty(&self.to_json()).with_context(|| "failed to serialize report")?;
+        tokio::fs::write(path, json)
+            .await
+            .with_context(|| "failed to write report")
+    }
+
+    /// Zero if nothing failed, otherwise whether only some or all attempted episodes failed
+    pub(crate) fn exit_code(&self) -> i32 {
+        let failed = self.count(OutcomeStatus::Failed);
+
+        if failed == 0 {
+            0
+        } else if failed == self.count(OutcomeStatus::Succeeded) + failed {
+            EXIT_CODE_TOTAL_FAILURE
+        } else {
+            EXIT_CODE_PARTIAL_FAILURE
+        }
+    }
+}
+
+/// Name of an episode in the report and in log messages, e.g. `S01E003 GerDub`.
+/// The language tells apart the downloads of an episode in several languages.
+pub(crate) fn get_episode_label(season: Option<u32>, episode: &EpisodeNumber, language: Option<&VideoType>) -> String {
+    let episode = format_episode_number(episode, Some(3));
+    let label = match season {
+        Some(season) => format!("S{season:02}E{episode}"),
+        None => format!("E{episode}"),
+    };
+
+    match language {
+        Some(VideoType::Unspecified(Language::Unspecified)) | None => label,
+        Some(language) => format!("{label} {language}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::downloaders::{EpisodeNumber, Language, VideoType};
+    use crate::report::{get_episode_label, DownloadReport, EXIT_CODE_PARTIAL_FAILURE, EXIT_CODE_TOTAL_FAILURE};
+
+    #[test]
+    fn test_download_report() {
+        let report = DownloadReport::default();
+        assert_eq!(report.exit_code(), 0);
+
+        report.failed(
+            get_episode_label(Some(1), &EpisodeNumber::Number(2), None),
+            &anyhow::anyhow!("no streams\nin requested language"),
+        );
+        report.skipped(
+            get_episode_label(None, &EpisodeNumber::Number(12), None),
+            "already in the download archive",
+        );
+        assert_eq!(report.exit_code(), EXIT_CODE_TOTAL_FAILURE);
+
+        report.succeeded(
+            get_episode_label(
+                Some(1),
+                &EpisodeNumber::Number(1),
+                Some(&VideoType::Dub(Language::German)),
+            ),
+            Path::new("Series - S01E01.mp4"),
+        );
+        assert_eq!(report.exit_code(), EXIT_CODE_PARTIAL_FAILURE);
+
+        assert_eq!(
+            report.summary(),
+            "1 succeeded, 1 skipped, 1 failed\n  S01E002         failed     no streams\n  E012            skipped    \
+             already in the download archive\n  S01E001 GerDub  succeeded"
+        );
+        assert_eq!(report.to_json()["episodes"][2]["path"], "Series - S01E01.mp4");
+    }
+
+    #[test]
+    fn test_get_episode_label() {
+        assert_eq!(get_episode_label(Some(1), &EpisodeNumber::Number(2), None), "S01E002");
+        assert_eq!(
+            get_episode_label(
+                None,
+                &EpisodeNumber::Number(12),
+                Some(&VideoType::Sub(Language::English))
+            ),
+            "E012 EngSub"
+        );
+        assert_eq!(
+            get_episode_label(
+                Some(0),
+                &EpisodeNumber::Number(1),
+                Some(&VideoType::Unspecified(Language::Unspecified))
+            ),
+            "S00E001"
+        );
+    }
+}
@@ -0,0 +1,341 @@
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use anyhow::Context;
+
+use crate::download::{avoid_windows_reserved_name, format_episode_number, prepare_series_name_for_file};
+use crate::downloaders::{EpisodeInfo, EpisodeNumber, Language, VideoType};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TemplateField {
+    Series,
+    Season,
+    Episode,
+    Title,
+    Language,
+    Hoster,
+    Year,
+}
+
+impl FromStr for TemplateField {
+    type Err = anyhow::Error;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let field = match input {
+            "series" => TemplateField::Series,
+            "season" => TemplateField::Season,
+            "episode" => TemplateField::Episode,
+            "title" => TemplateField::Title,
+            "language" => TemplateField::Language,
+            "hoster" => TemplateField::Hoster,
+            "year" => TemplateField::Year,
+            _ => anyhow::bail!("unknown output template field: {}", input),
+        };
+
+        Ok(field)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum TemplatePart {
+    Literal(String),
+    Field {
+        field: TemplateField,
+        /// Numbers are padded with zeros to this width
+        width: Option<usize>,
+    },
+}
+
+/// An output path like `{series}/Season {season:02}/{series} S{season:02}E{episode:02} {title}`,
+/// relative to the save directory and without extension. Every path component
+/// is sanitized on its own, so field values can never add directories.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct OutputTemplate {
+    components: Vec<Vec<TemplatePart>>,
+}
+
+/// The values that the fields of an output template are filled with
+pub(crate) struct TemplateValues<'a> {
+    pub(crate) series: &'a str,
+    pub(crate) episode_info: &'a EpisodeInfo,
+    pub(crate) language: Option<&'a VideoType>,
+    pub(crate) hoster: Option<&'a str>,
+    pub(crate) year: Option<u32>,
+}
+
+impl TemplateValues<'_> {
+    fn get(&self, field: TemplateField, width: Option<usize>) -> String {
+        let pad_number = |number: u32| format!("{number:0>width$}", width = width.unwrap_or(0));
+
+        match field {
+            TemplateField::Series => self.series.to_owned(),
+            TemplateField::Season => self.episode_info.season_number.map(pad_number).unwrap_or_default(),
+            TemplateField::Episode => match (&self.episode_info.episode_number, width) {
+                (EpisodeNumber::Number(number), Some(_)) => pad_number(*number),
+                (episode_number, _) => {
+                    let alignment_episode_number = self
+                        .episode_info
+                        .max_episode_number_in_season
+                        .map(|max_num| (max_num.checked_ilog10().unwrap_or(0) + 1) as usize);
+                    format_episode_number(episode_number, alignment_episode_number)
+                }
+            },
+            TemplateField::Title => self.episode_info.name.clone().unwrap_or_default(),
+            TemplateField::Language => match self.language {
+                Some(VideoType::Unspecified(Language::Unspecified)) | None => String::new(),
+                Some(language) => language.to_string(),
+            },
+            TemplateField::Hoster => self.hoster.unwrap_or_default().to_owned(),
+            TemplateField::Year => self.year.map(pad_number).unwrap_or_default(),
+        }
+    }
+}
+
+impl OutputTemplate {
+    pub(crate) fn render(&self, values: &TemplateValues) -> PathBuf {
+        render_components(&self.components, |field, width| values.get(field, width))
+    }
+
+    /// Whether the leading directories only depend on the series and contain its name, so that they can hold files
+    /// of the whole series like `tvshow.nfo`
+    pub(crate) fn has_series_directory(&self) -> bool {
+        self.get_series_directory_components().is_some()
+    }
+
+    /// The leading directories that only depend on the series, relative to the save directory
+    pub(crate) fn render_series_directory(&self, series: &str, year: Option<u32>) -> Option<PathBuf> {
+        let components = self.get_series_directory_components()?;
+        let path = render_components(components, |field, width| match field {
+            TemplateField::Series => series.to_owned(),
+            TemplateField::Year => year
+                .map(|year| format!("{year:0>width$}", width = width.unwrap_or(0)))
+                .unwrap_or_default(),
+            _ => String::new(),
+        });
+
+        Some(path)
+    }
+
+    fn get_series_directory_components(&self) -> Option<&[Vec<TemplatePart>]> {
+        let is_series_part = |part: &TemplatePart| match part {
+            TemplatePart::Literal(_) => true,
+            TemplatePart::Field { field, .. } => matches!(field, TemplateField::Series | TemplateField::Year),
+        };
+        let directories = &self.components[..self.components.len() - 1];
+        let count = directories
+            .iter()
+            .take_while(|component| component.iter().all(is_series_part))
+            .count();
+        let components = &directories[..count];
+
+        let has_series = components.iter().flatten().any(|part| {
+            matches!(
+                part,
+                TemplatePart::Field {
+                    field: TemplateField::Series,
+                    ..
+                }
+            )
+        });
+
+        has_series.then_some(components)
+    }
+}
+
+fn render_components(
+    components: &[Vec<TemplatePart>],
+    get: impl Fn(TemplateField, Option<usize>) -> String,
+) -> PathBuf {
+    let mut path = PathBuf::new();
+
+    for component in components {
+        let mut rendered = String::new();
+
+        for part in component {
+            match part {
+                TemplatePart::Literal(literal) => rendered.push_str(literal),
+                TemplatePart::Field { field, width } => rendered.push_str(&get(*field, *width)),
+            }
+        }
+
+        // Missing values can leave separators dangling at the ends
+        let rendered = rendered.trim_matches([' ', '-', '_']);
+
+        // The extension is added to the last component, which does not avoid reserved names either
+        if let Some(component) = prepare_series_name_for_file(rendered) {
+            path.push(avoid_windows_reserved_name(&component).as_ref());
+        }
+    }
+
+    path
+}
+
+impl FromStr for OutputTemplate {
+    type Err = anyhow::Error;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let mut components = Vec::new();
+
+        for component in input.split(['/', '\\']) {
+            let mut parts = Vec::new();
+            let mut literal = String::new();
+            let mut rest = component;
+
+            while let Some(char) = rest.chars().next() {
+                rest = &rest[char.len_utf8()..];
+
+                match char {
+                    '{' | '}' if rest.starts_with(char) => {
+                        rest = &rest[1..];
+                        literal.push(char);
+                    }
+                    '{' => {
+                        let field_end = rest
+                            .find('}')
+                            .with_context(|| format!("unclosed field in output template: {}", input))?;
+                        let (name, width) = match rest[..field_end].split_once(':') {
+                            Some((name, width)) => {
+                                let width = width
+                                    .parse::<usize>()
+                                    .with_context(|| format!("invalid width in output template: {}", width))?;
+                                (name, Some(width))
+                            }
+                            None => (&rest[..field_end], None),
+                        };
+
+                        if !literal.is_empty() {
+                            parts.push(TemplatePart::Literal(std::mem::take(&mut literal)));
+                        }
+
+                        parts.push(TemplatePart::Field {
+                            field: name.trim().parse()?,
+                            width,
+                        });
+                        rest = &rest[field_end + 1..];
+                    }
+                    '}' => anyhow::bail!("unmatched }} in output template: {}", input),
+                    _ => literal.push(char),
+                }
+            }
+
+            if !literal.is_empty() {
+                parts.push(TemplatePart::Literal(literal));
+            }
+
+            components.push(parts);
+        }
+
+        let has_episode = components.iter().flatten().any(|part| {
+            matches!(
+                part,
+                TemplatePart::Field {
+                    field: TemplateField::Episode,
+                    ..
+                }
+            )
+        });
+
+        if !has_episode {
+            anyhow::bail!("output template has to contain the {{episode}} field");
+        }
+
+        Ok(OutputTemplate { components })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::downloaders::{EpisodeInfo, EpisodeNumber, Language, VideoType};
+    use crate::template::{OutputTemplate, TemplateValues};
+
+    #[test]
+    fn test_output_template() {
+        let episode_info = EpisodeInfo {
+            name: Some("Romance Dawn".to_owned()),
+            season_number: Some(1),
+            episode_number: EpisodeNumber::Number(1),
+            max_episode_number_in_season: Some(61),
+        };
+        let values = TemplateValues {
+            series: "One Piece: Egghead",
+            episode_info: &episode_info,
+            language: Some(&VideoType::Dub(Language::German)),
+            hoster: Some("VOE"),
+            year: None,
+        };
+
+        let template = "{series}/Season {season:02}/{series} S{season:02}E{episode:02} {title}"
+            .parse::<OutputTemplate>()
+            .unwrap();
+        assert_eq!(
+            template.render(&values),
+            PathBuf::from("One Piece - Egghead")
+                .join("Season 01")
+                .join("One Piece - Egghead S01E01 Romance Dawn")
+        );
+
+        let template = "{year}/E{episode} - {language} - {hoster} - {title}/a/b"
+            .parse::<OutputTemplate>()
+            .unwrap();
+        let episode_info = EpisodeInfo {
+            name: None,
+            ..episode_info.clone()
+        };
+        let values = TemplateValues {
+            episode_info: &episode_info,
+            ..values
+        };
+        assert_eq!(
+            template.render(&values),
+            PathBuf::from("E01 - GerDub - VOE").join("a").join("b")
+        );
+
+        let template = "/../{{{episode}}}".parse::<OutputTemplate>().unwrap();
+        assert_eq!(template.render(&values), PathBuf::from("{01}"));
+
+        let values = TemplateValues {
+            series: "CON",
+            ..values
+        };
+        let template = "{series}/{series} E{episode}".parse::<OutputTemplate>().unwrap();
+        assert_eq!(template.render(&values), PathBuf::from("CON_").join("CON E01"));
+    }
+
+    #[test]
+    fn test_render_series_directory() {
+        let template = "Anime/{series} ({year})/Season {season}/{series} E{episode}"
+            .parse::<OutputTemplate>()
+            .unwrap();
+        assert!(template.has_series_directory());
+        assert_eq!(
+            template.render_series_directory("Yuruyuri", Some(2011)),
+            Some(PathBuf::from("Anime").join("Yuruyuri (2011)"))
+        );
+
+        let template = "Season {season}/{series}/{series} E{episode}"
+            .parse::<OutputTemplate>()
+            .unwrap();
+        assert!(!template.has_series_directory());
+        assert_eq!(template.render_series_directory("Yuruyuri", None), None);
+
+        let template = "{series}/{series} E{episode}".parse::<OutputTemplate>().unwrap();
+        assert_eq!(
+            template.render_series_directory("CON", None),
+            Some(PathBuf::from("CON_"))
+        );
+
+        let template = "{series} E{episode}".parse::<OutputTemplate>().unwrap();
+        assert!(!template.has_series_directory());
+    }
+
+    #[test]
+    fn test_parse_output_template_errors() {
+        assert!("{series}".parse::<OutputTemplate>().is_err());
+        assert!("{episode".parse::<OutputTemplate>().is_err());
+        assert!("{episode}}".parse::<OutputTemplate>().is_err());
+        assert!("{episode} {unknown}".parse::<OutputTemplate>().is_err());
+        assert!("{episode:x}".parse::<OutputTemplate>().is_err());
+    }
+}